@@ -0,0 +1,212 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+pub const ARCHIVE_EXTENSION: &str = ".tar.zst";
+
+pub fn is_archive(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(ARCHIVE_EXTENSION))
+}
+
+/// Name of the backup an archive holds, i.e. its file name without
+/// `.tar.zst`.
+pub fn archive_backup_name(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.strip_suffix(ARCHIVE_EXTENSION)
+        .unwrap_or(&name)
+        .to_string()
+}
+
+/// Packs a backup folder into `<folder>.tar.zst` next to it. The archive
+/// holds the folder itself, so unpacking recreates `<folder>/...`.
+pub fn compress_dir(dir: &Path, level: i32) -> io::Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "backup has no name"))?;
+    let archive_path =
+        dir.with_file_name(format!("{}{}", name.to_string_lossy(), ARCHIVE_EXTENSION));
+
+    let encoder = zstd::Encoder::new(File::create(&archive_path)?, level)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.append_dir_all(name, dir)?;
+    builder.into_inner()?.finish()?;
+
+    Ok(archive_path)
+}
+
+/// Writes a backup straight into `<folder>.tar.zst` one file at a time, for
+/// `backup --stream`, laid out as [`compress_dir`] would.
+///
+/// Each file is compressed into a zstd frame of its own in a scratch file
+/// next to the archive, and only appended once it is complete, so a
+/// transfer that fails part way leaves nothing behind and parallel pulls
+/// don't wait for each other. zstd reads concatenated frames as one stream,
+/// so [`unpack`] needs nothing special. The archive is written as
+/// `<folder>.tar.zst.part` in the temp folder until
+/// [`StreamArchive::finish`] moves it next to the backup folder.
+pub struct StreamArchive {
+    path: PathBuf,
+    partial_path: PathBuf,
+    name: String,
+    level: i32,
+    file: Mutex<File>,
+    scratch_count: AtomicUsize,
+}
+
+impl StreamArchive {
+    /// Starts the archive for the backup folder `dir`, which itself holds
+    /// only what [`StreamArchive::finish`] adds at the end. The partial
+    /// archive and scratch files go in `temp_root`.
+    pub fn create(dir: &Path, level: i32, temp_root: &Path) -> io::Result<Self> {
+        let name = dir
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "backup has no name"))?
+            .to_string_lossy()
+            .to_string();
+        let path = dir.with_file_name(format!("{}{}", name, ARCHIVE_EXTENSION));
+        let partial_path = temp_root.join(format!("{}{}.part", name, ARCHIVE_EXTENSION));
+        Ok(Self {
+            file: Mutex::new(File::create(&partial_path)?),
+            path,
+            partial_path,
+            name,
+            level,
+            scratch_count: AtomicUsize::new(0),
+        })
+    }
+
+    /// A new scratch file path for [`StreamArchive::append`]. It grows
+    /// while the file is read, so it can be watched for progress.
+    pub fn scratch_path(&self) -> PathBuf {
+        let count = self.scratch_count.fetch_add(1, Ordering::Relaxed);
+        let mut name = self.partial_path.as_os_str().to_owned();
+        name.push(format!(".{}", count));
+        PathBuf::from(name)
+    }
+
+    /// Adds `<folder>/<entry>` with the `size` bytes read from `data`,
+    /// compressing them into `scratch` first. Fails without adding anything
+    /// if `data` doesn't hold exactly `size` bytes.
+    pub fn append(
+        &self,
+        entry: &Path,
+        size: u64,
+        data: &mut dyn Read,
+        scratch: &Path,
+    ) -> io::Result<()> {
+        let result = self
+            .compress_entry(entry, size, data, scratch)
+            .and_then(|()| {
+                let mut frame = File::open(scratch)?;
+                io::copy(&mut frame, &mut *self.file.lock().unwrap())?;
+                Ok(())
+            });
+        let _ = fs::remove_file(scratch);
+        result
+    }
+
+    fn compress_entry(
+        &self,
+        entry: &Path,
+        size: u64,
+        data: &mut dyn Read,
+        scratch: &Path,
+    ) -> io::Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(Path::new(&self.name).join(entry))?;
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0),
+        );
+        header.set_cksum();
+
+        let mut encoder = zstd::Encoder::new(File::create(scratch)?, self.level)?;
+        encoder.write_all(header.as_bytes())?;
+        let copied = io::copy(&mut data.take(size), &mut encoder)?;
+        if copied != size || data.read(&mut [0])? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected {} bytes of {:?}", size, entry),
+            ));
+        }
+        // Entries are padded to whole 512 byte blocks.
+        let padding = (512 - size % 512) % 512;
+        encoder.write_all(&vec![0; padding as usize])?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Adds the files now in the backup folder `dir`, such as the manifest,
+    /// ends the archive and moves it to `<folder>.tar.zst`.
+    pub fn finish(self, dir: &Path) -> io::Result<PathBuf> {
+        let file = self.file.lock().unwrap().try_clone()?;
+        let mut builder = tar::Builder::new(zstd::Encoder::new(file, self.level)?);
+        builder.append_dir_all(&self.name, dir)?;
+        builder.into_inner()?.finish()?;
+        if fs::rename(&self.partial_path, &self.path).is_err() {
+            // The temp folder is on another filesystem; copy under a partial
+            // name so a failed copy never looks like a finished archive.
+            let mut copy_path = self.path.as_os_str().to_owned();
+            copy_path.push(".part");
+            fs::copy(&self.partial_path, &copy_path)?;
+            fs::rename(&copy_path, &self.path)?;
+            fs::remove_file(&self.partial_path)?;
+        }
+        Ok(self.path.clone())
+    }
+}
+
+impl Drop for StreamArchive {
+    /// Removes the archive if it was never finished.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.partial_path);
+    }
+}
+
+/// Unpacks an archive made by [`compress_dir`] or [`StreamArchive`] into
+/// `dest` and returns the path of the backup folder inside it.
+pub fn unpack(archive_path: &Path, dest: &Path) -> io::Result<PathBuf> {
+    let decoder = zstd::Decoder::new(File::open(archive_path)?)?;
+    tar::Archive::new(decoder).unpack(dest)?;
+
+    let backup_dir = dest.join(archive_backup_name(archive_path));
+    if !backup_dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} does not contain a backup folder", archive_path),
+        ));
+    }
+    Ok(backup_dir)
+}
+
+/// A scratch directory that is removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `<prefix>-<pid>` under `root`, the `--temp-dir` folder.
+    pub fn new(root: &Path, prefix: &str) -> io::Result<Self> {
+        let path = root.join(format!("{}-{}", prefix, std::process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
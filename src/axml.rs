@@ -0,0 +1,300 @@
+//! Reads the package name, split name and version metadata from the binary
+//! `AndroidManifest.xml` inside an APK.
+//!
+//! Only the parts of the binary XML format needed for that are handled: the
+//! string pool, the resource map and start-element chunks.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const UTF8_FLAG: u32 = 1 << 8;
+
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_HEX: u8 = 0x11;
+
+// android.R.attr ids, used when attribute names have been stripped.
+const ATTR_VERSION_CODE: u32 = 0x0101_021b;
+const ATTR_VERSION_NAME: u32 = 0x0101_021c;
+const ATTR_MIN_SDK_VERSION: u32 = 0x0101_020c;
+const ATTR_TARGET_SDK_VERSION: u32 = 0x0101_0270;
+
+/// Package name, split name and version metadata from an APK's manifest.
+/// Fields are `None` when the manifest doesn't set them or sets them to a
+/// resource reference; `split` is `None` for a base APK.
+#[derive(Clone, Default)]
+pub struct ApkInfo {
+    pub package: Option<String>,
+    pub split: Option<String>,
+    /// The feature split a config split belongs to, from `configForSplit`.
+    pub config_for_split: Option<String>,
+    /// `android:requiredSplitTypes`: split types the rest of the set must
+    /// provide for this APK to install.
+    pub required_split_types: Vec<String>,
+    /// `android:splitTypes`: the split types this APK provides.
+    pub split_types: Vec<String>,
+    pub version_code: Option<u64>,
+    pub version_name: Option<String>,
+    pub min_sdk: Option<u32>,
+    pub target_sdk: Option<u32>,
+}
+
+/// Opens an APK and parses its `AndroidManifest.xml`.
+pub fn read_apk_info(apk_path: &Path) -> io::Result<ApkInfo> {
+    let mut archive = zip::ZipArchive::new(File::open(apk_path)?).map_err(invalid_data)?;
+    let mut entry = archive
+        .by_name("AndroidManifest.xml")
+        .map_err(invalid_data)?;
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    parse_manifest(&data)
+}
+
+/// Parses a binary `AndroidManifest.xml`.
+pub fn parse_manifest(data: &[u8]) -> io::Result<ApkInfo> {
+    if read_u16(data, 0)? != RES_XML_TYPE {
+        return Err(invalid_data("not a binary XML document"));
+    }
+
+    let mut strings = Vec::new();
+    let mut resource_ids = Vec::new();
+    let mut info = ApkInfo::default();
+
+    let mut offset = usize::from(read_u16(data, 2)?);
+    while offset + 8 <= data.len() {
+        let chunk_type = read_u16(data, offset)?;
+        let chunk_size = read_u32(data, offset + 4)? as usize;
+        if chunk_size < 8 || offset + chunk_size > data.len() {
+            return Err(invalid_data("truncated chunk"));
+        }
+        let chunk = &data[offset..offset + chunk_size];
+
+        match chunk_type {
+            RES_STRING_POOL_TYPE => strings = parse_string_pool(chunk)?,
+            RES_XML_RESOURCE_MAP_TYPE => {
+                let header_size = usize::from(read_u16(chunk, 2)?);
+                resource_ids = (header_size..chunk.len())
+                    .step_by(4)
+                    .map(|at| read_u32(chunk, at))
+                    .collect::<io::Result<_>>()?;
+            }
+            RES_XML_START_ELEMENT_TYPE => {
+                let element = parse_start_element(chunk, &strings, &resource_ids)?;
+                match element.name.as_str() {
+                    "manifest" => {
+                        // `package` and `split` are not android: attributes,
+                        // so they are only ever found by name.
+                        let by_name = |name: &str| {
+                            element
+                                .attributes
+                                .iter()
+                                .find(|attr| attr.name == name)
+                                .and_then(|attr| attr.string.clone())
+                        };
+                        info.package = by_name("package");
+                        info.split = by_name("split");
+                        info.config_for_split = by_name("configForSplit");
+                        // The split type attributes (Android 12) are only
+                        // looked up by name, as comma-separated lists.
+                        let types = |name: &str| -> Vec<String> {
+                            by_name(name)
+                                .map(|types| {
+                                    types
+                                        .split(',')
+                                        .map(str::trim)
+                                        .filter(|kind| !kind.is_empty())
+                                        .map(String::from)
+                                        .collect()
+                                })
+                                .unwrap_or_default()
+                        };
+                        info.required_split_types = types("requiredSplitTypes");
+                        info.split_types = types("splitTypes");
+                        info.version_code =
+                            element.int(ATTR_VERSION_CODE, "versionCode").map(u64::from);
+                        info.version_name = element.string(ATTR_VERSION_NAME, "versionName");
+                    }
+                    "uses-sdk" => {
+                        info.min_sdk = element.int(ATTR_MIN_SDK_VERSION, "minSdkVersion");
+                        info.target_sdk = element.int(ATTR_TARGET_SDK_VERSION, "targetSdkVersion");
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        offset += chunk_size;
+    }
+
+    Ok(info)
+}
+
+struct Element {
+    name: String,
+    attributes: Vec<Attribute>,
+}
+
+struct Attribute {
+    name: String,
+    resource_id: Option<u32>,
+    /// Raw string value, set for string attributes.
+    string: Option<String>,
+    data_type: u8,
+    data: u32,
+}
+
+impl Element {
+    fn attribute(&self, resource_id: u32, name: &str) -> Option<&Attribute> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.resource_id == Some(resource_id))
+            .or_else(|| self.attributes.iter().find(|attr| attr.name == name))
+    }
+
+    fn int(&self, resource_id: u32, name: &str) -> Option<u32> {
+        let attr = self.attribute(resource_id, name)?;
+        match attr.data_type {
+            TYPE_INT_DEC | TYPE_INT_HEX => Some(attr.data),
+            // Some build tools write numbers as strings.
+            TYPE_STRING => attr.string.as_deref()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn string(&self, resource_id: u32, name: &str) -> Option<String> {
+        let attr = self.attribute(resource_id, name)?;
+        match attr.data_type {
+            TYPE_STRING => attr.string.clone(),
+            TYPE_INT_DEC => Some(attr.data.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn parse_start_element(
+    chunk: &[u8],
+    strings: &[String],
+    resource_ids: &[u32],
+) -> io::Result<Element> {
+    let string_at = |index: u32| strings.get(index as usize).cloned();
+
+    let ext = usize::from(read_u16(chunk, 2)?);
+    let name = string_at(read_u32(chunk, ext + 4)?).unwrap_or_default();
+    let attribute_start = usize::from(read_u16(chunk, ext + 8)?);
+    let attribute_size = usize::from(read_u16(chunk, ext + 10)?);
+    let attribute_count = usize::from(read_u16(chunk, ext + 12)?);
+
+    let mut attributes = Vec::with_capacity(attribute_count);
+    for i in 0..attribute_count {
+        let at = ext + attribute_start + i * attribute_size;
+        let name_index = read_u32(chunk, at + 4)?;
+        let raw_value = read_u32(chunk, at + 8)?;
+        // Res_value: size (u16), res0 (u8), dataType (u8), data (u32).
+        let data_type = *chunk
+            .get(at + 15)
+            .ok_or_else(|| invalid_data("truncated attribute"))?;
+        let data = read_u32(chunk, at + 16)?;
+
+        let string = if data_type == TYPE_STRING {
+            string_at(if raw_value != u32::MAX {
+                raw_value
+            } else {
+                data
+            })
+        } else {
+            None
+        };
+        attributes.push(Attribute {
+            name: string_at(name_index).unwrap_or_default(),
+            resource_id: resource_ids.get(name_index as usize).copied(),
+            string,
+            data_type,
+            data,
+        });
+    }
+
+    Ok(Element { name, attributes })
+}
+
+fn parse_string_pool(chunk: &[u8]) -> io::Result<Vec<String>> {
+    let header_size = usize::from(read_u16(chunk, 2)?);
+    let string_count = read_u32(chunk, 8)? as usize;
+    let flags = read_u32(chunk, 16)?;
+    let strings_start = read_u32(chunk, 20)? as usize;
+    let utf8 = flags & UTF8_FLAG != 0;
+
+    (0..string_count)
+        .map(|i| {
+            let offset = read_u32(chunk, header_size + i * 4)? as usize;
+            let at = strings_start + offset;
+            if utf8 {
+                read_utf8_string(chunk, at)
+            } else {
+                read_utf16_string(chunk, at)
+            }
+        })
+        .collect()
+}
+
+fn read_utf8_string(chunk: &[u8], at: usize) -> io::Result<String> {
+    // The UTF-16 length comes first and is skipped; then the byte length.
+    let (_, at) = read_utf8_length(chunk, at)?;
+    let (len, at) = read_utf8_length(chunk, at)?;
+    let bytes = chunk
+        .get(at..at + len)
+        .ok_or_else(|| invalid_data("truncated string"))?;
+    Ok(String::from_utf8_lossy(bytes).to_string())
+}
+
+fn read_utf8_length(chunk: &[u8], at: usize) -> io::Result<(usize, usize)> {
+    let byte = |at: usize| {
+        chunk
+            .get(at)
+            .map(|&b| usize::from(b))
+            .ok_or_else(|| invalid_data("truncated string"))
+    };
+    let first = byte(at)?;
+    if first & 0x80 != 0 {
+        Ok((((first & 0x7f) << 8) | byte(at + 1)?, at + 2))
+    } else {
+        Ok((first, at + 1))
+    }
+}
+
+fn read_utf16_string(chunk: &[u8], at: usize) -> io::Result<String> {
+    let first = usize::from(read_u16(chunk, at)?);
+    let (len, at) = if first & 0x8000 != 0 {
+        (
+            ((first & 0x7fff) << 16) | usize::from(read_u16(chunk, at + 2)?),
+            at + 4,
+        )
+    } else {
+        (first, at + 2)
+    };
+    let units = (0..len)
+        .map(|i| read_u16(chunk, at + i * 2))
+        .collect::<io::Result<Vec<u16>>>()?;
+    Ok(String::from_utf16_lossy(&units))
+}
+
+fn read_u16(data: &[u8], at: usize) -> io::Result<u16> {
+    data.get(at..at + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid_data("unexpected end of binary XML"))
+}
+
+fn read_u32(data: &[u8], at: usize) -> io::Result<u32> {
+    data.get(at..at + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| invalid_data("unexpected end of binary XML"))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
@@ -0,0 +1,2624 @@
+//! The `backup` subcommand and [`backup`](fn@crate::backup): choosing what to
+//! back up, pulling it with several jobs and writing the manifest.
+
+use crate::archive::{StreamArchive, TempDir, is_archive};
+use crate::axml::ApkInfo;
+use crate::config::Config;
+use crate::device::{
+    AdbRunner, adb_info, adb_version_text, connect_device, device_has_root, device_serial,
+    device_sha256, dumpsys_package, get_device_output, get_device_sdk, get_installers,
+    get_package_paths, get_packages, get_third_party_packages, get_version_codes,
+    granted_permissions, last_install_time, pm_args, stat_device_files,
+};
+use crate::error::{AppError, Result};
+use crate::events::Event;
+use crate::install::{KNOWN_ABIS, KNOWN_DENSITIES, SplitFilter, find_apk_files, order_split_apks};
+use crate::manifest::{
+    ApkFile, DeviceInfo, Manifest, PackageEntry, PackageStatus, SCHEMA_VERSION, ToolInfo,
+    sha256_file,
+};
+use crate::output::Warnings;
+use crate::progress::TransferProgress;
+use crate::pull::{
+    EXTERNAL_DATA, OBB_FILES, PullOptions, StreamTarget, apk_split_name, exec_out_works,
+    extract_apk, extract_app_data, extract_shared_dir, split_file_names,
+};
+use crate::store::ObjectStore;
+use crate::{
+    DEVICE_INFO_DIR, PackageList, ReportList, archive, axml, backup_time, events, file_names,
+    interrupt, manifest, output, parse_args, progress, read_package_list, read_report_list, remote,
+    select_from, sort_backups, store,
+};
+use chrono::{Local, NaiveDateTime};
+use clap::{ArgGroup, Args, ValueEnum};
+use regex::Regex;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Timestamp in backup names when `--name-format` isn't given.
+pub(crate) const DEFAULT_NAME_FORMAT: &str = "%Y%m%d%H%M%S";
+const MAX_DEFAULT_JOBS: usize = 4;
+const PROGRESS_REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// Extra room the free-space preflight asks for on top of the APK sizes.
+pub(crate) const FREE_SPACE_MARGIN_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Options of the `backup` subcommand.
+#[derive(Args)]
+#[command(group(ArgGroup::new("mode").args(["new", "diff", "auto_base"])))]
+pub struct BackupArgs {
+    /// Make a new backup without prompting
+    #[arg(long, conflicts_with = "diff")]
+    new: bool,
+    /// Name of the new backup folder; `$date` is replaced with the timestamp
+    #[arg(long, requires = "mode")]
+    name: Option<String>,
+    /// Replace the backup folder if the name is already taken, instead of
+    /// asking for another name
+    ///
+    /// The old folder is deleted, after asking unless --yes is given, just
+    /// before the new backup starts. A differential backup can't replace its
+    /// own base.
+    #[arg(long, conflicts_with = "resume")]
+    overwrite: bool,
+    /// Don't ask before replacing a backup with --overwrite
+    #[arg(short, long, requires = "overwrite")]
+    yes: bool,
+    /// Format of the timestamp naming new backups and replacing `$date`
+    /// [default: %Y%m%d%H%M%S]
+    ///
+    /// Takes chrono strftime specifiers, plus `{model}` and `{serial}` for
+    /// the device's. Backups whose names lack a `%Y%m%d%H%M%S` timestamp are
+    /// sorted by modification time instead. Overrides `name-format` in
+    /// apktool.toml.
+    #[arg(long, value_name = "FORMAT", value_parser = parse_name_format)]
+    pub(crate) name_format: Option<String>,
+    /// Make a differential backup without prompting
+    #[arg(long, requires = "base")]
+    diff: bool,
+    /// Backup folder to use as the differential base
+    #[arg(long, requires = "diff")]
+    base: Option<String>,
+    /// Make a differential backup on the newest complete backup of this
+    /// device, or a full backup if there is none
+    ///
+    /// Backups are matched on the device serial in their manifest. Partial
+    /// backups and ones with failed packages aren't used as a base.
+    #[arg(long, conflicts_with_all = ["new", "diff", "resume", "packages", "from_file", "from_report", "since"])]
+    auto_base: bool,
+    /// Make a differential backup complete by linking APKs unchanged since
+    /// the base instead of leaving their packages out
+    ///
+    /// Each package the base holds is hashed on the device with `sha256sum`
+    /// and compared with the checksums in the base's manifest. Identical
+    /// files are hard-linked from the base, and only packages that differ
+    /// are pulled. A base without a manifest has no checksums, so its
+    /// packages are pulled again. The new backup doesn't depend on its base.
+    #[arg(long, conflicts_with = "new")]
+    link_unchanged: bool,
+    /// Only back up this installed package (repeatable)
+    #[arg(long = "package", value_name = "PACKAGE", conflicts_with = "diff")]
+    packages: Vec<String>,
+    /// Only back up the packages listed in this file, one per line
+    ///
+    /// Blank lines and `#` comments are ignored. Listed packages that aren't
+    /// installed are reported and left out. Can be combined with --package.
+    #[arg(long, value_name = "FILE", conflicts_with = "diff", value_parser = read_package_list)]
+    from_file: Option<PackageList>,
+    /// Only back up the packages listed in a JSON report (`--json` output
+    /// of backup or install) or a backup's manifest.json
+    ///
+    /// Listed packages that aren't installed are reported and left out. Can
+    /// be combined with --package and --from-file.
+    #[arg(long, value_name = "FILE", conflicts_with = "diff", value_parser = read_report_list)]
+    from_report: Option<ReportList>,
+    /// With --from-report, only take the packages the report lists as
+    /// failed, to retry them
+    #[arg(long, requires = "from_report")]
+    failed_only: bool,
+    /// Only back up packages installed or updated since then: a duration
+    /// back from now such as 7d, 12h or 30m, or a date such as 2024-05-01 or
+    /// "2024-05-01 18:30"
+    ///
+    /// Install and update times come from `dumpsys package`, in the
+    /// device's local time, and durations count back from the device's
+    /// clock. Packages whose times can't be read are backed up anyway.
+    #[arg(long, value_name = "WHEN", conflicts_with = "diff", value_parser = parse_since)]
+    since: Option<Since>,
+    /// Also back up system apps, such as updated preinstalled apps
+    ///
+    /// These can usually only be installed again as an update to the copy
+    /// already on the device.
+    #[arg(long, conflicts_with_all = ["packages", "from_file", "from_report"])]
+    include_system: bool,
+    /// Only back up packages matching this regex (repeatable)
+    #[arg(long = "include", value_name = "REGEX", value_parser = Regex::new)]
+    pub(crate) includes: Vec<Regex>,
+    /// Skip packages matching this regex (repeatable)
+    #[arg(long = "exclude", value_name = "REGEX", value_parser = Regex::new)]
+    pub(crate) excludes: Vec<Regex>,
+    /// Also back up `/data/data/<package>` where possible: for debuggable apps
+    /// through run-as, or for any app on a rooted device
+    #[arg(long)]
+    with_data: bool,
+    /// Record each package's granted runtime permissions in the manifest,
+    /// for `install --restore-permissions`
+    #[arg(long)]
+    with_permissions: bool,
+    /// Also back up `/sdcard/Android/data/<package>` for each package
+    #[arg(long)]
+    with_external_data: bool,
+    /// Also back up `/sdcard/Android/obb/<package>` expansion files
+    #[arg(long)]
+    with_obb: bool,
+    /// Record the default launcher into `device_info/`
+    ///
+    /// `install --restore-launcher` makes it the default again. The home
+    /// screen layout isn't recorded.
+    #[arg(long)]
+    capture_launcher: bool,
+    /// Pull APKs with `adb pull -a` so they keep their on-device mtime
+    ///
+    /// The mtime is recorded in the manifest as well.
+    #[arg(long)]
+    preserve_timestamps: bool,
+    /// Only back up ABI config splits for this ABI (repeatable)
+    ///
+    /// The base and all other splits are still backed up. A package with no
+    /// split for any kept ABI keeps all of its ABI splits.
+    #[arg(long = "keep-abi", value_name = "ABI",
+          value_parser = clap::builder::PossibleValuesParser::new(KNOWN_ABIS))]
+    keep_abis: Vec<String>,
+    /// Only back up density config splits for this density (repeatable)
+    ///
+    /// Works like --keep-abi, for splits such as `split_config.xxhdpi.apk`.
+    #[arg(long = "keep-density", value_name = "DENSITY",
+          value_parser = clap::builder::PossibleValuesParser::new(KNOWN_DENSITIES))]
+    keep_densities: Vec<String>,
+    /// Record each APK's on-device size and mtime in the manifest
+    ///
+    /// Costs one extra `stat` on the device per package. --preserve-timestamps
+    /// records the mtime too, and gives the pulled files that mtime.
+    #[arg(long)]
+    record_file_stats: bool,
+    /// Keep one copy of identical APKs across packages and backups
+    ///
+    /// Each distinct APK is stored once under `.objects/` in the backup
+    /// directory, and package folders hard-link to it, so backups keep their
+    /// usual layout. Falls back to plain copies where hard links aren't
+    /// supported.
+    #[arg(long)]
+    dedup: bool,
+    /// Pack the finished backup into `<name>.tar.zst`
+    #[arg(long)]
+    compress: bool,
+    /// zstd compression level for --compress
+    #[arg(long, default_value_t = 3, requires = "compress",
+          value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: i32,
+    /// Delete the backup folder once it has been compressed
+    #[arg(long, requires = "compress")]
+    remove_uncompressed: bool,
+    /// With --compress, stream each APK off the device straight into the
+    /// archive instead of pulling it to disk first
+    ///
+    /// Uses `adb exec-out cat`, falling back to pulling each APK to a
+    /// scratch file when exec-out doesn't work on the device. No backup
+    /// folder is left behind. Each APK is added before its manifest can be
+    /// read, so it needs --no-splits-merge, and the manifest doesn't record
+    /// splits. Can't be combined with options that need the files on disk.
+    #[arg(long, requires_all = ["compress", "no_splits_merge"], conflicts_with_all = [
+        "remove_uncompressed", "dedup", "link_unchanged", "resume",
+        "with_data", "with_external_data", "with_obb",
+    ])]
+    stream: bool,
+    /// Copy the finished backup into this folder on another machine, over
+    /// SSH
+    ///
+    /// Uses rsync when it is installed and scp otherwise; the folder is
+    /// created if needed. A compressed backup is copied as its archive only.
+    /// Nothing is copied when the backup stops early.
+    #[arg(long, value_name = "[USER@]HOST:PATH")]
+    remote: Option<remote::Remote>,
+    /// How many times to retry a failed `adb pull` before failing the package [default: 3]
+    #[arg(long)]
+    pub(crate) retries: Option<u32>,
+    /// Number of packages to extract in parallel [default: available cores, at most 4]
+    #[arg(short, long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) jobs: Option<u16>,
+    /// Order in which packages are handed to the --jobs workers
+    ///
+    /// largest-first starts the biggest packages, by their APK sizes on the
+    /// device, while the other workers take the small ones, so one large
+    /// package doesn't hold up the end of a parallel backup. The sizes are
+    /// read even with --force; device-order skips that.
+    #[arg(long, value_name = "ORDER", default_value = "largest-first")]
+    schedule: Schedule,
+    /// Start the backup even if the free-space check says it won't fit
+    #[arg(long)]
+    force: bool,
+    /// Print the packages and APK paths that would be pulled, without pulling
+    #[arg(long)]
+    dry_run: bool,
+    /// Log one line per file instead of drawing a progress bar
+    #[arg(long)]
+    no_progress: bool,
+    /// After a successful backup, delete the oldest ones so at most N remain
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    keep: Option<u32>,
+    /// Continue an interrupted backup in this folder under the backup dir
+    ///
+    /// Packages whose folder already holds every APK the device reports, at
+    /// the device's size, are kept as they are; the rest are pulled again.
+    /// A differential backup's base is read from its manifest, or can be
+    /// given with --diff --base.
+    #[arg(long, value_name = "FOLDER", conflicts_with_all = ["new", "name"])]
+    resume: Option<String>,
+    /// Ask `pm path` once more, after a short wait, when it lists no APKs
+    /// for a package
+    ///
+    /// Packages it still lists nothing for are skipped, not failed.
+    #[arg(long)]
+    retry_pm_path: bool,
+    /// Keep the APK file names from the device
+    ///
+    /// By default each APK is saved as base.apk or split_<name>.apk after
+    /// the split its manifest names, so backups read the same whatever the
+    /// device called the files. Either way the manifest records each file's
+    /// split, except with --stream.
+    #[arg(long)]
+    no_splits_merge: bool,
+    /// Stop starting new packages after the first one fails
+    ///
+    /// Packages already running finish, and the manifest records what was
+    /// backed up. The backup is then neither compressed nor rotated.
+    #[arg(long)]
+    stop_on_error: bool,
+}
+
+/// A `backup --since` cutoff.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Since {
+    Ago(chrono::Duration),
+    At(NaiveDateTime),
+}
+
+pub(crate) fn parse_since(value: &str) -> Result<Since, String> {
+    let value = value.trim();
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ] {
+        if let Ok(at) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(Since::At(at));
+        }
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(Since::At(date.and_time(chrono::NaiveTime::MIN)));
+    }
+
+    let invalid = || format!("{:?} is neither a duration like 7d nor a date", value);
+    let (unit_at, _) = value.char_indices().next_back().ok_or_else(invalid)?;
+    let (count, unit) = value.split_at(unit_at);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let duration = match unit {
+        "m" => chrono::Duration::try_minutes(count),
+        "h" => chrono::Duration::try_hours(count),
+        "d" => chrono::Duration::try_days(count),
+        "w" => chrono::Duration::try_weeks(count),
+        _ => None,
+    };
+    duration
+        .filter(|duration| *duration >= chrono::Duration::zero())
+        .map(Since::Ago)
+        .ok_or_else(invalid)
+}
+
+impl BackupArgs {
+    /// Parses `backup` options as the command line does, without the
+    /// subcommand name, e.g. `["--new", "--with-data"]`.
+    pub fn parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        parse_args("backup", args)
+    }
+}
+
+/// The order of the packages in a backup's work queue.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Schedule {
+    /// Biggest packages first
+    LargestFirst,
+    /// As `pm list packages` lists them
+    DeviceOrder,
+}
+
+/// Orders the packages for [`Schedule`], using their total APK sizes;
+/// packages without a size go last. The sort is stable, so packages of the
+/// same size keep the device's order.
+fn schedule_packages(packages: &mut [String], schedule: Schedule, sizes: &HashMap<String, u64>) {
+    if schedule == Schedule::LargestFirst {
+        packages.sort_by_key(|package| std::cmp::Reverse(sizes.get(package).copied().unwrap_or(0)));
+    }
+}
+
+pub(crate) fn run_backup_mode(
+    adb: &dyn AdbRunner,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<()> {
+    let Some(report) = backup(adb, args, config)? else {
+        return Ok(());
+    };
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    report.check()
+}
+
+/// Makes a backup as `backup` does. Unlike the subcommand it never reads
+/// stdin, so the options must give the mode, and `--overwrite` replaces a
+/// folder without asking. Returns `None` when nothing was backed up on
+/// purpose, such as a dry run. Packages that failed are reported, not
+/// returned as an error.
+pub fn backup(
+    adb: &dyn AdbRunner,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<Option<BackupReport>> {
+    // Check for a way to copy the backup before making it.
+    let tool = match &args.remote {
+        Some(_) if !args.dry_run => Some(remote::Tool::find().ok_or_else(|| {
+            AppError::Transfer("--remote needs rsync or scp, but neither was found.".to_string())
+        })?),
+        _ => None,
+    };
+    let Some(mut report) = make_backup(adb, args, config)? else {
+        return Ok(None);
+    };
+    // A backup stopped early isn't worth keeping a copy of yet.
+    if let (Some(remote), Some(tool)) = (&args.remote, tool)
+        && report.not_started.is_none()
+    {
+        let local = report.archive.as_deref().unwrap_or(&report.backup);
+        status!("Copying {:?} to {}...", local.file_name().unwrap(), remote);
+        remote::push(tool, local, remote).map_err(|e| {
+            AppError::Transfer(format!(
+                "Backed up to {}, but copying it to {} failed: {}",
+                local.display(),
+                remote,
+                e
+            ))
+        })?;
+        status!("✓ Copied to {}", remote);
+        report.remote = Some(remote.to_string());
+    }
+    Ok(Some(report))
+}
+
+/// Makes the local backup for [`backup`].
+fn make_backup(
+    adb: &dyn AdbRunner,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<Option<BackupReport>> {
+    connect_device(adb, &config.warnings)?;
+
+    let backup_root = config.backup_dir.as_path();
+    if !backup_root.exists() && !args.dry_run {
+        fs::create_dir_all(backup_root)?;
+    }
+
+    if let Some(folder) = &args.resume {
+        let target_dir = backup_root.join(folder);
+        if !target_dir.is_dir() {
+            return Err(AppError::BackupNotFound(folder.clone()));
+        }
+        let base = args.base.clone().or_else(|| {
+            Manifest::load(&target_dir)
+                .ok()
+                .flatten()
+                .and_then(|manifest| manifest.base)
+        });
+        let base_backup = base.map(|base| backup_root.join(base));
+        if let Some(base_backup) = &base_backup
+            && !base_backup.is_dir()
+        {
+            return Err(AppError::BackupNotFound(base_backup.display().to_string()));
+        }
+        status!("Resuming {}", folder);
+        return perform_backup(adb, &target_dir, base_backup.as_deref(), args, config);
+    }
+
+    let timestamp = backup_timestamp(adb, config.name_format.as_deref())?;
+
+    if args.new {
+        let name = args.name.as_deref().unwrap_or("");
+        let folder_name = backup_folder_name(
+            backup_root,
+            name,
+            &timestamp,
+            &timestamp,
+            args.overwrite,
+            &config.warnings,
+        )?;
+        return start_backup(adb, &backup_root.join(folder_name), None, args, config);
+    }
+
+    if let Some(base) = &args.base {
+        let base_backup = backup_root.join(base);
+        if !base_backup.is_dir() {
+            return Err(AppError::BackupNotFound(base.clone()));
+        }
+        let default_name = diff_folder_name(base, &timestamp);
+        let name = args.name.as_deref().unwrap_or("");
+        let folder_name = backup_folder_name(
+            backup_root,
+            name,
+            &timestamp,
+            &default_name,
+            args.overwrite,
+            &config.warnings,
+        )?;
+        return start_backup(
+            adb,
+            &backup_root.join(folder_name),
+            Some(&base_backup),
+            args,
+            config,
+        );
+    }
+
+    if args.auto_base {
+        let serial =
+            device_serial(adb).ok_or("Could not read the device serial to find a base backup.")?;
+        let name = args.name.as_deref().unwrap_or("");
+        let Some(base_backup) = find_auto_base(backup_root, &serial) else {
+            status!(
+                "No complete backup of {} found; making a full backup.",
+                serial
+            );
+            let folder_name = backup_folder_name(
+                backup_root,
+                name,
+                &timestamp,
+                &timestamp,
+                args.overwrite,
+                &config.warnings,
+            )?;
+            return start_backup(adb, &backup_root.join(folder_name), None, args, config);
+        };
+        let base = base_backup
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        if args.link_unchanged {
+            status!(
+                "Using {} as the base: the newest complete backup of {}, with {} package(s) to link from.",
+                base,
+                serial,
+                linkable_base_packages(&base_backup).len()
+            );
+        } else {
+            status!(
+                "Using {} as the base: the newest complete backup of {}.",
+                base,
+                serial
+            );
+        }
+        let default_name = diff_folder_name(&base, &timestamp);
+        let folder_name = backup_folder_name(
+            backup_root,
+            name,
+            &timestamp,
+            &default_name,
+            args.overwrite,
+            &config.warnings,
+        )?;
+        return start_backup(
+            adb,
+            &backup_root.join(folder_name),
+            Some(&base_backup),
+            args,
+            config,
+        );
+    }
+
+    if !config.interactive {
+        return Err(
+            "Choose the backup mode with --new, --diff --base, --auto-base or --resume.".into(),
+        );
+    }
+    loop {
+        prompt!("Select backup mode:\n");
+        prompt!("1. New Backup\n");
+        prompt!("2. Differential Backup\n");
+        prompt!(": ");
+        io::stdout().flush()?;
+
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        match choice.trim() {
+            "1" => return new_backup(adb, backup_root, &timestamp, args, config),
+            "2" => return differential_backup(adb, backup_root, &timestamp, args, config),
+            _ => {
+                eprintln!("Invalid choice. Please enter 1 or 2.");
+                continue;
+            }
+        }
+    }
+}
+
+/// For `--auto-base`: the newest backup folder whose manifest names the
+/// device `serial`, leaving out partial backups and ones with failed
+/// packages.
+fn find_auto_base(backup_root: &Path, serial: &str) -> Option<PathBuf> {
+    let mut candidates: Vec<_> = fs::read_dir(backup_root)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|path| path.is_dir() && !store::is_store(path))
+        .filter(|path| {
+            Manifest::load(path).ok().flatten().is_some_and(|manifest| {
+                manifest.device.serial.as_deref() == Some(serial)
+                    && !manifest.partial
+                    && manifest
+                        .packages
+                        .iter()
+                        .all(|entry| entry.status != PackageStatus::Failed)
+            })
+        })
+        .collect();
+    candidates.sort_by_cached_key(|path| (backup_time(path), path.clone()));
+    candidates.pop()
+}
+
+fn new_backup(
+    adb: &dyn AdbRunner,
+    backup_root: &Path,
+    timestamp: &str,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<Option<BackupReport>> {
+    let folder_name = prompt_backup_name(
+        backup_root,
+        timestamp,
+        timestamp,
+        args.overwrite,
+        &config.warnings,
+    )?;
+    start_backup(adb, &backup_root.join(folder_name), None, args, config)
+}
+
+/// Starts a new or differential backup in `target_dir`, first deleting a
+/// folder already there for `--overwrite`, and refusing one without it.
+/// Returns `None` if the user declined to replace it; without a terminal to
+/// ask on, `--overwrite` is taken as the answer.
+fn start_backup(
+    adb: &dyn AdbRunner,
+    target_dir: &Path,
+    base_backup: Option<&Path>,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<Option<BackupReport>> {
+    if target_dir.exists() && !args.dry_run {
+        let name = target_dir.file_name().unwrap().to_string_lossy();
+        if !args.overwrite {
+            return Err(format!("Folder already exists: {}.", name).into());
+        }
+        if base_backup == Some(target_dir) {
+            return Err(
+                format!("{} is the base of this backup and can't be replaced.", name).into(),
+            );
+        }
+        if !args.yes && config.interactive {
+            prompt!("Replace backup {}? [y/N]: ", name);
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                status!("Aborted.");
+                return Ok(None);
+            }
+        }
+        fs::remove_dir_all(target_dir)?;
+        status!("Removed the old {}", name);
+        prune_store(target_dir.parent().unwrap(), &config.warnings);
+    }
+    perform_backup(adb, target_dir, base_backup, args, config)
+}
+
+/// Asks for a backup folder name until one is usable. An empty answer picks
+/// `default_name`.
+fn prompt_backup_name(
+    backup_root: &Path,
+    timestamp: &str,
+    default_name: &str,
+    overwrite: bool,
+    warnings: &Warnings,
+) -> io::Result<String> {
+    if default_name == timestamp {
+        prompt!("Enter backup name (or leave empty for timestamp):\n");
+    } else {
+        prompt!("Enter backup name (or leave empty for {}):\n", default_name);
+    }
+    let mut name = String::new();
+
+    loop {
+        prompt!("> ");
+        io::stdout().flush()?;
+        name.clear();
+        io::stdin().read_line(&mut name)?;
+
+        match backup_folder_name(
+            backup_root,
+            &name,
+            timestamp,
+            default_name,
+            overwrite,
+            warnings,
+        ) {
+            Ok(folder_name) => return Ok(folder_name),
+            Err(e) => {
+                eprintln!("{} Try another name.", e);
+                continue;
+            }
+        }
+    }
+}
+
+/// Turns a user-entered backup name into a folder name, substituting `$date`
+/// and falling back to `default_name` when the name is empty. A name that is
+/// already taken is refused unless `overwrite` allows replacing it.
+pub(crate) fn backup_folder_name(
+    backup_root: &Path,
+    name: &str,
+    timestamp: &str,
+    default_name: &str,
+    overwrite: bool,
+    warnings: &Warnings,
+) -> Result<String, String> {
+    let trimmed = name.trim();
+    let replaced = if trimmed.is_empty() {
+        default_name.to_string()
+    } else {
+        let replaced = trimmed.replace("$date", timestamp);
+        validate_backup_name(&replaced)?;
+        replaced
+    };
+    if backup_root.join(&replaced).exists() && !overwrite {
+        return Err(format!("Folder already exists: {}.", replaced));
+    }
+    if let Ok(entries) = fs::read_dir(backup_root) {
+        for entry in entries.filter_map(Result::ok) {
+            let existing = entry.file_name().to_string_lossy().to_string();
+            if existing != replaced && existing.trim_end() == replaced {
+                warning!(
+                    warnings,
+                    "Warning: {:?} differs from the existing backup {:?} only by trailing whitespace.",
+                    replaced,
+                    existing
+                );
+            }
+        }
+    }
+    Ok(replaced)
+}
+
+/// Device names Windows reserves, with or without an extension.
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that a backup name is a single, visible folder name, so it can't
+/// land outside the backup directory or fail later when the folder is
+/// created.
+fn validate_backup_name(name: &str) -> Result<(), String> {
+    if let Some(c) = name.chars().find(|c| matches!(c, '/' | '\\' | '\0')) {
+        return Err(format!("Backup names can't contain {:?}.", c));
+    }
+    if name.starts_with('.') {
+        return Err("Backup names can't start with a dot.".to_string());
+    }
+    if cfg!(windows) {
+        if let Some(c) = name
+            .chars()
+            .find(|c| matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') || c.is_control())
+        {
+            return Err(format!("Backup names can't contain {:?} on Windows.", c));
+        }
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        {
+            return Err(format!("{} is a reserved name on Windows.", name));
+        }
+        if name.ends_with('.') {
+            return Err("Backup names can't end with a dot on Windows.".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Checks a `--name-format` value: chrono specifiers chrono can format,
+/// with `{model}` and `{serial}` allowed anywhere.
+pub(crate) fn parse_name_format(format: &str) -> Result<String, String> {
+    let specifiers = format.replace("{model}", "").replace("{serial}", "");
+    if chrono::format::StrftimeItems::new(&specifiers)
+        .any(|item| matches!(item, chrono::format::Item::Error))
+    {
+        return Err(format!("{:?} has an invalid % specifier", format));
+    }
+    Ok(format.to_string())
+}
+
+/// The timestamp part of new backup names, in `format` or
+/// [`DEFAULT_NAME_FORMAT`]. Checked with the same rules as a typed name, so
+/// a format that makes an unusable folder name fails before anything runs.
+fn backup_timestamp(adb: &dyn AdbRunner, format: Option<&str>) -> Result<String> {
+    let Some(format) = format else {
+        return Ok(Local::now().format(DEFAULT_NAME_FORMAT).to_string());
+    };
+    let mut format = format.to_string();
+    if format.contains("{model}") {
+        let model = get_device_output(adb, &["shell", "getprop", "ro.product.model"]);
+        format = format.replace("{model}", &name_token(model));
+    }
+    if format.contains("{serial}") {
+        format = format.replace("{serial}", &name_token(device_serial(adb)));
+    }
+
+    let timestamp = Local::now().format(&format).to_string();
+    let timestamp = timestamp.trim();
+    if timestamp.is_empty() {
+        return Err("--name-format gives an empty backup name.".into());
+    }
+    validate_backup_name(timestamp)
+        .map_err(|e| format!("--name-format gives {:?}: {}", timestamp, e))?;
+    Ok(timestamp.to_string())
+}
+
+/// A device property made safe for a folder name: anything but letters,
+/// digits, `.`, `_` and `-` becomes `_`, which also keeps `%` out of the
+/// format string.
+fn name_token(value: Option<String>) -> String {
+    value
+        .unwrap_or_else(|| "unknown".to_string())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn diff_folder_name(base: &str, timestamp: &str) -> String {
+    format!("{}_diff_{}", base, timestamp)
+}
+
+fn differential_backup(
+    adb: &dyn AdbRunner,
+    backup_root: &Path,
+    timestamp: &str,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<Option<BackupReport>> {
+    let mut entries: Vec<_> = fs::read_dir(backup_root)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|path| path.is_dir() && !store::is_store(path))
+        .collect();
+
+    if entries.is_empty() {
+        eprintln!("No backups found.");
+        return Ok(None);
+    }
+
+    sort_backups(&mut entries, config.backup_order);
+    let names = file_names(&entries);
+    let Some(index) = select_from(&names, "Select base backup:")? else {
+        eprintln!("No base backup selected.");
+        return Ok(None);
+    };
+
+    let base_backup = entries[index].clone();
+    let base_name = names[index].clone();
+    let folder_name = prompt_backup_name(
+        backup_root,
+        timestamp,
+        &diff_folder_name(&base_name, timestamp),
+        args.overwrite,
+        &config.warnings,
+    )?;
+    start_backup(
+        adb,
+        &backup_root.join(folder_name),
+        Some(&base_backup),
+        args,
+        config,
+    )
+}
+
+fn perform_backup(
+    adb: &dyn AdbRunner,
+    target_dir: &Path,
+    base_backup: Option<&Path>,
+    args: &BackupArgs,
+    config: &Config,
+) -> Result<Option<BackupReport>> {
+    let warnings_before = config.warnings.count();
+    // Packages named in --from-file and --from-report, with where from.
+    let listed: Vec<(&Path, &[String])> = args
+        .from_file
+        .iter()
+        .map(|list| (list.path.as_path(), list.packages.as_slice()))
+        .chain(
+            args.from_report
+                .iter()
+                .map(|report| (report.path.as_path(), report.selected(args.failed_only))),
+        )
+        .collect();
+    let system_packages: HashSet<String> =
+        if args.include_system || !args.packages.is_empty() || !listed.is_empty() {
+            get_packages(adb, "-s")?.into_iter().collect()
+        } else {
+            HashSet::new()
+        };
+    let device_packages = if args.packages.is_empty() && listed.is_empty() {
+        let mut packages = get_third_party_packages(adb)?;
+        if args.include_system {
+            let mut system: Vec<_> = system_packages
+                .iter()
+                .filter(|package| !packages.contains(package))
+                .cloned()
+                .collect();
+            system.sort();
+            packages.extend(system);
+        }
+        packages
+    } else {
+        for package in &args.packages {
+            if get_package_paths(adb, package).is_err() {
+                return Err(format!("Package {} is not installed on the device.", package).into());
+            }
+        }
+        let mut packages = args.packages.clone();
+        if !listed.is_empty() {
+            let installed: HashSet<String> = get_third_party_packages(adb)?
+                .into_iter()
+                .chain(system_packages.iter().cloned())
+                .collect();
+            for (path, listed_packages) in &listed {
+                for package in *listed_packages {
+                    if packages.contains(package) {
+                        continue;
+                    }
+                    if installed.contains(package) {
+                        packages.push(package.clone());
+                    } else {
+                        warning!(
+                            config.warnings,
+                            "Warning: {} (listed in {}) is not installed on the device.",
+                            package,
+                            path.display()
+                        );
+                    }
+                }
+            }
+        }
+        packages
+    };
+    let device_packages: Vec<String> = device_packages
+        .into_iter()
+        .filter(|package| {
+            (config.includes.is_empty() || config.includes.iter().any(|re| re.is_match(package)))
+                && !config.excludes.iter().any(|re| re.is_match(package))
+        })
+        .collect();
+    let device_packages = match args.since {
+        Some(since) => updated_since(adb, device_packages, since, &config.warnings),
+        None => device_packages,
+    };
+    let version_codes = get_version_codes(adb, &device_packages);
+    let installers = get_installers(adb, args.include_system);
+
+    // With --link-unchanged, packages the version codes say are unchanged
+    // are still backed up, but expected to be linked rather than pulled.
+    let mut expected_unchanged = HashSet::new();
+    let packages_to_backup: Vec<_> = match base_backup {
+        Some(base) => {
+            let changes = classify_changes(&device_packages, &version_codes, base)?;
+            let count = |kind| changes.iter().filter(|(_, k)| *k == kind).count();
+            status!(
+                "Differential: {} new, {} updated, {} unchanged",
+                count(Change::New),
+                count(Change::Updated),
+                count(Change::Unchanged)
+            );
+            changes
+                .into_iter()
+                .filter(|(package, kind)| {
+                    if *kind != Change::Unchanged {
+                        return true;
+                    }
+                    if args.link_unchanged {
+                        expected_unchanged.insert(package.clone());
+                    }
+                    args.link_unchanged
+                })
+                .map(|(package, _)| package)
+                .collect()
+        }
+        None => device_packages,
+    };
+
+    if packages_to_backup.is_empty() {
+        status!("No package differences found for device.")
+    }
+
+    let splits = SplitFilter {
+        abis: &args.keep_abis,
+        densities: &args.keep_densities,
+    };
+    if args.dry_run {
+        print_backup_plan(adb, target_dir, &packages_to_backup, splits);
+        return Ok(None);
+    }
+
+    // The progress bar is only drawn on a terminal, and never under --json
+    // where stdout is reserved for the summary, or under --quiet.
+    let show_progress =
+        !args.no_progress && !output::quiet() && io::stdout().is_terminal() && !output::json();
+    let package_sizes: Vec<(String, Vec<(String, u64)>)> =
+        if !args.force || show_progress || args.schedule == Schedule::LargestFirst {
+            packages_to_backup
+                .iter()
+                .filter(|package| !expected_unchanged.contains(*package))
+                .map(|package| {
+                    let sizes = device_apk_sizes(adb, package);
+                    let paths: Vec<_> = sizes.iter().map(|(path, _)| path.clone()).collect();
+                    let (kept, _) = splits.apply(&paths);
+                    let sizes = sizes
+                        .into_iter()
+                        .filter(|(path, _)| kept.contains(path))
+                        .collect();
+                    (package.clone(), sizes)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+    let totals: HashMap<String, u64> = package_sizes
+        .iter()
+        .map(|(package, sizes)| (package.clone(), sizes.iter().map(|(_, size)| size).sum()))
+        .collect();
+    let mut packages_to_backup = packages_to_backup;
+    schedule_packages(&mut packages_to_backup, args.schedule, &totals);
+    let apk_sizes: HashMap<String, u64> = package_sizes
+        .into_iter()
+        .flat_map(|(_, sizes)| sizes)
+        .collect();
+    if !args.force {
+        check_free_space(target_dir, apk_sizes.values().sum())?;
+        // --stream builds the whole archive in the temp folder first.
+        if args.stream {
+            check_temp_dir(&config.temp_dir, apk_sizes.values().sum())?;
+        }
+    }
+    let progress = show_progress.then(|| TransferProgress::new(apk_sizes));
+    let started = Instant::now();
+    fs::create_dir_all(target_dir)?;
+    let store = if args.dedup {
+        Some(ObjectStore::open(target_dir.parent().unwrap())?)
+    } else {
+        None
+    };
+    let stream = if args.stream {
+        let exec_out = exec_out_works(adb);
+        if !exec_out {
+            warning!(
+                config.warnings,
+                "Warning: adb exec-out doesn't work here; each APK is pulled to a scratch file first."
+            );
+        }
+        Some(StreamTarget {
+            archive: StreamArchive::create(target_dir, args.compression_level, &config.temp_dir)?,
+            exec_out,
+        })
+    } else {
+        None
+    };
+
+    if args.capture_launcher {
+        capture_launcher(adb, target_dir, &config.warnings);
+    }
+
+    events::emit(Event::BackupStarted {
+        backup: target_dir,
+        packages: packages_to_backup.len(),
+    });
+    let catch_interrupt = interrupt::catch();
+    let pause = if config.interactive {
+        PauseControl::spawn()
+    } else {
+        None
+    };
+    if pause.is_some() && !packages_to_backup.is_empty() {
+        status!("(Type 'p' and press Enter to pause after the current package.)");
+    }
+
+    let jobs = config
+        .jobs
+        .map(usize::from)
+        .unwrap_or_else(default_jobs)
+        .clamp(1, packages_to_backup.len().max(1));
+    let next_package = AtomicUsize::new(0);
+    let output_lock = Mutex::new(());
+    let failed = Mutex::new(Vec::new());
+    let no_paths = Mutex::new(Vec::new());
+    let stopped = AtomicBool::new(false);
+    let entries = Mutex::new(Vec::new());
+
+    let pulls_done = AtomicBool::new(false);
+    let has_root = args.with_data && device_has_root(adb);
+    let base_packages = match base_backup {
+        Some(base) if args.link_unchanged => {
+            if !base.join(manifest::MANIFEST_FILE).is_file() {
+                status!(
+                    "{:?} has no manifest.json to compare with, so its packages are pulled again.",
+                    base.file_name().unwrap_or_default()
+                );
+            }
+            linkable_base_packages(base)
+        }
+        _ => HashMap::new(),
+    };
+
+    thread::scope(|scope| {
+        if let Some(progress) = &progress {
+            scope.spawn(|| {
+                while !pulls_done.load(Ordering::Relaxed) {
+                    thread::sleep(PROGRESS_REDRAW_INTERVAL);
+                    let _output = output_lock.lock().unwrap();
+                    progress::clear_line();
+                    print!("{}", progress.render());
+                    let _ = io::stdout().flush();
+                }
+                let _output = output_lock.lock().unwrap();
+                progress::clear_line();
+                let _ = io::stdout().flush();
+            });
+        }
+
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    loop {
+                        if let Some(pause) = &pause {
+                            pause.wait_if_requested();
+                        }
+                        if stopped.load(Ordering::Relaxed) || interrupt::requested() {
+                            break;
+                        }
+                        let index = next_package.fetch_add(1, Ordering::Relaxed);
+                        let Some(package) = packages_to_backup.get(index) else {
+                            break;
+                        };
+                        events::emit(Event::PackageStarted {
+                            package,
+                            index: index + 1,
+                            total: packages_to_backup.len(),
+                        });
+
+                        let header = format!(
+                            "Backing up {} of {} ({})",
+                            index + 1,
+                            packages_to_backup.len(),
+                            package
+                        );
+                        let mut log = PackageLog::default();
+                        // Sequential runs show progress as it happens; parallel
+                        // ones print the header with the rest of the package's
+                        // block.
+                        if jobs == 1 {
+                            let _output = output_lock.lock().unwrap();
+                            if progress.is_some() {
+                                progress::clear_line();
+                            }
+                            status!("{}", header);
+                        } else {
+                            log.info(header);
+                        }
+
+                        let package_dir = target_dir.join(package);
+                        let options = PullOptions {
+                            preserve_timestamps: args.preserve_timestamps,
+                            record_stats: args.record_file_stats,
+                            splits,
+                            retry_pm_path: args.retry_pm_path,
+                            split_names: !args.no_splits_merge,
+                            retries: config.retries,
+                            store: store.as_ref(),
+                            stream: stream.as_ref(),
+                            progress: progress.as_ref(),
+                        };
+                        let resumed = if args.resume.is_some() {
+                            completed_apks(adb, package, &package_dir, &options)
+                        } else {
+                            None
+                        };
+                        // Extras are only captured along with fresh APKs; a
+                        // kept package keeps what it already has.
+                        let pulled = resumed.is_none();
+                        let result = match resumed {
+                            Some(apks) => {
+                                log.info("    Already backed up, keeping it".to_string());
+                                Ok(apks)
+                            }
+                            None => {
+                                // Clear out partial pulls so they aren't kept
+                                // next to fresh copies.
+                                if args.resume.is_some() && package_dir.exists() {
+                                    let _ = fs::remove_dir_all(&package_dir);
+                                }
+                                let linked =
+                                    base_packages.get(package).and_then(|(base_dir, base)| {
+                                        unchanged_apks(
+                                            adb,
+                                            package,
+                                            &package_dir,
+                                            base_dir,
+                                            base,
+                                            &options,
+                                        )
+                                    });
+                                match linked {
+                                    Some(apks) => {
+                                        log.info(
+                                            "    Unchanged since the base, linked its APKs"
+                                                .to_string(),
+                                        );
+                                        Ok(apks)
+                                    }
+                                    None => {
+                                        extract_apk(adb, package, target_dir, &options, &mut log)
+                                    }
+                                }
+                            }
+                        };
+                        let (result, dropped_splits) = match result {
+                            Ok((files, dropped)) => (Ok(files), dropped),
+                            Err(e) => (Err(e), Vec::new()),
+                        };
+                        let pulled = pulled && result.is_ok();
+                        if pulled && args.with_external_data {
+                            extract_shared_dir(
+                                adb,
+                                &EXTERNAL_DATA,
+                                package,
+                                &target_dir.join(package),
+                                &mut log,
+                            );
+                        }
+                        if pulled && args.with_obb {
+                            extract_shared_dir(
+                                adb,
+                                &OBB_FILES,
+                                package,
+                                &target_dir.join(package),
+                                &mut log,
+                            );
+                        }
+                        if pulled && args.with_data {
+                            extract_app_data(
+                                adb,
+                                package,
+                                &target_dir.join(package),
+                                has_root,
+                                &mut log,
+                            );
+                        }
+                        let permissions = if pulled && args.with_permissions {
+                            granted_permissions(adb, package, &mut log)
+                        } else {
+                            Vec::new()
+                        };
+
+                        // Streamed APKs aren't on disk to read.
+                        let apk_info =
+                            result
+                                .as_ref()
+                                .ok()
+                                .filter(|_| stream.is_none())
+                                .and_then(|files| {
+                                    read_base_apk_info(&target_dir.join(package), files, &mut log)
+                                });
+                        let mut entry = package_entry(
+                            package,
+                            &target_dir.join(package),
+                            version_codes.get(package).copied(),
+                            installers.get(package).cloned(),
+                            apk_info.unwrap_or_default(),
+                            &result,
+                        );
+                        entry.system = system_packages.contains(package);
+                        entry.permissions = permissions;
+                        entry.dropped_splits = dropped_splits;
+                        let bytes = entry.total_bytes;
+                        entries.lock().unwrap().push(entry);
+
+                        let _output = output_lock.lock().unwrap();
+                        if progress.is_some() {
+                            progress::clear_line();
+                        }
+                        log.print(&config.warnings);
+                        match result {
+                            Ok(_) => {
+                                events::emit(Event::PackageCompleted {
+                                    package,
+                                    bytes: Some(bytes),
+                                });
+                                status!("  ✓ {}", package);
+                            }
+                            Err(AppError::NoApkPaths(_)) => {
+                                events::emit(Event::PackageSkipped {
+                                    package,
+                                    reason: Some("no APK paths"),
+                                });
+                                status!("  - {} skipped: pm path lists no APKs", package);
+                                no_paths.lock().unwrap().push(package.clone());
+                            }
+                            Err(e) => {
+                                events::emit(Event::PackageFailed {
+                                    package,
+                                    error: &e.to_string(),
+                                });
+                                failure!("  ✗ {} failed: {}", package, e);
+                                failed.lock().unwrap().push(package.clone());
+                                if args.stop_on_error {
+                                    stopped.store(true, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            if let Err(panic) = worker.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+        pulls_done.store(true, Ordering::Relaxed);
+    });
+    // Stops the pause reader before anything else reads stdin.
+    drop(pause);
+
+    let mut entries = entries.into_inner().unwrap();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    // A linked backup holds every package itself, so it has no base.
+    let manifest_base = base_backup.filter(|_| !args.link_unchanged);
+    let interrupted = interrupt::requested();
+    let warnings = config.warnings.count() - warnings_before;
+    let strict_failed = config.strict && warnings > 0;
+    write_manifest(
+        adb,
+        target_dir,
+        manifest_base,
+        entries.clone(),
+        ManifestStatus {
+            partial: interrupted,
+            warnings,
+            strict_failed,
+        },
+    )?;
+    // A streamed backup only exists as its archive, so it is finished even
+    // when --stop-on-error or Ctrl-C stopped the run.
+    let streamed = match stream {
+        Some(stream) => {
+            let archive_path = stream.archive.finish(target_dir)?;
+            fs::remove_dir_all(target_dir)?;
+            Some(archive_path)
+        }
+        None => None,
+    };
+    drop(catch_interrupt);
+
+    let failed = failed.into_inner().unwrap();
+    let mut no_paths = no_paths.into_inner().unwrap();
+    no_paths.sort();
+    let total_bytes: u64 = entries.iter().map(|entry| entry.total_bytes).sum();
+    let elapsed = started.elapsed();
+    let skipped = packages_to_backup.len() - entries.len();
+    events::emit(Event::BackupCompleted {
+        backup: target_dir,
+        succeeded: entries.len() - failed.len() - no_paths.len(),
+        failed: failed.len(),
+        skipped: skipped + no_paths.len(),
+        total_bytes,
+        duration_secs: elapsed.as_secs_f64(),
+    });
+    if stopped.into_inner() || interrupted {
+        return Ok(Some(BackupReport {
+            backup: target_dir.to_path_buf(),
+            archive: streamed,
+            total_bytes,
+            duration_secs: elapsed.as_secs_f64(),
+            packages: entries,
+            not_started: Some(skipped),
+            interrupted,
+            warnings,
+            strict_failed,
+            remote: None,
+        }));
+    }
+    if !packages_to_backup.is_empty() {
+        summary!(
+            "Backed up {} of {} packages, {} in {}.",
+            packages_to_backup.len() - failed.len() - no_paths.len(),
+            packages_to_backup.len(),
+            progress::format_size(total_bytes),
+            progress::format_duration(elapsed)
+        );
+    }
+    if !no_paths.is_empty() {
+        summary!(
+            "{} skipped (no APK paths): {}",
+            no_paths.len(),
+            no_paths.join(", ")
+        );
+    }
+    if !failed.is_empty() {
+        summary!("{} failed: {}", failed.len(), failed.join(", "));
+    }
+
+    let mut archive = streamed;
+    if let Some(archive_path) = &archive {
+        status!("✓ Wrote {:?}", archive_path);
+    } else if args.compress {
+        status!("Compressing backup...");
+        let archive_path = archive::compress_dir(target_dir, args.compression_level)?;
+        status!("✓ Wrote {:?}", archive_path);
+        if args.remove_uncompressed {
+            fs::remove_dir_all(target_dir)?;
+        }
+        archive = Some(archive_path);
+    }
+
+    if let Some(keep) = args.keep
+        && failed.is_empty()
+    {
+        let current = archive.as_deref().unwrap_or(target_dir);
+        rotate_backups(
+            target_dir.parent().unwrap(),
+            keep,
+            Some(current),
+            &config.warnings,
+        )?;
+    }
+
+    Ok(Some(BackupReport {
+        backup: target_dir.to_path_buf(),
+        archive,
+        total_bytes,
+        duration_secs: elapsed.as_secs_f64(),
+        packages: entries,
+        not_started: None,
+        interrupted: false,
+        warnings,
+        strict_failed,
+        remote: None,
+    }))
+}
+
+/// Deletes the oldest backups (folders and archives, by modification time)
+/// under `backup_root` until at most `keep` remain. `current` is never
+/// deleted, and neither is a folder that a kept differential backup uses as
+/// its base.
+pub(crate) fn rotate_backups(
+    backup_root: &Path,
+    keep: u32,
+    current: Option<&Path>,
+    warnings: &Warnings,
+) -> Result<()> {
+    if !backup_root.exists() {
+        return Ok(());
+    }
+
+    let mut backups: Vec<(SystemTime, PathBuf)> = fs::read_dir(backup_root)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|path| (path.is_dir() && !store::is_store(path)) || is_archive(path))
+        .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+        .collect();
+    // Newest first.
+    backups.sort_by_key(|(modified, _)| Reverse(*modified));
+
+    let is_current = |path: &Path| current.is_some_and(|current| current == path);
+    let mut kept: Vec<PathBuf> = backups
+        .iter()
+        .map(|(_, path)| path)
+        .filter(|path| is_current(path))
+        .cloned()
+        .collect();
+    for (_, path) in &backups {
+        if kept.len() >= keep as usize {
+            break;
+        }
+        if !is_current(path) {
+            kept.push(path.clone());
+        }
+    }
+
+    // A differential backup needs every base up its chain, not just its own.
+    let bases: HashSet<String> = kept
+        .iter()
+        .flat_map(|path| base_chain(path).into_iter().skip(1))
+        .map(|(dir, _)| dir.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+
+    let mut removed = 0;
+    for (_, path) in &backups {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if kept.contains(path) {
+            continue;
+        }
+        if bases.contains(&name) {
+            status!("Keeping {} (a base of a kept differential backup)", name);
+            continue;
+        }
+
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        status!("Removed old backup {}", name);
+        removed += 1;
+    }
+
+    if removed == 0 {
+        status!("No backups to prune.");
+    } else {
+        prune_store(backup_root, warnings);
+    }
+    Ok(())
+}
+
+/// Drops objects from the `--dedup` store that no remaining backup uses.
+pub(crate) fn prune_store(backup_root: &Path, warnings: &Warnings) {
+    match store::prune(backup_root) {
+        Ok(0) => {}
+        Ok(removed) => status!("Removed {} unused deduplicated file(s)", removed),
+        Err(e) => warning!(
+            warnings,
+            "Warning: Could not clean up {}: {}",
+            store::STORE_DIR,
+            e
+        ),
+    }
+}
+
+/// Summary printed under `--json` and returned by [`backup`]. `packages`
+/// holds this run's manifest entries; file names are relative to each
+/// package folder.
+#[derive(Serialize)]
+pub struct BackupReport {
+    pub backup: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<PathBuf>,
+    /// Size of the APKs in `packages`, and how long pulling them took.
+    pub total_bytes: u64,
+    pub duration_secs: f64,
+    pub packages: Vec<PackageEntry>,
+    /// How many packages were never started because `--stop-on-error` or
+    /// Ctrl-C stopped the run, if one did.
+    #[serde(skip)]
+    pub not_started: Option<usize>,
+    /// Ctrl-C stopped the run; the manifest is marked partial.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+    /// Warnings printed during the backup, and whether `--strict` failed it
+    /// for them; both are also in the manifest.
+    #[serde(skip_serializing_if = "manifest::is_zero")]
+    pub warnings: usize,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub strict_failed: bool,
+    /// Where `--remote` copied the backup to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+}
+
+impl BackupReport {
+    /// Names of the packages that failed to back up.
+    pub fn failed(&self) -> Vec<&str> {
+        self.with_status(PackageStatus::Failed)
+    }
+
+    /// Names of the packages skipped because `pm path` listed no APKs.
+    pub fn skipped(&self) -> Vec<&str> {
+        self.with_status(PackageStatus::Skipped)
+    }
+
+    fn with_status(&self, status: PackageStatus) -> Vec<&str> {
+        self.packages
+            .iter()
+            .filter(|entry| entry.status == status)
+            .map(|entry| entry.name.as_str())
+            .collect()
+    }
+
+    /// The error the `backup` command exits with for this report, if any
+    /// package failed.
+    fn check(&self) -> Result<()> {
+        let failed = self.failed();
+        if self.interrupted {
+            let resume = match &self.archive {
+                Some(_) => String::new(),
+                None => format!(
+                    " Run `apktool backup --resume {}` to finish it.",
+                    self.backup
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                ),
+            };
+            return Err(AppError::Interrupted(format!(
+                "Interrupted; {} package(s) not started.{}",
+                self.not_started.unwrap_or_default(),
+                resume
+            )));
+        }
+        if let Some(not_started) = self.not_started {
+            return Err(AppError::PackagesFailed(format!(
+                "Stopped after {} failed to back up (--stop-on-error); {} package(s) not started.",
+                failed.join(", "),
+                not_started
+            )));
+        }
+        if !failed.is_empty() {
+            return Err(AppError::PackagesFailed(format!(
+                "{} package(s) failed to back up",
+                failed.len()
+            )));
+        }
+        if self.strict_failed {
+            return Err(AppError::StrictWarnings(self.warnings));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Change {
+    New,
+    Updated,
+    Unchanged,
+}
+
+/// Compares device packages against a base backup. Packages are matched on
+/// the version code recorded in the base manifest; bases without a manifest
+/// only tell us which packages are present.
+fn classify_changes(
+    device_packages: &[String],
+    version_codes: &HashMap<String, u64>,
+    base: &Path,
+) -> Result<Vec<(String, Change)>> {
+    let base_versions: HashMap<String, Option<u64>> = match Manifest::load(base)? {
+        Some(manifest) => manifest
+            .packages
+            .into_iter()
+            .filter(|entry| entry.status == PackageStatus::Ok)
+            .map(|entry| (entry.name, entry.version_code))
+            .collect(),
+        None => fs::read_dir(base)?
+            .filter_map(Result::ok)
+            .filter(|e| e.path().is_dir())
+            .map(|e| (e.file_name().to_string_lossy().to_string(), None))
+            .collect(),
+    };
+
+    Ok(device_packages
+        .iter()
+        .map(|package| {
+            let change = match base_versions.get(package) {
+                None => Change::New,
+                Some(Some(base_version)) => match version_codes.get(package) {
+                    Some(device_version) if device_version > base_version => Change::Updated,
+                    _ => Change::Unchanged,
+                },
+                Some(None) => Change::Unchanged,
+            };
+            (package.clone(), change)
+        })
+        .collect())
+}
+
+/// Lists what `perform_backup` would pull for `--dry-run`.
+fn print_backup_plan(
+    adb: &dyn AdbRunner,
+    target_dir: &Path,
+    packages: &[String],
+    splits: SplitFilter,
+) {
+    status!(
+        "Dry run: would back up {} package(s) into {:?}",
+        packages.len(),
+        target_dir
+    );
+    for package in packages {
+        status!("  {}", package);
+        match get_package_paths(adb, package) {
+            Ok(paths) => {
+                let (kept, dropped) = splits.apply(&paths);
+                for path in kept {
+                    status!("    {}", path);
+                }
+                for path in dropped {
+                    status!("    (leave out {})", path);
+                }
+            }
+            Err(e) => status!("    (could not list APKs: {})", e),
+        }
+    }
+}
+
+/// Fails if the disk holding `target_dir` can't fit `required` bytes of APKs
+/// plus [`FREE_SPACE_MARGIN_BYTES`].
+fn check_free_space(target_dir: &Path, required: u64) -> Result<()> {
+    let available = available_space(target_dir)?;
+
+    if required + FREE_SPACE_MARGIN_BYTES > available {
+        return Err(format!(
+            "Not enough free space: the APKs need {:.1} MiB (plus a {} MiB margin) but only {:.1} MiB is available. Use --force to back up anyway.",
+            required as f64 / (1024.0 * 1024.0),
+            FREE_SPACE_MARGIN_BYTES / (1024 * 1024),
+            available as f64 / (1024.0 * 1024.0)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Fails unless `dir` is a folder that can be written to, with room for
+/// `required` bytes plus [`FREE_SPACE_MARGIN_BYTES`].
+pub(crate) fn check_temp_dir(dir: &Path, required: u64) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(format!("Temp folder {} doesn't exist.", dir.display()).into());
+    }
+    TempDir::new(dir, "apktool-probe")
+        .map_err(|e| format!("Cannot write to temp folder {}: {}", dir.display(), e))?;
+    let available = available_space(dir)?;
+    if required + FREE_SPACE_MARGIN_BYTES > available {
+        return Err(format!(
+            "Not enough free space in temp folder {}: {:.1} MiB needed (plus a {} MiB margin) but only {:.1} MiB is available.",
+            dir.display(),
+            required as f64 / (1024.0 * 1024.0),
+            FREE_SPACE_MARGIN_BYTES / (1024 * 1024),
+            available as f64 / (1024.0 * 1024.0)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Free space on the filesystem holding `dir`. The folder may not exist
+/// yet, so the nearest existing ancestor is measured instead.
+pub(crate) fn available_space(dir: &Path) -> io::Result<u64> {
+    let existing_dir = dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    fs4::available_space(existing_dir)
+}
+
+/// On-device sizes of a package's APK files by device path. Files whose
+/// size can't be read are left out, so they count as zero; external data
+/// isn't included.
+fn device_apk_sizes(adb: &dyn AdbRunner, package: &str) -> Vec<(String, u64)> {
+    let Ok(paths) = get_package_paths(adb, package) else {
+        return vec![];
+    };
+    stat_device_files(adb, &paths)
+        .into_iter()
+        .map(|(path, stat)| (path, stat.size))
+        .collect()
+}
+
+/// For `--resume`: the package's files, hashed for the manifest, if its
+/// folder already holds one APK for every path `pm path` reports and each
+/// matches the size the device reports. A different file count or a short
+/// file means the package is incomplete. Kept files are counted as done in
+/// `progress`.
+fn completed_apks(
+    adb: &dyn AdbRunner,
+    package: &str,
+    package_dir: &Path,
+    options: &PullOptions,
+) -> Option<(Vec<ApkFile>, Vec<String>)> {
+    let (apk_paths, dropped) = options.splits.apply(&get_package_paths(adb, package).ok()?);
+    if find_apk_files(package_dir).ok()?.len() != apk_paths.len() {
+        return None;
+    }
+    let stats = stat_device_files(adb, &apk_paths);
+
+    let files = apk_paths
+        .iter()
+        .map(|apk_path| {
+            let name = Path::new(apk_path).file_name()?;
+            let local_path = package_dir.join(name);
+            let len = fs::metadata(&local_path).ok()?.len();
+            let stat = stats.get(apk_path);
+            if stat.is_some_and(|stat| stat.size != len) {
+                return None;
+            }
+            let (device_size, device_modified_at) = options.recorded_stat(stat);
+            Some(ApkFile {
+                name: name.to_string_lossy().to_string(),
+                sha256: Some(sha256_file(&local_path).ok()?),
+                device_size,
+                device_modified_at,
+                split: apk_split_name(&local_path),
+                device_name: None,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    if let Some(progress) = options.progress {
+        for apk_path in &apk_paths {
+            progress.skip(apk_path);
+        }
+    }
+    Some((files, split_file_names(&dropped)))
+}
+
+/// For `--link-unchanged`: the package folders the base backup can link
+/// APKs from, with their manifest entries, by package name. A differential
+/// base lends its own base's packages as well, nearer backups first.
+/// Entries without a checksum for every file can't be compared and are left
+/// out.
+fn linkable_base_packages(base: &Path) -> HashMap<String, (PathBuf, PackageEntry)> {
+    let mut packages = HashMap::new();
+    for (dir, manifest) in base_chain(base).into_iter().rev() {
+        for entry in manifest.packages {
+            if entry.status == PackageStatus::Ok
+                && !entry.files.is_empty()
+                && entry.files.iter().all(|file| file.sha256.is_some())
+            {
+                packages.insert(entry.name.clone(), (dir.join(&entry.name), entry));
+            }
+        }
+    }
+    packages
+}
+
+/// The backup folder `backup` and the bases its manifests lead back to,
+/// newest first. The walk stops at a folder without a manifest, and at one
+/// already visited, so a base that loops back can't recurse forever.
+pub(crate) fn base_chain(backup: &Path) -> Vec<(PathBuf, Manifest)> {
+    let mut chain: Vec<(PathBuf, Manifest)> = Vec::new();
+    let mut next = Some(backup.to_path_buf());
+    while let Some(dir) = next.take() {
+        if chain.iter().any(|(seen, _)| *seen == dir) {
+            break;
+        }
+        let Ok(Some(manifest)) = Manifest::load(&dir) else {
+            break;
+        };
+        next = manifest
+            .base
+            .as_ref()
+            .zip(dir.parent())
+            .map(|(base, root)| root.join(base));
+        chain.push((dir, manifest));
+    }
+    chain
+}
+
+/// For `--link-unchanged`: hard-links the package's APKs from `base_dir`
+/// into `package_dir` if the device still has exactly the files `base`
+/// lists, with the same size and SHA-256. Files are copied where they can't
+/// be linked. Linked files are counted as done in `progress`.
+fn unchanged_apks(
+    adb: &dyn AdbRunner,
+    package: &str,
+    package_dir: &Path,
+    base_dir: &Path,
+    base: &PackageEntry,
+    options: &PullOptions,
+) -> Option<(Vec<ApkFile>, Vec<String>)> {
+    let (apk_paths, dropped) = options.splits.apply(&get_package_paths(adb, package).ok()?);
+    if apk_paths.len() != base.files.len() {
+        return None;
+    }
+
+    // Sizes are compared first so changed files are usually caught without
+    // hashing anything on the device.
+    let stats = stat_device_files(adb, &apk_paths);
+    let mut matched = Vec::new();
+    for apk_path in &apk_paths {
+        let name = Path::new(apk_path).file_name()?.to_string_lossy();
+        let file = base
+            .files
+            .iter()
+            .find(|file| file.device_name.as_ref().unwrap_or(&file.name) == &name)?;
+        let len = fs::metadata(base_dir.join(&file.name)).ok()?.len();
+        if stats.get(apk_path)?.size != len {
+            return None;
+        }
+        matched.push((apk_path, file));
+    }
+    let hashes = device_sha256(adb, &apk_paths);
+    if matched
+        .iter()
+        .any(|(apk_path, file)| hashes.get(*apk_path) != file.sha256.as_ref())
+    {
+        return None;
+    }
+
+    fs::create_dir_all(package_dir).ok()?;
+    let files = matched
+        .iter()
+        .map(|(apk_path, file)| {
+            let source = base_dir.join(&file.name);
+            let local_path = package_dir.join(&file.name);
+            if fs::hard_link(&source, &local_path).is_err() {
+                fs::copy(&source, &local_path).ok()?;
+            }
+            let (device_size, device_modified_at) = options.recorded_stat(stats.get(*apk_path));
+            Some(ApkFile {
+                name: file.name.clone(),
+                sha256: file.sha256.clone(),
+                device_size,
+                device_modified_at,
+                split: file.split.clone(),
+                device_name: file.device_name.clone(),
+            })
+        })
+        .collect::<Option<Vec<_>>>();
+    let Some(files) = files else {
+        // Don't leave half the files behind for the pull that follows.
+        let _ = fs::remove_dir_all(package_dir);
+        return None;
+    };
+
+    if let Some(progress) = options.progress {
+        for apk_path in &apk_paths {
+            progress.skip(apk_path);
+        }
+    }
+    Some((files, split_file_names(&dropped)))
+}
+
+/// Reads version metadata from the base APK among a package's pulled files.
+pub(crate) fn read_base_apk_info(
+    package_dir: &Path,
+    files: &[ApkFile],
+    log: &mut PackageLog,
+) -> Option<ApkInfo> {
+    let paths = files
+        .iter()
+        .map(|file| package_dir.join(&file.name))
+        .collect();
+    let base = order_split_apks(paths).into_iter().next()?;
+    match axml::read_apk_info(&base) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            log.info(format!(
+                "    Could not read AndroidManifest.xml from {:?}: {}",
+                base.file_name().unwrap(),
+                e
+            ));
+            None
+        }
+    }
+}
+
+/// Describes the outcome of backing up one package for the manifest.
+pub(crate) fn package_entry(
+    package: &str,
+    package_dir: &Path,
+    version_code: Option<u64>,
+    installer: Option<String>,
+    apk_info: ApkInfo,
+    result: &Result<Vec<ApkFile>>,
+) -> PackageEntry {
+    // The pulled APK is the better source, since it matches the files kept.
+    let version_code = apk_info.version_code.or(version_code);
+    match result {
+        Ok(files) => PackageEntry {
+            name: package.to_string(),
+            status: PackageStatus::Ok,
+            version_code,
+            version_name: apk_info.version_name,
+            min_sdk: apk_info.min_sdk,
+            target_sdk: apk_info.target_sdk,
+            installer,
+            system: false,
+            permissions: Vec::new(),
+            dropped_splits: Vec::new(),
+            total_bytes: files
+                .iter()
+                .filter_map(|file| {
+                    fs::metadata(package_dir.join(&file.name))
+                        .map(|metadata| metadata.len())
+                        .ok()
+                        .or(file.device_size)
+                })
+                .sum(),
+            files: files.clone(),
+            error: None,
+        },
+        Err(e) => PackageEntry {
+            name: package.to_string(),
+            status: match e {
+                AppError::NoApkPaths(_) => PackageStatus::Skipped,
+                _ => PackageStatus::Failed,
+            },
+            version_code,
+            version_name: None,
+            min_sdk: None,
+            target_sdk: None,
+            installer,
+            system: false,
+            permissions: Vec::new(),
+            dropped_splits: Vec::new(),
+            files: vec![],
+            total_bytes: 0,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// This apktool and host, with the given adb version, for a manifest's
+/// `created_by`.
+pub(crate) fn tool_info(adb: Option<String>) -> ToolInfo {
+    ToolInfo {
+        apktool: env!("CARGO_PKG_VERSION").to_string(),
+        adb,
+        host_os: format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+    }
+}
+
+/// How a backup run ended, as recorded in its manifest.
+struct ManifestStatus {
+    partial: bool,
+    warnings: usize,
+    strict_failed: bool,
+}
+
+/// Writes `manifest.json` for the backup, keeping entries from an earlier run
+/// into the same folder for packages that weren't backed up this time.
+fn write_manifest(
+    adb: &dyn AdbRunner,
+    target_dir: &Path,
+    base_backup: Option<&Path>,
+    entries: Vec<PackageEntry>,
+    status: ManifestStatus,
+) -> Result<()> {
+    let mut manifest = Manifest::load(target_dir)?.unwrap_or_else(|| Manifest {
+        schema_version: SCHEMA_VERSION,
+        created_at: String::new(),
+        created_by: None,
+        base: None,
+        device: DeviceInfo::default(),
+        partial: false,
+        warnings: 0,
+        strict_failed: false,
+        packages: vec![],
+    });
+    manifest.partial = status.partial;
+    manifest.warnings = status.warnings;
+    manifest.strict_failed = status.strict_failed;
+    manifest.schema_version = SCHEMA_VERSION;
+    manifest.created_at = Local::now().to_rfc3339();
+    manifest.created_by = Some(tool_info(adb_info(adb).as_ref().map(adb_version_text)));
+    manifest.base = base_backup
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().to_string());
+    manifest.device = DeviceInfo {
+        serial: device_serial(adb),
+        model: get_device_output(adb, &["shell", "getprop", "ro.product.model"]),
+        abi: get_device_output(adb, &["shell", "getprop", "ro.product.cpu.abi"]),
+        sdk: get_device_sdk(adb),
+    };
+    manifest.merge_packages(entries);
+    manifest.save(target_dir)?;
+    Ok(())
+}
+
+fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_JOBS)
+}
+
+/// Output of one package's backup, buffered so it can be printed as a block
+/// and parallel workers don't interleave their lines.
+#[derive(Default)]
+pub(crate) struct PackageLog {
+    lines: Vec<(bool, String)>,
+}
+
+impl PackageLog {
+    pub(crate) fn info(&mut self, line: String) {
+        self.lines.push((false, line));
+    }
+
+    /// Records a warning, counted for `--strict` once printed.
+    pub(crate) fn warn(&mut self, line: String) {
+        self.lines.push((true, line));
+    }
+
+    /// Prints the lines, counting the warnings among them in `warnings`.
+    pub(crate) fn print(&self, warnings: &Warnings) {
+        for (is_warning, line) in &self.lines {
+            if *is_warning {
+                warning!(warnings, "{}", line);
+            } else {
+                status!("{}", line);
+            }
+        }
+    }
+}
+
+/// Lets the user pause a running backup between packages from the terminal.
+///
+/// Input is read on a background thread so the backup loop never blocks on
+/// stdin. The thread stops when this is dropped, so it doesn't take a
+/// line meant for a later prompt. Only available when stdin is a TTY.
+struct PauseControl {
+    input: Mutex<Receiver<String>>,
+    stop: Arc<AtomicBool>,
+}
+
+/// How long the pause reader waits for input before checking whether the
+/// backup is over.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+impl PauseControl {
+    fn spawn() -> Option<Self> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+
+        let (sender, input) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader_stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            while !reader_stop.load(Ordering::Relaxed) {
+                if !stdin_ready(PAUSE_POLL_INTERVAL) {
+                    continue;
+                }
+                let mut line = String::new();
+                match io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if sender.send(line.trim().to_lowercase()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Some(Self {
+            input: Mutex::new(input),
+            stop,
+        })
+    }
+
+    /// Blocks while a pause is in effect. Parallel workers queue up on the
+    /// lock, so none of them starts a new package until the backup resumes.
+    fn wait_if_requested(&self) {
+        let input = self.input.lock().unwrap();
+        let requested = input.try_iter().any(|line| line == "p");
+        if !requested {
+            return;
+        }
+
+        prompt!("Paused. Press Enter to resume.\n");
+        // A closed channel means stdin is gone, so there's nothing to wait for.
+        let _ = input.recv();
+        status!("Resuming.");
+    }
+}
+
+impl Drop for PauseControl {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether a line can be read from stdin without blocking, waiting up to
+/// `timeout` for one.
+#[cfg(unix)]
+fn stdin_ready(timeout: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` is a single valid pollfd for the duration of the call.
+    let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+    ready > 0
+}
+
+/// Without `poll` the reader just blocks, and only stops at the next line.
+#[cfg(not(unix))]
+fn stdin_ready(_timeout: Duration) -> bool {
+    true
+}
+
+/// For `--since`: the packages installed or updated at or after `since`,
+/// asking `dumpsys package` about each one.
+fn updated_since(
+    adb: &dyn AdbRunner,
+    packages: Vec<String>,
+    since: Since,
+    warnings: &Warnings,
+) -> Vec<String> {
+    let cutoff = match since {
+        Since::At(at) => at,
+        Since::Ago(duration) => {
+            let device_now = get_device_output(adb, &["shell", "date", "+%Y-%m-%d %H:%M:%S"])
+                .and_then(|now| NaiveDateTime::parse_from_str(&now, "%Y-%m-%d %H:%M:%S").ok());
+            device_now.unwrap_or_else(|| Local::now().naive_local()) - duration
+        }
+    };
+
+    let total = packages.len();
+    let mut unknown = Vec::new();
+    let packages: Vec<String> = packages
+        .into_iter()
+        .filter(
+            |package| match dumpsys_package(adb, package).and_then(|d| last_install_time(&d)) {
+                Some(time) => time >= cutoff,
+                None => {
+                    unknown.push(package.clone());
+                    true
+                }
+            },
+        )
+        .collect();
+    if !unknown.is_empty() {
+        warning!(
+            warnings,
+            "Warning: Could not read the install time of {}; backing them up anyway.",
+            unknown.join(", ")
+        );
+    }
+    status!(
+        "Since {}: {} of {} packages installed or updated",
+        cutoff.format("%Y-%m-%d %H:%M:%S"),
+        packages.len(),
+        total
+    );
+    packages
+}
+
+/// Records the default launcher and whether it is enabled into
+/// `device_info/launcher.txt`.
+///
+/// This is best-effort: Android has no common way to export the home screen
+/// layout itself, which is private to each launcher, so only what the package
+/// manager exposes is captured.
+fn capture_launcher(adb: &dyn AdbRunner, target_dir: &Path, warnings: &Warnings) {
+    let output = match adb.run(&[
+        "shell",
+        "cmd",
+        "package",
+        "resolve-activity",
+        "--brief",
+        "-a",
+        "android.intent.action.MAIN",
+        "-c",
+        "android.intent.category.HOME",
+    ]) {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warning!(
+                warnings,
+                "Warning: Failed to resolve the default launcher: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return;
+        }
+        Err(e) => {
+            warning!(
+                warnings,
+                "Warning: Failed to resolve the default launcher: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    // With --brief the resolved component is printed on the last line.
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let Some(component) = output_str
+        .lines()
+        .map(str::trim)
+        .rfind(|line| line.contains('/'))
+    else {
+        warning!(
+            warnings,
+            "Warning: No default launcher is set on the device."
+        );
+        return;
+    };
+    let package = component.split('/').next().unwrap_or(component);
+
+    let enabled = adb
+        .run(&pm_args(adb, &["list", "packages"], &["-e", package]))
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.trim() == format!("package:{}", package))
+        })
+        .unwrap_or(false);
+
+    let info_dir = target_dir.join(DEVICE_INFO_DIR);
+    let contents = format!(
+        "# Best-effort capture. The home screen layout is launcher-specific\n\
+         # and is not exported; only the default launcher is recorded.\n\
+         component={}\npackage={}\nenabled={}\n",
+        component, package, enabled
+    );
+    match fs::create_dir_all(&info_dir)
+        .and_then(|_| fs::write(info_dir.join("launcher.txt"), contents))
+    {
+        Ok(_) => status!("Captured default launcher: {}", component),
+        Err(e) => warning!(warnings, "Warning: Failed to write launcher info: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{get_version_codes, last_install_time};
+    use crate::install::SplitFilter;
+    use crate::pull::PullOptions;
+    use crate::testing::{FakeAdb, fake_entry, fake_manifest};
+
+    #[test]
+    fn differential_backup_picks_new_and_updated_packages() {
+        let base = TempDir::new(&std::env::temp_dir(), "apktool-test-differential").unwrap();
+        let entries = [
+            ("com.example.one", PackageStatus::Ok, 10),
+            ("com.example.two", PackageStatus::Ok, 20),
+            ("com.example.four", PackageStatus::Failed, 40),
+        ]
+        .map(|(name, status, version_code)| PackageEntry {
+            version_code: Some(version_code),
+            ..fake_entry(name, status, &[])
+        });
+        fake_manifest(entries.to_vec()).save(base.path()).unwrap();
+        let adb = FakeAdb::default().with(
+            "shell pm list packages --show-versioncode",
+            "package:com.example.one versionCode:11\n\
+             package:com.example.two versionCode:20\n\
+             package:com.example.three versionCode:1\n\
+             package:com.example.four versionCode:40\n",
+        );
+        let packages: Vec<String> = ["one", "two", "three", "four"]
+            .iter()
+            .map(|name| format!("com.example.{}", name))
+            .collect();
+
+        let version_codes = get_version_codes(&adb, &packages);
+        let changes: Vec<_> = classify_changes(&packages, &version_codes, base.path())
+            .unwrap()
+            .into_iter()
+            .map(|(_, change)| change)
+            .collect();
+        assert_eq!(
+            changes,
+            [Change::Updated, Change::Unchanged, Change::New, Change::New]
+        );
+    }
+
+    #[test]
+    fn unchanged_apks_links_only_identical_files() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-link").unwrap();
+        let base_dir = root.path().join("base").join("com.example");
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::write(base_dir.join("base.apk"), "abc").unwrap();
+        let sha256 = sha256_file(&base_dir.join("base.apk")).unwrap();
+        let base = PackageEntry {
+            files: vec![ApkFile {
+                name: "base.apk".to_string(),
+                sha256: Some(sha256.clone()),
+                device_size: None,
+                device_modified_at: None,
+                split: None,
+                device_name: None,
+            }],
+            ..package_entry(
+                "com.example",
+                &base_dir,
+                None,
+                None,
+                ApkInfo::default(),
+                &Ok(Vec::new()),
+            )
+        };
+        let options = PullOptions {
+            preserve_timestamps: false,
+            record_stats: false,
+            splits: SplitFilter::default(),
+            retry_pm_path: false,
+            split_names: true,
+            retries: 0,
+            store: None,
+            stream: None,
+            progress: None,
+        };
+        let device = |sha256: &str| {
+            FakeAdb::default()
+                .with(
+                    "shell pm path com.example",
+                    "package:/data/app/x/base.apk\n",
+                )
+                .with(
+                    "shell stat -c '%s %Y %n' /data/app/x/base.apk",
+                    "3 1700000000 /data/app/x/base.apk\n",
+                )
+                .with(
+                    "shell sha256sum /data/app/x/base.apk",
+                    &format!("{}  /data/app/x/base.apk\n", sha256),
+                )
+        };
+
+        let package_dir = root.path().join("new").join("com.example");
+        let changed = unchanged_apks(
+            &device(&"0".repeat(64)),
+            "com.example",
+            &package_dir,
+            &base_dir,
+            &base,
+            &options,
+        );
+        assert!(changed.is_none());
+        assert!(!package_dir.exists());
+
+        let (files, _) = unchanged_apks(
+            &device(&sha256),
+            "com.example",
+            &package_dir,
+            &base_dir,
+            &base,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(files[0].sha256.as_deref(), Some(sha256.as_str()));
+        assert_eq!(fs::read(package_dir.join("base.apk")).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn rotate_backups_keeps_every_base_of_a_kept_chain() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-rotate").unwrap();
+        for (name, base) in [
+            ("full", None),
+            ("diff1", Some("full")),
+            ("diff2", Some("diff1")),
+            ("other", None),
+        ] {
+            let dir = root.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            let manifest = Manifest {
+                base: base.map(String::from),
+                ..fake_manifest(Vec::new())
+            };
+            manifest.save(&dir).unwrap();
+        }
+
+        let current = root.path().join("diff2");
+        rotate_backups(root.path(), 1, Some(&current), &Warnings::default()).unwrap();
+        for kept in ["full", "diff1", "diff2"] {
+            assert!(root.path().join(kept).is_dir(), "{} was removed", kept);
+        }
+        assert!(!root.path().join("other").exists());
+    }
+
+    #[test]
+    fn largest_first_schedule_sorts_by_apk_size() {
+        let packages = || {
+            [
+                "com.small",
+                "com.unsized",
+                "com.large",
+                "com.medium",
+                "com.also.medium",
+            ]
+            .map(String::from)
+            .to_vec()
+        };
+        let sizes = HashMap::from([
+            ("com.small".to_string(), 10),
+            ("com.large".to_string(), 5_000),
+            ("com.medium".to_string(), 300),
+            ("com.also.medium".to_string(), 300),
+        ]);
+
+        let mut largest = packages();
+        schedule_packages(&mut largest, Schedule::LargestFirst, &sizes);
+        assert_eq!(
+            largest,
+            [
+                "com.large",
+                "com.medium",
+                "com.also.medium",
+                "com.small",
+                "com.unsized"
+            ]
+        );
+
+        let mut device = packages();
+        schedule_packages(&mut device, Schedule::DeviceOrder, &sizes);
+        assert_eq!(device, packages());
+    }
+
+    /// How long `workers` take over `packages` when each free worker takes
+    /// the next one in the queue, with sizes as durations.
+    fn makespan(packages: &[String], sizes: &HashMap<String, u64>, workers: usize) -> u64 {
+        let mut busy_until = vec![0; workers];
+        for package in packages {
+            let worker = busy_until.iter_mut().min().unwrap();
+            *worker += sizes[package];
+        }
+        busy_until.into_iter().max().unwrap()
+    }
+
+    #[test]
+    fn largest_first_schedule_finishes_parallel_backups_sooner() {
+        let sizes = HashMap::from([
+            ("com.a".to_string(), 40),
+            ("com.b".to_string(), 30),
+            ("com.c".to_string(), 20),
+            ("com.d".to_string(), 10),
+            ("com.e".to_string(), 10),
+            ("com.big".to_string(), 300),
+            ("com.f".to_string(), 50),
+            ("com.g".to_string(), 60),
+        ]);
+        let device_order: Vec<String> = [
+            "com.a", "com.b", "com.c", "com.d", "com.e", "com.big", "com.f", "com.g",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        for workers in [2, 3, 4] {
+            let mut largest = device_order.clone();
+            schedule_packages(&mut largest, Schedule::LargestFirst, &sizes);
+            assert!(
+                makespan(&largest, &sizes, workers) < makespan(&device_order, &sizes, workers),
+                "no gain with {} workers",
+                workers
+            );
+        }
+    }
+
+    #[test]
+    fn auto_base_picks_the_newest_complete_backup_of_the_device() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-auto-base").unwrap();
+        for (name, serial, partial, status) in [
+            ("b-20200101000000", "S", false, PackageStatus::Ok),
+            ("b-20210101000000", "S", false, PackageStatus::Ok),
+            ("b-20220101000000", "S", false, PackageStatus::Failed),
+            ("b-20230101000000", "S", true, PackageStatus::Ok),
+            ("b-20240101000000", "T", false, PackageStatus::Ok),
+        ] {
+            let dir = root.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            let manifest = Manifest {
+                device: DeviceInfo {
+                    serial: Some(serial.to_string()),
+                    ..DeviceInfo::default()
+                },
+                partial,
+                ..fake_manifest(vec![fake_entry("com.example.one", status, &[])])
+            };
+            manifest.save(&dir).unwrap();
+        }
+
+        let base = find_auto_base(root.path(), "S").unwrap();
+        assert_eq!(base.file_name().unwrap(), "b-20210101000000");
+        assert_eq!(
+            find_auto_base(root.path(), "T")
+                .unwrap()
+                .file_name()
+                .unwrap(),
+            "b-20240101000000"
+        );
+        assert_eq!(find_auto_base(root.path(), "U"), None);
+    }
+
+    #[test]
+    fn backup_folder_name_refuses_taken_default_names() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-folder-name").unwrap();
+        fs::create_dir(root.path().join("2024-06-10")).unwrap();
+        let warnings = Warnings::default();
+        let name = |name: &str, overwrite| {
+            backup_folder_name(
+                root.path(),
+                name,
+                "2024-06-10",
+                "2024-06-10",
+                overwrite,
+                &warnings,
+            )
+        };
+        assert!(name("", false).is_err());
+        assert!(name("$date", false).is_err());
+        assert_eq!(name("", true).unwrap(), "2024-06-10");
+        assert_eq!(name("other", false).unwrap(), "other");
+    }
+
+    #[test]
+    fn name_format_takes_chrono_specifiers_and_device_tokens() {
+        assert!(parse_name_format("%Y-%m-%dT%H%M%S_{model}").is_ok());
+        assert!(parse_name_format("{serial}_%Q").is_err());
+        assert_eq!(name_token(Some("Pixel 7 Pro".to_string())), "Pixel_7_Pro");
+        assert_eq!(
+            name_token(Some("192.168.1.5:5555".to_string())),
+            "192.168.1.5_5555"
+        );
+        assert_eq!(name_token(None), "unknown");
+    }
+
+    #[test]
+    fn since_reads_durations_and_dates_and_dumpsys_times() {
+        let at = |time: &str| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(parse_since("7d"), Ok(Since::Ago(chrono::Duration::days(7))));
+        assert_eq!(
+            parse_since("30m"),
+            Ok(Since::Ago(chrono::Duration::minutes(30)))
+        );
+        assert_eq!(
+            parse_since("2024-05-01"),
+            Ok(Since::At(at("2024-05-01 00:00:00")))
+        );
+        assert_eq!(
+            parse_since("2024-05-01 18:30"),
+            Ok(Since::At(at("2024-05-01 18:30:00")))
+        );
+        assert!(parse_since("7").is_err());
+        assert!(parse_since("-1d").is_err());
+        assert!(parse_since("").is_err());
+        assert!(parse_since("7é").is_err());
+
+        let dumpsys = "    versionCode=20 minSdk=21 targetSdk=34\n\
+                       \x20   firstInstallTime=2024-01-01 10:00:00\n\
+                       \x20   lastUpdateTime=2024-03-02 11:12:13\n\
+                       \x20   User 0: installed=true firstInstallTime=2024-04-01 08:00:00\n\
+                       \x20 Hidden system packages:\n\
+                       \x20   lastUpdateTime=1970-01-01 00:00:00\n";
+        assert_eq!(last_install_time(dumpsys), Some(at("2024-04-01 08:00:00")));
+        assert_eq!(last_install_time("    versionCode=20\n"), None);
+    }
+}
@@ -0,0 +1,401 @@
+//! Unpacks split APK bundles (`.apks`, `.xapk`, `.apkm`) into a package
+//! folder laid out like one in a backup, so they install the same way.
+//!
+//! All three formats are zips of APKs, but place and describe them
+//! differently:
+//!
+//! - XAPK lists its APKs in `manifest.json` under `split_apks`, with the
+//!   base marked by `"id": "base"`, and may carry expansion files under
+//!   `Android/obb/<package>/`.
+//! - bundletool's `.apks` keeps splits under `splits/` with the base as
+//!   `base-master.apk`, or a single `universal.apk`.
+//! - SAI's `.apks` and APKMirror's `.apkm` have `base.apk` and `split_*.apk`
+//!   at the root.
+//!
+//! [`pack`] goes the other way and writes the root layout, described by an
+//! XAPK `manifest.json` or, for `.apks`, SAI's `meta.sai_v2.json`.
+
+use crate::axml;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const BUNDLE_EXTENSIONS: [&str; 3] = ["apks", "xapk", "apkm"];
+
+const XAPK_MANIFEST: &str = "manifest.json";
+const SAI_META: &str = "meta.sai_v2.json";
+const BUNDLETOOL_SPLITS_DIR: &str = "splits/";
+const BUNDLETOOL_BASE: &str = "splits/base-master.apk";
+const BUNDLETOOL_UNIVERSAL: &str = "universal.apk";
+const XAPK_OBB_DIR: &str = "Android/obb/";
+
+/// Version of the XAPK manifest format written by [`pack`].
+const XAPK_VERSION: u32 = 2;
+
+/// The bundle formats [`pack`] writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BundleFormat {
+    /// SAI's `.apks`: the APKs at the root with a `meta.sai_v2.json`
+    Apks,
+    /// `.xapk`: the APKs at the root with a `manifest.json`
+    Xapk,
+}
+
+impl BundleFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            BundleFormat::Apks => "apks",
+            BundleFormat::Xapk => "xapk",
+        }
+    }
+
+    /// The format a bundle file name asks for: `.apks` for SAI's layout,
+    /// anything else for XAPK.
+    pub fn for_path(path: &Path) -> Self {
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("apks"))
+        {
+            BundleFormat::Apks
+        } else {
+            BundleFormat::Xapk
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct XapkManifest {
+    package_name: Option<String>,
+    #[serde(default)]
+    split_apks: Vec<XapkSplit>,
+}
+
+/// The fields of an XAPK manifest that [`pack`] fills in. XAPK stores the
+/// version code as a string.
+#[derive(Serialize)]
+struct XapkManifestOut<'a> {
+    xapk_version: u32,
+    package_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_sdk_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_sdk_version: Option<String>,
+    split_apks: Vec<XapkSplitOut>,
+}
+
+/// SAI's v2 `.apks` metadata. Unlike XAPK, the version code is a number.
+#[derive(Serialize)]
+struct SaiMeta<'a> {
+    meta_version: u32,
+    package: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_code: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_sdk: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_sdk: Option<u32>,
+    split_apk: bool,
+    /// Milliseconds since the Unix epoch.
+    export_timestamp: u64,
+}
+
+#[derive(Serialize)]
+struct XapkSplitOut {
+    file: String,
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct XapkSplit {
+    file: String,
+    id: String,
+}
+
+/// Which zip entries hold the base APK and the splits.
+struct Layout {
+    base: String,
+    splits: Vec<String>,
+    /// Package name recorded by the bundle itself, if any.
+    package: Option<String>,
+}
+
+/// Unpacks `bundle` into `dest/<package>/` as `base.apk`, `split_*.apk`
+/// and, for XAPKs, `obb/<package>/...`, and returns that folder. The
+/// package name comes from the base APK's manifest, falling back to the
+/// bundle's own metadata and then its file name.
+pub fn unpack(bundle: &Path, dest: &Path) -> io::Result<PathBuf> {
+    let mut archive = zip::ZipArchive::new(File::open(bundle)?).map_err(invalid_data)?;
+    let names: Vec<String> = archive
+        .file_names()
+        .map(|name| name.map(|name| name.to_string()))
+        .collect::<Result<_, _>>()
+        .map_err(invalid_data)?;
+
+    let layout = choose_apks(&mut archive, &names)?;
+
+    let staging = dest.join(".bundle");
+    fs::create_dir_all(&staging)?;
+    extract_entry(&mut archive, &layout.base, &staging.join("base.apk"))?;
+    let package = axml::read_apk_info(&staging.join("base.apk"))
+        .ok()
+        .and_then(|info| info.package)
+        .or(layout.package)
+        .or_else(|| {
+            bundle
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .ok_or_else(|| invalid_data("cannot tell the bundle's package name"))?;
+
+    let package_dir = dest.join(&package);
+    fs::rename(&staging, &package_dir)?;
+
+    for split in &layout.splits {
+        let file_name = Path::new(split)
+            .file_name()
+            .ok_or_else(|| invalid_data("split without a file name"))?
+            .to_string_lossy();
+        let local_name = if file_name.starts_with("split_") {
+            file_name.to_string()
+        } else {
+            format!("split_{}", file_name)
+        };
+        extract_entry(&mut archive, split, &package_dir.join(local_name))?;
+    }
+
+    for name in &names {
+        let Some(relative) = name.strip_prefix(XAPK_OBB_DIR) else {
+            continue;
+        };
+        if name.ends_with('/') {
+            continue;
+        }
+        // Only the `<package>/<file>` part is kept, so entries can't point
+        // outside the package folder.
+        let parts: Vec<_> = Path::new(relative).components().collect();
+        if let [
+            std::path::Component::Normal(dir),
+            std::path::Component::Normal(file),
+        ] = parts.as_slice()
+        {
+            let local_path = package_dir.join("obb").join(dir).join(file);
+            fs::create_dir_all(local_path.parent().unwrap())?;
+            extract_entry(&mut archive, name, &local_path)?;
+        }
+    }
+
+    Ok(package_dir)
+}
+
+/// Writes a package's APKs to a new bundle at `output` in `format`. `apks`
+/// must start with the base APK, which is stored as `base.apk`; the splits
+/// keep their file names. APKs are stored uncompressed, as installers
+/// expect.
+pub fn pack(
+    apks: &[PathBuf],
+    info: &axml::ApkInfo,
+    package: &str,
+    output: &Path,
+    format: BundleFormat,
+) -> io::Result<()> {
+    let Some((base, splits)) = apks.split_first() else {
+        return Err(invalid_data("no APKs to pack"));
+    };
+
+    let mut entries = vec![(base, "base.apk".to_string())];
+    for split in splits {
+        let file_name = split
+            .file_name()
+            .ok_or_else(|| invalid_data("split without a file name"))?
+            .to_string_lossy()
+            .to_string();
+        if file_name == "base.apk" || file_name == XAPK_MANIFEST || file_name == SAI_META {
+            return Err(invalid_data(format!("unexpected split name {}", file_name)));
+        }
+        entries.push((split, file_name));
+    }
+
+    let (meta_name, meta) = match format {
+        BundleFormat::Xapk => (
+            XAPK_MANIFEST,
+            serde_json::to_vec_pretty(&XapkManifestOut {
+                xapk_version: XAPK_VERSION,
+                package_name: package,
+                version_code: info.version_code.map(|code| code.to_string()),
+                version_name: info.version_name.as_deref(),
+                min_sdk_version: info.min_sdk.map(|sdk| sdk.to_string()),
+                target_sdk_version: info.target_sdk.map(|sdk| sdk.to_string()),
+                split_apks: entries
+                    .iter()
+                    .map(|(_, name)| XapkSplitOut {
+                        file: name.clone(),
+                        id: split_id(name),
+                    })
+                    .collect(),
+            })?,
+        ),
+        BundleFormat::Apks => (
+            SAI_META,
+            serde_json::to_vec_pretty(&SaiMeta {
+                meta_version: 2,
+                package,
+                version_code: info.version_code,
+                version_name: info.version_name.as_deref(),
+                min_sdk: info.min_sdk,
+                target_sdk: info.target_sdk,
+                split_apk: entries.len() > 1,
+                export_timestamp: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+            })?,
+        ),
+    };
+
+    let mut writer = zip::ZipWriter::new(File::create(output)?);
+    let stored =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    writer
+        .start_file(meta_name, zip::write::SimpleFileOptions::default())
+        .map_err(invalid_data)?;
+    writer.write_all(&meta)?;
+    for (path, name) in &entries {
+        writer
+            .start_file(name.as_str(), stored)
+            .map_err(invalid_data)?;
+        io::copy(&mut File::open(path)?, &mut writer)?;
+    }
+    writer.finish().map_err(invalid_data)?;
+    Ok(())
+}
+
+/// The XAPK split id for an entry name: `base` for the base APK, otherwise
+/// the file name without `split_` and `.apk`.
+fn split_id(name: &str) -> String {
+    if name == "base.apk" {
+        return "base".to_string();
+    }
+    let name = name.strip_prefix("split_").unwrap_or(name);
+    name.strip_suffix(".apk").unwrap_or(name).to_string()
+}
+
+/// Whether `path` has one of the [`BUNDLE_EXTENSIONS`].
+pub fn is_bundle(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        BUNDLE_EXTENSIONS
+            .iter()
+            .any(|bundle| ext.eq_ignore_ascii_case(bundle))
+    })
+}
+
+/// Picks the base APK and the splits to install, by entry name.
+fn choose_apks(archive: &mut zip::ZipArchive<File>, names: &[String]) -> io::Result<Layout> {
+    let has = |name: &str| names.iter().any(|entry| entry == name);
+
+    if has(XAPK_MANIFEST) {
+        let mut contents = String::new();
+        archive
+            .by_name(XAPK_MANIFEST)
+            .map_err(invalid_data)?
+            .read_to_string(&mut contents)?;
+        if let Ok(manifest) = serde_json::from_str::<XapkManifest>(&contents) {
+            if let Some(base) = manifest.split_apks.iter().find(|split| split.id == "base") {
+                let splits = manifest
+                    .split_apks
+                    .iter()
+                    .filter(|split| split.id != "base")
+                    .map(|split| split.file.clone())
+                    .collect();
+                return Ok(Layout {
+                    base: base.file.clone(),
+                    splits,
+                    package: manifest.package_name,
+                });
+            }
+            // Single-APK XAPKs name the APK after the package.
+            if let Some(package) = manifest.package_name {
+                let apk = format!("{}.apk", package);
+                if has(&apk) {
+                    return Ok(Layout {
+                        base: apk,
+                        splits: Vec::new(),
+                        package: Some(package),
+                    });
+                }
+            }
+        }
+    }
+
+    if has(BUNDLETOOL_UNIVERSAL) {
+        return Ok(Layout {
+            base: BUNDLETOOL_UNIVERSAL.to_string(),
+            splits: Vec::new(),
+            package: None,
+        });
+    }
+    if has(BUNDLETOOL_BASE) {
+        let splits = names
+            .iter()
+            .filter(|name| {
+                name.starts_with(BUNDLETOOL_SPLITS_DIR)
+                    && name.ends_with(".apk")
+                    && *name != BUNDLETOOL_BASE
+            })
+            .cloned()
+            .collect();
+        return Ok(Layout {
+            base: BUNDLETOOL_BASE.to_string(),
+            splits,
+            package: None,
+        });
+    }
+
+    let mut apks: Vec<String> = names
+        .iter()
+        .filter(|name| !name.contains('/') && name.ends_with(".apk"))
+        .cloned()
+        .collect();
+    apks.sort();
+    let base = apks
+        .iter()
+        .position(|name| name == "base.apk")
+        .or_else(|| {
+            let non_split: Vec<_> = (0..apks.len())
+                .filter(|&i| !apks[i].starts_with("split_"))
+                .collect();
+            match non_split.as_slice() {
+                [only] => Some(*only),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| invalid_data("no base APK found in bundle"))?;
+    let base = apks.remove(base);
+    Ok(Layout {
+        base,
+        splits: apks,
+        package: None,
+    })
+}
+
+fn extract_entry(
+    archive: &mut zip::ZipArchive<File>,
+    name: &str,
+    local_path: &Path,
+) -> io::Result<()> {
+    let mut entry = archive
+        .by_name(name)
+        .map_err(|e| invalid_data(format!("{}: {}", name, e)))?;
+    io::copy(&mut entry, &mut File::create(local_path)?)?;
+    Ok(())
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
@@ -0,0 +1,96 @@
+use crate::output::Warnings;
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "apktool.toml";
+
+/// Defaults read from `apktool.toml`. Every setting is optional, and command
+/// line flags take precedence over all of them.
+///
+/// ```toml
+/// backup-dir = "/mnt/external/apk-backups"
+/// jobs = 2
+/// retries = 5
+/// timeout = 120
+/// name-format = "%Y-%m-%d_%H%M%S_{model}"
+/// include = ['^com\.mycompany\.']
+/// exclude = ['\.debug$']
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub backup_dir: Option<PathBuf>,
+    pub jobs: Option<u16>,
+    pub retries: Option<u32>,
+    /// Seconds, as for `--timeout`.
+    pub timeout: Option<u64>,
+    /// As for `backup --name-format`.
+    pub name_format: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl ConfigFile {
+    /// Reads the first config file found: `apktool.toml` in the working
+    /// directory, then `apktool/apktool.toml` in the platform config
+    /// directory (e.g. `~/.config` on Linux). No file means all defaults.
+    pub fn load() -> io::Result<Self> {
+        let candidates = [
+            Some(PathBuf::from(CONFIG_FILE)),
+            dirs::config_dir().map(|dir| dir.join("apktool").join(CONFIG_FILE)),
+        ];
+        match candidates.into_iter().flatten().find(|path| path.is_file()) {
+            Some(path) => Self::read(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+}
+
+/// Settings in effect for a run: command line flags, then the config file,
+/// then built-in defaults.
+pub struct Config {
+    pub backup_dir: PathBuf,
+    /// Parallel backup workers; `None` picks a default from the core count.
+    pub jobs: Option<u16>,
+    pub retries: u32,
+    /// Format of the timestamp in new backup names; `None` for the default.
+    pub name_format: Option<String>,
+    pub includes: Vec<Regex>,
+    pub excludes: Vec<Regex>,
+    /// `--strict`: a backup that printed warnings is recorded as failed.
+    pub strict: bool,
+    /// Where scratch files go: unpacked archives, and `--stream`'s partial
+    /// archive.
+    pub temp_dir: PathBuf,
+    /// Order of the backups offered for selection, from `--sort-backups`.
+    pub backup_order: BackupOrder,
+    /// Warnings printed so far in the run.
+    pub warnings: Warnings,
+    /// Whether the run may ask on the terminal. Only the CLI sets this, so
+    /// [`backup`](fn@crate::backup) and [`install`](fn@crate::install) fail
+    /// where they would otherwise prompt.
+    pub(crate) interactive: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum BackupOrder {
+    /// Most recent first, by the timestamp in the name or else the mtime
+    Newest,
+    Oldest,
+    /// Alphabetically by name
+    Name,
+}
@@ -0,0 +1,1628 @@
+//! Talking to the device over adb: running adb itself, picking the device
+//! and user to work on, and asking the package manager about packages.
+
+use crate::backup::PackageLog;
+use crate::error::{AppError, Result};
+use crate::output::Warnings;
+use crate::{Commands, interrupt, runlog, select_from};
+use chrono::{Local, NaiveDateTime};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SERVER_RESTART_POLLS: u32 = 10;
+const SERVER_RESTART_INTERVAL: Duration = Duration::from_millis(500);
+/// How long `--retry-pm-path` waits before asking `pm path` again.
+pub(crate) const PM_PATH_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How often `--wait-for-device` checks `adb devices`.
+const DEVICE_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A device connected with `adb connect` for `--connect`. Disconnects on
+/// drop if this run made the connection.
+pub(crate) struct TcpConnection {
+    pub(crate) serial: String,
+    initiated: bool,
+    adb_path: Option<PathBuf>,
+}
+
+impl TcpConnection {
+    pub(crate) fn open(adb: &SystemAdb, address: &str) -> Result<Self> {
+        // adb uses the default port when none is given, and lists the device
+        // under host:port either way.
+        let serial = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:5555", address)
+        };
+
+        let output = adb.run_global(&["connect", &serial])?;
+        // adb connect exits 0 even when it fails, so go by its message.
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let initiated = if stdout.contains("already connected") {
+            false
+        } else if stdout.contains("connected to") {
+            true
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = format!("{} {}", stdout.trim(), stderr.trim());
+            return Err(AppError::AdbCommand {
+                args: format!("connect {}", serial),
+                stderr: message.trim().to_string(),
+            });
+        };
+
+        status!("Connected to {}", serial);
+        Ok(Self {
+            serial,
+            initiated,
+            adb_path: adb.path.clone(),
+        })
+    }
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        if self.initiated {
+            let mut adb = SystemAdb::new();
+            if let Some(path) = &self.adb_path {
+                adb = adb.with_path(path);
+            }
+            let _ = adb.run_global(&["disconnect", &self.serial]);
+        }
+    }
+}
+
+/// The ABIs and API level of the device being installed to. Either is empty
+/// when the device doesn't report it.
+pub(crate) struct DeviceTarget {
+    /// Supported ABIs, preferred first.
+    pub(crate) abis: Vec<String>,
+    pub(crate) sdk: Option<u32>,
+}
+
+pub(crate) fn get_device_target(adb: &dyn AdbRunner) -> DeviceTarget {
+    let abis = get_device_output(adb, &["shell", "getprop", "ro.product.cpu.abilist"])
+        .or_else(|| get_device_output(adb, &["shell", "getprop", "ro.product.cpu.abi"]))
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|abi| !abi.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    DeviceTarget {
+        abis,
+        sdk: get_device_sdk(adb),
+    }
+}
+
+pub(crate) fn get_device_sdk(adb: &dyn AdbRunner) -> Option<u32> {
+    get_device_output(adb, &["shell", "getprop", "ro.build.version.sdk"])?
+        .parse()
+        .ok()
+}
+
+/// What `stat` reports for a file on the device.
+pub(crate) struct DeviceFileStat {
+    pub(crate) size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    modified: i64,
+}
+
+impl DeviceFileStat {
+    /// The modification time in RFC 3339, like the manifest's `created_at`.
+    pub(crate) fn modified_at(&self) -> Option<String> {
+        chrono::DateTime::from_timestamp(self.modified, 0)
+            .map(|time| time.with_timezone(&Local).to_rfc3339())
+    }
+}
+
+/// Stats `paths` on the device with a single `stat` call. Files it couldn't
+/// stat are left out.
+pub(crate) fn stat_device_files(
+    adb: &dyn AdbRunner,
+    paths: &[String],
+) -> HashMap<String, DeviceFileStat> {
+    // adb shell joins its arguments for the device shell, hence the quotes.
+    let mut args = vec![
+        "shell".to_string(),
+        "stat".into(),
+        "-c".into(),
+        "'%s %Y %n'".into(),
+    ];
+    args.extend(paths.iter().cloned());
+
+    match adb.run(&args) {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                // "12345 1700000000 /data/app/.../base.apk"
+                let mut parts = line.trim().splitn(3, ' ');
+                let size = parts.next()?.parse().ok()?;
+                let modified = parts.next()?.parse().ok()?;
+                let path = parts.next()?.to_string();
+                Some((path, DeviceFileStat { size, modified }))
+            })
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Hashes `paths` on the device with a single `sha256sum` call, returning
+/// lowercase hex digests by path. Files it couldn't hash are left out.
+pub(crate) fn device_sha256(adb: &dyn AdbRunner, paths: &[String]) -> HashMap<String, String> {
+    let mut args = vec!["shell".to_string(), "sha256sum".into()];
+    args.extend(paths.iter().cloned());
+
+    match adb.run(&args) {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                // "<hex>  /data/app/.../base.apk"
+                let (hash, path) = line.trim().split_once(char::is_whitespace)?;
+                Some((path.trim_start().to_string(), hash.to_ascii_lowercase()))
+            })
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Runs an adb command and returns its trimmed stdout, or `None` if it failed
+/// or printed nothing.
+pub(crate) fn get_device_output(adb: &dyn AdbRunner, args: &[&str]) -> Option<String> {
+    let output = adb.run(args).ok()?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !value.is_empty()).then_some(value)
+}
+
+/// Oldest adb apktool supports; older releases still run but fail in
+/// confusing ways against current devices.
+pub(crate) const MIN_ADB_VERSION: AdbVersion = AdbVersion(1, 0, 39);
+/// `adb install-multiple` first shipped in adb 1.0.32.
+pub(crate) const MIN_INSTALL_MULTIPLE_ADB_VERSION: AdbVersion = AdbVersion(1, 0, 32);
+/// `adb install --incremental` first shipped in adb 1.0.41.
+pub(crate) const MIN_INCREMENTAL_ADB_VERSION: AdbVersion = AdbVersion(1, 0, 41);
+/// adb 1.0.41 spans many platform-tools releases; incremental install
+/// became usable in 30.0.4.
+const MIN_AUTO_INCREMENTAL_PLATFORM_TOOLS: AdbVersion = AdbVersion(30, 0, 4);
+const INCREMENTAL_DELIVERY_FEATURE: &str = "android.software.incremental_delivery";
+
+/// An adb release number, e.g. `1.0.41` from "Android Debug Bridge version
+/// 1.0.41".
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct AdbVersion(u32, u32, u32);
+
+impl std::fmt::Display for AdbVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+pub(crate) struct AdbInfo {
+    /// `None` if the output didn't contain a recognizable version line.
+    pub(crate) version: Option<AdbVersion>,
+    /// Platform-tools release, e.g. `34.0.5-10900879`; older adb doesn't
+    /// print it.
+    pub(crate) platform_tools: Option<String>,
+}
+
+/// What the runner's `adb version` reports, or `None` when adb can't be run.
+pub(crate) fn adb_info(adb: &dyn AdbRunner) -> Option<AdbInfo> {
+    adb.version().map(parse_adb_version)
+}
+
+fn parse_adb_version(output: &str) -> AdbInfo {
+    let version = output.lines().find_map(|line| {
+        let number = line.trim().strip_prefix("Android Debug Bridge version ")?;
+        let mut parts = number.split('.').map(|part| part.trim().parse::<u32>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => {
+                Some(AdbVersion(major, minor, patch))
+            }
+            _ => None,
+        }
+    });
+    let platform_tools = output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Version "))
+        .map(|release| release.trim().to_string());
+    AdbInfo {
+        version,
+        platform_tools,
+    }
+}
+
+/// The text for `--version`: apktool's version followed by the adb it
+/// finds, so bug reports include both.
+pub(crate) fn long_version(adb: &SystemAdb) -> String {
+    let adb = adb_info(adb).map_or_else(|| "not found".to_string(), |info| adb_version_text(&info));
+    format!("{}\nadb {}", env!("CARGO_PKG_VERSION"), adb)
+}
+
+/// The adb version with its platform-tools release, as shown by `--version`
+/// and recorded in manifests.
+pub(crate) fn adb_version_text(info: &AdbInfo) -> String {
+    let version = info
+        .version
+        .map_or_else(|| "unknown version".to_string(), |v| v.to_string());
+    match &info.platform_tools {
+        Some(release) => format!("{} (platform-tools {})", version, release),
+        None => version,
+    }
+}
+
+/// Whether `adb install --incremental` can be used without being asked
+/// for: adb must come from a platform-tools release that streams installs,
+/// and the device must have incremental delivery (Android 11 and later with
+/// IncFS).
+pub(crate) fn supports_incremental_install(adb: &dyn AdbRunner) -> bool {
+    adb_info(adb)
+        .and_then(|info| info.platform_tools)
+        .as_deref()
+        .and_then(parse_platform_tools_version)
+        .is_some_and(|version| version >= MIN_AUTO_INCREMENTAL_PLATFORM_TOOLS)
+        && get_device_output(
+            adb,
+            &["shell", "pm", "has-feature", INCREMENTAL_DELIVERY_FEATURE],
+        )
+        .is_some_and(|output| output == "true")
+}
+
+/// Reads `34.0.5` out of a platform-tools release like `34.0.5-10900879`.
+fn parse_platform_tools_version(release: &str) -> Option<AdbVersion> {
+    let number = release.split('-').next()?;
+    let mut parts = number.split('.').map(|part| part.parse::<u32>().ok());
+    Some(AdbVersion(parts.next()??, parts.next()??, parts.next()??))
+}
+
+/// Fails with a message naming `feature` when the detected adb is older than
+/// `minimum`. An unknown version is given the benefit of the doubt.
+pub(crate) fn require_adb_version(
+    adb: &dyn AdbRunner,
+    minimum: AdbVersion,
+    feature: &str,
+) -> Result<(), String> {
+    match adb_info(adb).and_then(|info| info.version) {
+        Some(version) if version < minimum => Err(format!(
+            "{} needs adb {} or newer, found {}. Please update platform-tools.",
+            feature, minimum, version
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// What to do about a device in an `adb devices` state other than
+/// `device`, for the states the user can fix.
+pub(crate) fn device_state_hint(state: &str) -> Option<&'static str> {
+    match short_device_state(state) {
+        "unauthorized" => Some("accept the USB debugging prompt on the device, then try again"),
+        "offline" => Some("reconnect the device, or run adb kill-server"),
+        "no permissions" => {
+            Some("adb may not access the USB device; check its udev rules or plugdev membership")
+        }
+        _ => None,
+    }
+}
+
+/// The state without the explanation and link adb appends to some, e.g.
+/// `no permissions (user in plugdev group; ...); see [...]`.
+pub(crate) fn short_device_state(state: &str) -> &str {
+    match state.find(" (") {
+        Some(end) => &state[..end],
+        None => state,
+    }
+}
+
+/// Whether `adb devices` output lists the device `serial`, or any device
+/// when there is none.
+fn lists_device(output: &Output, serial: Option<&str>) -> bool {
+    let serials = connected_serials(output);
+    match serial {
+        Some(serial) => serials.iter().any(|listed| listed == serial),
+        None => !serials.is_empty(),
+    }
+}
+
+/// Serials of the devices that `adb devices` lists as ready.
+fn connected_serials(output: &Output) -> Vec<String> {
+    device_states(output)
+        .into_iter()
+        .filter(|(_, state)| state == "device")
+        .map(|(serial, _)| serial)
+        .collect()
+}
+
+/// Every device `adb devices` lists, with its state: `device` when ready,
+/// or e.g. `unauthorized` or `offline`.
+pub(crate) fn device_states(output: &Output) -> Vec<(String, String)> {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let (serial, state) = line.split_once('\t')?;
+            Some((serial.trim().to_string(), state.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Checks through [`AdbRunner::connect`] that the device can be talked to,
+/// warning when adb is older than [`MIN_ADB_VERSION`].
+pub(crate) fn connect_device(adb: &dyn AdbRunner, warnings: &Warnings) -> Result<()> {
+    adb.connect()?;
+    if let Some(version) = adb_info(adb).and_then(|info| info.version)
+        && version < MIN_ADB_VERSION
+    {
+        warning!(
+            warnings,
+            "Warning: adb {} is older than {}, the oldest supported version. Some commands may fail; please update platform-tools.",
+            version,
+            MIN_ADB_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// The serial of the runner's device: the one it was given, or else what
+/// `adb get-serialno` reports.
+pub(crate) fn device_serial(adb: &dyn AdbRunner) -> Option<String> {
+    adb.serial()
+        .map(str::to_string)
+        .or_else(|| get_device_output(adb, &["get-serialno"]))
+}
+
+/// Whether the command talks to a device, so the command line settles which
+/// one before it runs.
+pub(crate) fn needs_device(command: &Commands) -> bool {
+    match command {
+        Commands::Backup(_)
+        | Commands::Install(_)
+        | Commands::Bench(_)
+        | Commands::List(_)
+        | Commands::Uninstall(_)
+        | Commands::Verify(_)
+        | Commands::Pull(_) => true,
+        Commands::Check(args) => args.repull,
+        _ => false,
+    }
+}
+
+/// Settles the device and user for the command line: with several devices
+/// attached and no `--serial` the user picks a device, and with `--user`
+/// given without an id, a user on it. Problems reaching the device are left
+/// for the command's own [`connect_device`] to report.
+pub(crate) fn choose_device(mut adb: SystemAdb, ask_user: bool) -> Result<SystemAdb> {
+    if adb.version().is_none() {
+        return Ok(adb);
+    }
+    adb.wait_for_device()?;
+
+    if adb.serial().is_none() {
+        let serials = connected_serials(&adb.run_global(&["devices"])?);
+        if serials.len() > 1 {
+            let Some(index) = select_from(&serials, "Multiple devices connected. Select device:")?
+            else {
+                return Err("No device selected.".into());
+            };
+            adb = adb.with_serial(&serials[index]);
+        }
+    }
+
+    if ask_user {
+        let users = get_device_users(&adb)?;
+        let index = if users.len() < 2 {
+            0
+        } else {
+            let names: Vec<_> = users
+                .iter()
+                .map(|user| format!("{} ({})", user.id, user.name))
+                .collect();
+            select_from(&names, "Select user:")?.ok_or("No user selected.")?
+        };
+        if let Some(user) = users.get(index) {
+            adb = adb.with_user(user.id);
+        }
+    }
+    Ok(adb)
+}
+
+/// An Android user or profile from `pm list users`.
+struct DeviceUser {
+    id: u32,
+    name: String,
+}
+
+/// Checks that the user `id` given to `--user` exists on the device.
+fn check_device_user(adb: &dyn AdbRunner, id: u32) -> Result<()> {
+    let users = get_device_users(adb)?;
+    if users.is_empty() || users.iter().any(|user| user.id == id) {
+        return Ok(());
+    }
+    let available: Vec<_> = users
+        .iter()
+        .map(|user| format!("{} ({})", user.id, user.name))
+        .collect();
+    Err(format!(
+        "User {} does not exist on the device. Available users: {}",
+        id,
+        available.join(", ")
+    )
+    .into())
+}
+
+fn get_device_users(adb: &dyn AdbRunner) -> Result<Vec<DeviceUser>> {
+    let args = ["shell", "pm", "list", "users"];
+    let output = adb.run(&args)?;
+    if !output.status.success() {
+        return Err(AppError::adb_command(&args, &output));
+    }
+    Ok(parse_device_users(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses `pm list users` output, whose user lines look like
+/// "\tUserInfo{10:Work profile:1030} running".
+fn parse_device_users(output: &str) -> Vec<DeviceUser> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let info = line.trim().strip_prefix("UserInfo{")?;
+            let info = &info[..info.find('}')?];
+            let (id, rest) = info.split_once(':')?;
+            let name = rest.rsplit_once(':').map_or(rest, |(name, _flags)| name);
+            Some(DeviceUser {
+                id: id.parse().ok()?,
+                name: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Arguments for `adb shell pm <command> <args>`, with `--user` inserted
+/// after `command` when the runner has a user.
+pub(crate) fn pm_args(adb: &dyn AdbRunner, command: &[&str], args: &[&str]) -> Vec<String> {
+    let mut pm: Vec<String> = ["shell", "pm"]
+        .iter()
+        .chain(command)
+        .map(|arg| arg.to_string())
+        .collect();
+    if let Some(user) = adb.user() {
+        pm.extend(["--user".to_string(), user.to_string()]);
+    }
+    pm.extend(args.iter().map(|arg| arg.to_string()));
+    pm
+}
+
+/// Finds `--adb-path` in the raw arguments, falling back to `$ADB`, the same
+/// way clap would for [`Cli::adb_path`](crate::Cli::adb_path).
+pub(crate) fn adb_path_arg(args: impl Iterator<Item = OsString>) -> Option<PathBuf> {
+    let mut args = args.take_while(|arg| arg != "--");
+    let mut found = None;
+    while let Some(arg) = args.next() {
+        if arg == "--adb-path" {
+            found = args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--adb-path=")) {
+            found = Some(PathBuf::from(path));
+        }
+    }
+    found.or_else(|| {
+        std::env::var_os("ADB")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+/// Runs adb commands against one device, and knows which device and user
+/// those are. Everything that talks to the device takes one of these
+/// instead of spawning adb itself, so tests can stand in canned output for
+/// a real device.
+pub trait AdbRunner: Sync {
+    /// Runs `adb <args>` to completion, like [`Command::output`].
+    fn run_os(&self, args: &[&OsStr]) -> io::Result<Output>;
+
+    /// Runs `adb <args>` with `input` as its stdin, e.g. for `exec-in`.
+    fn run_os_with_input(&self, args: &[&OsStr], input: File) -> io::Result<Output>;
+
+    /// Runs `adb <args>`, handing each line of its stdout to `on_line` as
+    /// it's printed. An error from `on_line` stops the command and is
+    /// returned. By default the lines are handed over once the command has
+    /// finished.
+    fn stream_os(
+        &self,
+        args: &[&OsStr],
+        on_line: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Output> {
+        let output = self.run_os(args)?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            on_line(line)?;
+        }
+        Ok(output)
+    }
+
+    /// Runs `adb <args>`, handing its raw stdout to `read` as a stream
+    /// rather than collecting it, e.g. to copy a file off the device. An
+    /// error from `read` stops the command and is returned. The output's
+    /// stdout is left empty. By default `read` gets the collected stdout
+    /// once the command has finished.
+    fn read_os(
+        &self,
+        args: &[&OsStr],
+        read: &mut dyn FnMut(&mut dyn Read) -> io::Result<()>,
+    ) -> io::Result<Output> {
+        let mut output = self.run_os(args)?;
+        read(&mut output.stdout.as_slice())?;
+        output.stdout.clear();
+        Ok(output)
+    }
+
+    /// Serial of the device the commands go to, when one was chosen.
+    fn serial(&self) -> Option<&str> {
+        None
+    }
+
+    /// Android user whose apps are listed, backed up and installed, when
+    /// one was chosen; otherwise adb's default, the current user.
+    fn user(&self) -> Option<u32> {
+        None
+    }
+
+    /// What `adb version` prints, or `None` when adb can't be run.
+    fn version(&self) -> Option<&str> {
+        None
+    }
+
+    /// Checks that the device can be talked to before a command starts,
+    /// without asking anything: failing with [`AppError::AdbNotFound`],
+    /// [`AppError::NoDevice`] or [`AppError::DeviceNotReady`], or when several
+    /// devices are attached and none was chosen. By default there is nothing
+    /// to check.
+    fn connect(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl dyn AdbRunner + '_ {
+    /// [`AdbRunner::run_os`] for any kind of string arguments.
+    pub(crate) fn run<S: AsRef<OsStr>>(&self, args: &[S]) -> io::Result<Output> {
+        self.run_os(&os_args(args))
+    }
+
+    /// [`AdbRunner::run_os_with_input`] for any kind of string arguments.
+    pub(crate) fn run_with_input<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        input: File,
+    ) -> io::Result<Output> {
+        self.run_os_with_input(&os_args(args), input)
+    }
+
+    /// [`AdbRunner::stream_os`] for any kind of string arguments.
+    fn run_streaming<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        on_line: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Output> {
+        self.stream_os(&os_args(args), on_line)
+    }
+}
+
+fn os_args<S: AsRef<OsStr>>(args: &[S]) -> Vec<&OsStr> {
+    args.iter().map(AsRef::as_ref).collect()
+}
+
+/// Runs the real adb binary, set up the way `--adb-path`, `--serial`,
+/// `--user`, `--timeout` and `--wait-for-device` set it up for the command
+/// line. Every command is recorded in the run log.
+#[derive(Default)]
+pub struct SystemAdb {
+    /// The adb binary; `adb` from PATH when unset.
+    pub(crate) path: Option<PathBuf>,
+    serial: Option<String>,
+    user: Option<u32>,
+    timeout: Option<Duration>,
+    /// How long [`AdbRunner::connect`] waits for the device to show up,
+    /// `Some(None)` for as long as it takes.
+    wait: Option<Option<Duration>>,
+    /// What `adb version` printed, once it has been run.
+    version: OnceLock<Option<String>>,
+}
+
+impl SystemAdb {
+    /// Runs `adb` from PATH against the only device attached, for the
+    /// device's current user.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs this adb binary instead, as `--adb-path` does.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self.version = OnceLock::new();
+        self
+    }
+
+    /// Talks to the device with this serial, as `--serial` does. Needed
+    /// when several devices are attached.
+    pub fn with_serial(mut self, serial: impl Into<String>) -> Self {
+        self.serial = Some(serial.into());
+        self
+    }
+
+    /// Works with the apps of this Android user, as `--user <ID>` does.
+    pub fn with_user(mut self, user: u32) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    /// Kills adb commands, transfers included, still running after
+    /// `timeout`, as `--timeout` does.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Makes [`AdbRunner::connect`] wait for the device to show up, for at
+    /// most `timeout` or, with `None`, for good, as `--wait-for-device`
+    /// does.
+    pub fn with_wait_for_device(mut self, timeout: Option<Duration>) -> Self {
+        self.wait = Some(timeout);
+        self
+    }
+
+    /// Builds a command running the adb binary.
+    fn program(&self) -> Command {
+        let mut command = match &self.path {
+            Some(path) => Command::new(path),
+            None => Command::new("adb"),
+        };
+        interrupt::shield(&mut command);
+        command
+    }
+
+    /// Builds an adb command targeting the device.
+    fn command(&self) -> Command {
+        let mut command = self.program();
+        if let Some(serial) = &self.serial {
+            command.arg("-s").arg(serial);
+        }
+        command
+    }
+
+    /// Runs adb without selecting a device, for commands such as `devices`
+    /// and `connect`.
+    pub(crate) fn run_global(&self, args: &[&str]) -> io::Result<Output> {
+        let mut command = self.program();
+        command.args(args);
+        let start = Instant::now();
+        let result = command.output();
+        runlog::record(&command, &result, start.elapsed());
+        result
+    }
+
+    /// Runs an adb command, retrying it once if it failed because the adb
+    /// server had to be restarted due to a client/server version mismatch.
+    /// If the device isn't listed again, the failed output is returned.
+    fn run_adb(&self, args: &[&OsStr]) -> io::Result<Output> {
+        let output = self.run_adb_with_timeout(args)?;
+        if output.status.success() || !is_server_version_mismatch(&output) {
+            return Ok(output);
+        }
+
+        if !self.wait_for_server_restart() {
+            return Ok(output);
+        }
+        self.run_adb_with_timeout(args)
+    }
+
+    /// Runs an adb command like [`Command::output`], but kills it and
+    /// returns a `TimedOut` error if it's still running after the timeout.
+    fn run_adb_with_timeout(&self, args: &[&OsStr]) -> io::Result<Output> {
+        let mut command = self.command();
+        command.args(args);
+        let start = Instant::now();
+        let result = match self.timeout {
+            Some(timeout) => output_with_timeout(command.stdin(Stdio::null()), timeout),
+            None => command.output(),
+        };
+        runlog::record(&command, &result, start.elapsed());
+        result
+    }
+
+    /// Runs an adb command like [`SystemAdb::run_adb`], but hands each line
+    /// of its stdout to `on_line` as adb prints it instead of once it exits.
+    /// An error from `on_line` stops the command and is returned, as is
+    /// `NoDevice` if the device isn't listed again after a server restart.
+    fn run_adb_streaming(
+        &self,
+        args: &[&OsStr],
+        on_line: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Output> {
+        let output = self.stream_adb(args, on_line)?;
+        if output.status.success() || !is_server_version_mismatch(&output) {
+            return Ok(output);
+        }
+
+        if !self.wait_for_server_restart() {
+            return Err(AppError::NoDevice);
+        }
+        self.stream_adb(args, on_line)
+    }
+
+    fn stream_adb(
+        &self,
+        args: &[&OsStr],
+        on_line: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Output> {
+        let mut command = self.command();
+        command.args(args);
+        let mut error = None;
+        let start = Instant::now();
+        let result = output_by_line(&mut command, self.timeout, &mut |line| {
+            error = on_line(line).err();
+            error.is_none()
+        });
+        runlog::record(&command, &result, start.elapsed());
+        match error {
+            Some(error) => Err(error),
+            None => Ok(result?),
+        }
+    }
+
+    /// Runs an adb command with `input` as its stdin, e.g. for `exec-in`.
+    fn run_adb_with_input(&self, args: &[&OsStr], input: File) -> io::Result<Output> {
+        let mut command = self.command();
+        command.args(args).stdin(input);
+        let start = Instant::now();
+        let result = match self.timeout {
+            Some(timeout) => output_with_timeout(&mut command, timeout),
+            None => command.output(),
+        };
+        runlog::record(&command, &result, start.elapsed());
+        result
+    }
+
+    /// Runs an adb command with its stdout handed to `read` as it arrives,
+    /// killing it once the timeout passes. The run log records it without
+    /// its stdout.
+    fn read_adb(
+        &self,
+        args: &[&OsStr],
+        read: &mut dyn FnMut(&mut dyn Read) -> io::Result<()>,
+    ) -> io::Result<Output> {
+        let mut command = self.command();
+        command.args(args);
+        let start = Instant::now();
+        let result = (|| {
+            let mut child = command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            let stderr = child.stderr.take().map(read_in_background);
+            let stdout = child.stdout.take();
+            let child = Arc::new(Mutex::new(child));
+            let watchdog = self
+                .timeout
+                .map(|timeout| kill_after(Arc::clone(&child), timeout));
+            let read_result = match stdout {
+                Some(mut stdout) => read(&mut stdout),
+                None => Ok(()),
+            };
+            let timed_out = watchdog.is_some_and(Watchdog::finish);
+            let mut child = child.lock().unwrap();
+            if read_result.is_err() {
+                let _ = child.kill();
+            }
+            let status = child.wait()?;
+            let stderr = stderr.and_then(|s| s.join().ok()).unwrap_or_default();
+            if timed_out && (read_result.is_err() || !status.success()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "adb timed out after {}s",
+                        self.timeout.unwrap_or_default().as_secs()
+                    ),
+                ));
+            }
+            // adb's own complaint explains a cut off stream better.
+            if let Err(e) = read_result {
+                let message = String::from_utf8_lossy(&stderr).trim().to_string();
+                return Err(match message.is_empty() {
+                    true => e,
+                    false => io::Error::new(e.kind(), message),
+                });
+            }
+            Ok(Output {
+                status,
+                stdout: Vec::new(),
+                stderr,
+            })
+        })();
+        runlog::record(&command, &result, start.elapsed());
+        result
+    }
+
+    /// Waits for adb to finish restarting its server and for the device to
+    /// be listed again. Returns whether the device came back.
+    fn wait_for_server_restart(&self) -> bool {
+        eprintln!("adb server version mismatch detected. Waiting for the server to restart...");
+
+        for _ in 0..SERVER_RESTART_POLLS {
+            thread::sleep(SERVER_RESTART_INTERVAL);
+            if let Ok(output) = self.run_global(&["devices"])
+                && lists_device(&output, self.serial())
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Checks that the device, or any device when none was chosen, is
+    /// ready. A device that is listed but unauthorized, offline or lacking
+    /// USB permissions gets an error saying what to do about it.
+    fn check_device_connected(&self) -> Result<()> {
+        let output = match self.run_global(&["devices"]) {
+            Ok(output) if is_server_version_mismatch(&output) => {
+                return if self.wait_for_server_restart() {
+                    Ok(())
+                } else {
+                    Err(AppError::NoDevice)
+                };
+            }
+            Ok(output) => output,
+            Err(_) => return Err(AppError::NoDevice),
+        };
+        if lists_device(&output, self.serial()) {
+            return Ok(());
+        }
+
+        let not_ready = device_states(&output)
+            .into_iter()
+            .filter(|(serial, _)| self.serial().is_none_or(|selected| selected == serial))
+            .find_map(|(serial, state)| {
+                let hint = device_state_hint(&state)?;
+                Some((serial, state, hint))
+            });
+        match not_ready {
+            Some((serial, state, hint)) => Err(AppError::DeviceNotReady {
+                serial,
+                state: short_device_state(&state).to_string(),
+                hint,
+            }),
+            None => Err(AppError::NoDevice),
+        }
+    }
+
+    /// Checks for a ready device like [`SystemAdb::check_device_connected`],
+    /// but with a wait set keeps polling until one shows up or the wait runs
+    /// out.
+    fn wait_for_device(&self) -> Result<()> {
+        let Some(timeout) = self.wait else {
+            return self.check_device_connected();
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut last_message = None;
+        loop {
+            let error = match self.check_device_connected() {
+                Ok(()) => return Ok(()),
+                Err(e @ (AppError::NoDevice | AppError::DeviceNotReady { .. })) => e,
+                Err(e) => return Err(e),
+            };
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(error);
+            }
+
+            if last_message.is_none() {
+                status!("Waiting for a device...");
+            }
+            let message = error.to_string();
+            if matches!(error, AppError::DeviceNotReady { .. })
+                && last_message.as_ref() != Some(&message)
+            {
+                status!("  {}", message);
+            }
+            last_message = Some(message);
+            thread::sleep(DEVICE_WAIT_INTERVAL);
+        }
+    }
+}
+
+impl AdbRunner for SystemAdb {
+    fn run_os(&self, args: &[&OsStr]) -> io::Result<Output> {
+        self.run_adb(args)
+    }
+
+    fn run_os_with_input(&self, args: &[&OsStr], input: File) -> io::Result<Output> {
+        self.run_adb_with_input(args, input)
+    }
+
+    fn stream_os(
+        &self,
+        args: &[&OsStr],
+        on_line: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Output> {
+        self.run_adb_streaming(args, on_line)
+    }
+
+    fn read_os(
+        &self,
+        args: &[&OsStr],
+        read: &mut dyn FnMut(&mut dyn Read) -> io::Result<()>,
+    ) -> io::Result<Output> {
+        self.read_adb(args, read)
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    fn user(&self) -> Option<u32> {
+        self.user
+    }
+
+    fn version(&self) -> Option<&str> {
+        self.version
+            .get_or_init(|| {
+                let output = self.program().arg("version").output().ok()?;
+                Some(String::from_utf8_lossy(&output.stdout).to_string())
+            })
+            .as_deref()
+    }
+
+    fn connect(&self) -> Result<()> {
+        if self.version().is_none() {
+            return Err(AppError::AdbNotFound);
+        }
+        self.wait_for_device()?;
+        if self.serial.is_none() {
+            let serials = connected_serials(&self.run_global(&["devices"])?);
+            if serials.len() > 1 {
+                return Err(format!(
+                    "{} devices are connected ({}); choose one with --serial.",
+                    serials.len(),
+                    serials.join(", ")
+                )
+                .into());
+            }
+        }
+        if let Some(user) = self.user {
+            check_device_user(self, user)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a command like [`Command::output`], but kills it and returns a
+/// `TimedOut` error if it's still running after `timeout`. Stdin is left as
+/// the caller set it.
+fn output_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain both pipes while waiting so a chatty command can't block on a
+    // full pipe buffer.
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("adb timed out after {}s", timeout.as_secs()),
+            ));
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    };
+
+    let collect = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+        reader
+            .map(|reader| reader.join().unwrap_or_default())
+            .unwrap_or_default()
+    };
+    Ok(Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+/// Runs a command like [`output_with_timeout`], calling `on_line` with each
+/// line of stdout as it's read. The command is killed once `on_line`
+/// returns false. The output still holds all of stdout, for the run log.
+fn output_by_line(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    on_line: &mut dyn FnMut(&str) -> bool,
+) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stderr = child.stderr.take().map(read_in_background);
+    let (sender, lines) = mpsc::channel();
+    let reader = child.stdout.take().map(|stdout| {
+        thread::spawn(move || {
+            for line in io::BufReader::new(stdout).split(b'\n') {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        })
+    });
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut stdout = Vec::new();
+    loop {
+        let line = match deadline {
+            Some(deadline) => {
+                lines.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => lines
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match line {
+            Ok(line) => {
+                stdout.extend_from_slice(&line);
+                stdout.push(b'\n');
+                let line = String::from_utf8_lossy(&line);
+                if !on_line(line.trim_end_matches('\r')) {
+                    let _ = child.kill();
+                    break;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "adb timed out after {}s",
+                        timeout.unwrap_or_default().as_secs()
+                    ),
+                ));
+            }
+        }
+    }
+    drop(lines);
+    if let Some(reader) = reader {
+        let _ = reader.join();
+    }
+
+    let status = child.wait()?;
+    Ok(Output {
+        status,
+        stdout,
+        stderr: stderr
+            .map(|reader| reader.join().unwrap_or_default())
+            .unwrap_or_default(),
+    })
+}
+
+/// Kills a child that is still running once a timeout passes, unless
+/// [`Watchdog::finish`] is called first.
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Stops watching, and returns whether the child was killed.
+    fn finish(self) -> bool {
+        drop(self.done);
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+fn kill_after(child: Arc<Mutex<Child>>, timeout: Duration) -> Watchdog {
+    let (done, finished) = mpsc::channel::<()>();
+    let thread = thread::spawn(move || {
+        let expired = finished.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout);
+        if expired {
+            let _ = child.lock().unwrap().kill();
+        }
+        expired
+    });
+    Watchdog { done, thread }
+}
+
+fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = reader.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+fn is_server_version_mismatch(output: &Output) -> bool {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    [stdout, stderr].iter().any(|text| {
+        text.contains("doesn't match this client") || text.contains("adb server is out of date")
+    })
+}
+
+pub(crate) fn get_third_party_packages(adb: &dyn AdbRunner) -> Result<Vec<String>> {
+    get_packages(adb, "-3")
+}
+
+/// Lists installed packages matching a `pm list packages` filter flag such
+/// as `-3` (third-party) or `-s` (system).
+pub(crate) fn get_packages(adb: &dyn AdbRunner, filter: &str) -> Result<Vec<String>> {
+    let mut packages = Vec::new();
+    stream_packages(adb, filter, &mut |package| {
+        packages.push(package);
+        Ok(())
+    })?;
+    Ok(packages)
+}
+
+/// Like [`get_packages`], but hands each package to `on_package` as soon as
+/// `pm` prints it, so a long list can be shown while it's still coming in.
+pub(crate) fn stream_packages(
+    adb: &dyn AdbRunner,
+    filter: &str,
+    on_package: &mut dyn FnMut(String) -> Result<()>,
+) -> Result<()> {
+    let args = pm_args(adb, &["list", "packages"], &[filter]);
+    let output = adb.run_streaming(&args, &mut |line| match line.strip_prefix("package:") {
+        Some(package) => on_package(package.trim().to_string()),
+        None => Ok(()),
+    })?;
+
+    if !output.status.success() {
+        return Err(AppError::adb_command(&args, &output));
+    }
+    Ok(())
+}
+
+/// Looks up the installed version code of each package. Uses a single
+/// `pm list packages --show-versioncode` where supported, falling back to
+/// `dumpsys package` per package on older devices.
+pub(crate) fn get_version_codes(adb: &dyn AdbRunner, packages: &[String]) -> HashMap<String, u64> {
+    let mut version_codes = HashMap::new();
+
+    if let Ok(output) = adb.run(&pm_args(
+        adb,
+        &["list", "packages"],
+        &["--show-versioncode"],
+    )) && output.status.success()
+    {
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            // package:com.example.app versionCode:123
+            let Some(rest) = line.trim().strip_prefix("package:") else {
+                continue;
+            };
+            let mut parts = rest.split_whitespace();
+            let (Some(name), Some(version)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            if let Some(code) = version
+                .strip_prefix("versionCode:")
+                .and_then(|code| code.parse().ok())
+            {
+                version_codes.insert(name.to_string(), code);
+            }
+        }
+    }
+
+    for package in packages {
+        if !version_codes.contains_key(package)
+            && let Some(code) = get_dumpsys_version_code(adb, package)
+        {
+            version_codes.insert(package.clone(), code);
+        }
+    }
+
+    version_codes
+}
+
+/// Maps packages to the package that installed them (e.g. the Play Store),
+/// from `pm list packages -i`: only third-party packages, unless
+/// `include_system`. Packages without a known installer are left out.
+pub(crate) fn get_installers(adb: &dyn AdbRunner, include_system: bool) -> HashMap<String, String> {
+    let filter: &[&str] = if include_system {
+        &["-i"]
+    } else {
+        &["-3", "-i"]
+    };
+    let Ok(output) = adb.run(&pm_args(adb, &["list", "packages"], filter)) else {
+        return HashMap::new();
+    };
+    if !output.status.success() {
+        return HashMap::new();
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            // package:com.example.app  installer=com.android.vending
+            let mut parts = line.trim().strip_prefix("package:")?.split_whitespace();
+            let name = parts.next()?;
+            let installer = parts.find_map(|part| part.strip_prefix("installer="))?;
+            (installer != "null").then(|| (name.to_string(), installer.to_string()))
+        })
+        .collect()
+}
+
+pub(crate) fn get_dumpsys_version_code(adb: &dyn AdbRunner, package_name: &str) -> Option<u64> {
+    parse_dumpsys_version_code(&dumpsys_package(adb, package_name)?)
+}
+
+/// The latest `firstInstallTime` or `lastUpdateTime` in `dumpsys package`
+/// output. Both are `yyyy-MM-dd HH:mm:ss` in the device's local time; newer
+/// Android versions also repeat `firstInstallTime` per user, and hidden
+/// system packages have their own copies, so every occurrence is read.
+pub(crate) fn last_install_time(dumpsys: &str) -> Option<NaiveDateTime> {
+    const TIMESTAMP_LEN: usize = "yyyy-MM-dd HH:mm:ss".len();
+    ["firstInstallTime=", "lastUpdateTime="]
+        .iter()
+        .flat_map(|key| {
+            dumpsys
+                .match_indices(key)
+                .map(move |(at, _)| at + key.len())
+        })
+        .filter_map(|start| dumpsys.get(start..start + TIMESTAMP_LEN))
+        .filter_map(|time| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").ok())
+        .max()
+}
+
+pub(crate) fn dumpsys_package(adb: &dyn AdbRunner, package_name: &str) -> Option<String> {
+    let output = adb
+        .run(&["shell", "dumpsys", "package", package_name])
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// The runtime permissions `package` has been granted for the selected
+/// user, as recorded by `--with-permissions`.
+pub(crate) fn granted_permissions(
+    adb: &dyn AdbRunner,
+    package: &str,
+    log: &mut PackageLog,
+) -> Vec<String> {
+    let Some(dumpsys) = dumpsys_package(adb, package) else {
+        log.warn("    Warning: Could not read granted permissions".to_string());
+        return Vec::new();
+    };
+    let permissions = parse_runtime_permissions(&dumpsys, adb.user().unwrap_or(0));
+    log.info(format!(
+        "    Recorded {} granted permission(s)",
+        permissions.len()
+    ));
+    permissions
+}
+
+/// Reads the granted runtime permissions of `user` from `dumpsys package`
+/// output, where each user's state has a block like
+///
+/// ```text
+///     User 0: ceDataInode=4242 installed=true hidden=false ...
+///       runtime permissions:
+///         android.permission.CAMERA: granted=true, flags=[ USER_SET ]
+///         android.permission.RECORD_AUDIO: granted=false, flags=[ USER_SET ]
+/// ```
+///
+/// Only the first such block is read: later sections, such as hidden system
+/// packages, can repeat it for another copy of the package.
+fn parse_runtime_permissions(dumpsys: &str, user: u32) -> Vec<String> {
+    let user_header = format!("User {}:", user);
+    let mut user_indent = None;
+    let mut section_indent = None;
+    let mut permissions = Vec::new();
+
+    for line in dumpsys.lines() {
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+
+        if let Some(section) = section_indent {
+            if indent <= section {
+                break;
+            }
+            if let Some((name, state)) = text.split_once(": ")
+                && state.starts_with("granted=true")
+            {
+                permissions.push(name.to_string());
+            }
+            continue;
+        }
+
+        if user_indent.is_some_and(|user| indent <= user) {
+            user_indent = None;
+        }
+        if text.starts_with(&user_header) {
+            user_indent = Some(indent);
+        } else if user_indent.is_some() && text == "runtime permissions:" {
+            section_indent = Some(indent);
+        }
+    }
+
+    permissions
+}
+
+/// Reads the first `versionCode=` from `dumpsys package` output, as in
+/// "    versionCode=123 minSdk=21 targetSdk=34".
+fn parse_dumpsys_version_code(dumpsys: &str) -> Option<u64> {
+    dumpsys
+        .split_whitespace()
+        .find_map(|token| token.strip_prefix("versionCode="))
+        .and_then(|code| code.parse().ok())
+}
+
+/// Reads the first `versionName=` line from `dumpsys package` output.
+pub(crate) fn parse_dumpsys_version_name(dumpsys: &str) -> Option<String> {
+    dumpsys
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("versionName="))
+        .map(str::to_string)
+}
+
+pub(crate) fn get_package_paths(adb: &dyn AdbRunner, package_name: &str) -> Result<Vec<String>> {
+    let args = pm_args(adb, &["path"], &[package_name]);
+    let output = adb.run(&args)?;
+
+    if !output.status.success() {
+        return Err(AppError::adb_command(&args, &output));
+    }
+
+    let paths = parse_pm_path(&String::from_utf8_lossy(&output.stdout));
+    if paths.is_empty() {
+        return Err(AppError::NoApkPaths(package_name.to_string()));
+    }
+
+    Ok(paths)
+}
+
+/// Reads the APK paths from `pm path` output, one `package:<path>` line per
+/// APK. Only the leading `package:` is stripped, since install folders such
+/// as `/data/app/~~AbC==/` can contain anything. Some Android versions
+/// append `=<package>` or space separated attributes after the file name,
+/// which are dropped, and some list a path twice, which is kept once. Lines
+/// that aren't absolute paths are ignored.
+///
+/// The split name isn't kept from here: after a pull it is read from each
+/// APK's own manifest, which names the file unless --no-splits-merge.
+fn parse_pm_path(output: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    output
+        .lines()
+        .filter_map(|line| {
+            let path = line.trim().strip_prefix("package:")?.trim();
+            let path = if path.ends_with(".apk") {
+                path
+            } else {
+                path.match_indices(".apk")
+                    .map(|(start, _)| start + ".apk".len())
+                    .filter(|&end| path[end..].starts_with(['=', ' ', '\t']))
+                    .last()
+                    .map_or(path, |end| &path[..end])
+            };
+            (path.starts_with('/') && Path::new(path).file_name().is_some())
+                .then(|| path.to_string())
+        })
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
+/// Whether `su` on the device gives a root shell.
+pub(crate) fn device_has_root(adb: &dyn AdbRunner) -> bool {
+    adb.run(&["shell", "su", "-c", "id"]).is_ok_and(|output| {
+        output.status.success() && String::from_utf8_lossy(&output.stdout).contains("uid=0")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeAdb, fake_output};
+
+    #[test]
+    fn get_package_paths_lists_every_apk() {
+        let adb = FakeAdb::default().with(
+            "shell pm path com.example",
+            "package:/data/app/com.example-1/base.apk\n\
+             package:/data/app/com.example-1/split_config.en.apk\n",
+        );
+        assert_eq!(
+            get_package_paths(&adb, "com.example").unwrap(),
+            [
+                "/data/app/com.example-1/base.apk",
+                "/data/app/com.example-1/split_config.en.apk",
+            ]
+        );
+    }
+
+    #[test]
+    fn get_package_paths_fails_without_paths() {
+        let adb = FakeAdb::default().with("shell pm path com.example", "\n");
+        assert!(matches!(
+            get_package_paths(&adb, "com.example"),
+            Err(AppError::NoApkPaths(package)) if package == "com.example"
+        ));
+        assert!(get_package_paths(&adb, "com.missing").is_err());
+    }
+
+    #[test]
+    fn parse_pm_path_reads_split_apks() {
+        let output = "package:/data/app/~~Xy1==/com.example-Ab2==/base.apk\n\
+                      package:/data/app/~~Xy1==/com.example-Ab2==/split_config.en.apk\n";
+        assert_eq!(
+            parse_pm_path(output),
+            [
+                "/data/app/~~Xy1==/com.example-Ab2==/base.apk",
+                "/data/app/~~Xy1==/com.example-Ab2==/split_config.en.apk",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_pm_path_strips_package_suffix() {
+        let output = "package:/data/app/com.example-1/base.apk=com.example\n";
+        assert_eq!(parse_pm_path(output), ["/data/app/com.example-1/base.apk"]);
+    }
+
+    #[test]
+    fn parse_pm_path_keeps_apk_like_folder_names() {
+        let output = "package:/data/app/~~odd.apk==/com.example-1/base.apk\n";
+        assert_eq!(
+            parse_pm_path(output),
+            ["/data/app/~~odd.apk==/com.example-1/base.apk"]
+        );
+    }
+
+    #[test]
+    fn parse_pm_path_only_strips_leading_prefix() {
+        let output = "package:/data/app/package:weird/base.apk\r\n";
+        assert_eq!(parse_pm_path(output), ["/data/app/package:weird/base.apk"]);
+    }
+
+    #[test]
+    fn parse_pm_path_keeps_spaces_inside_paths() {
+        let output = "  package: /data/app/my app/base.apk  \n";
+        assert_eq!(parse_pm_path(output), ["/data/app/my app/base.apk"]);
+    }
+
+    #[test]
+    fn parse_pm_path_strips_trailing_attributes() {
+        let output = "package:/data/app/~~odd.apk==/com.example-1/base.apk=com.example\n\
+                      package:/data/app/com.example-1/split_config.en.apk  installer=null\n\
+                      package:/data/app/my app.apk dir/split_b.apk\tuser=0\n";
+        assert_eq!(
+            parse_pm_path(output),
+            [
+                "/data/app/~~odd.apk==/com.example-1/base.apk",
+                "/data/app/com.example-1/split_config.en.apk",
+                "/data/app/my app.apk dir/split_b.apk",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_pm_path_drops_duplicates() {
+        // Android 4 single APKs, then a repeated split with and without a
+        // suffix.
+        let output = "package:/data/app/com.example-1.apk\n\
+                      package:/data/app/com.example-1.apk\n\
+                      package:/data/app/x/split_a.apk=com.example\n\
+                      package:/data/app/x/split_a.apk\n";
+        assert_eq!(
+            parse_pm_path(output),
+            ["/data/app/com.example-1.apk", "/data/app/x/split_a.apk"]
+        );
+    }
+
+    #[test]
+    fn parse_pm_path_ignores_noise() {
+        let output = "WARNING: linker: unused DT entry\n\
+                      \n\
+                      package:\n\
+                      package:relative/base.apk\n\
+                      package:/\n\
+                      package:/data/app/com.example-1/base.apk\n";
+        assert_eq!(parse_pm_path(output), ["/data/app/com.example-1/base.apk"]);
+    }
+
+    #[test]
+    fn adb_path_arg_takes_last_flag_before_separator() {
+        let args = [
+            "list",
+            "--adb-path",
+            "/a/adb",
+            "--adb-path=/b/adb",
+            "--",
+            "--adb-path=/c",
+        ];
+        assert_eq!(
+            adb_path_arg(args.into_iter().map(OsString::from)),
+            Some(PathBuf::from("/b/adb"))
+        );
+    }
+
+    #[test]
+    fn device_state_hint_ignores_adb_explanations() {
+        let output = fake_output(
+            0,
+            "List of devices attached\n\
+             abc\tno permissions (missing udev rules? user is in the plugdev group); see [http://x]\n\
+             def\tunauthorized\n\
+             ghi\tbootloader\n\n",
+        );
+        let states: Vec<_> = device_states(&output)
+            .into_iter()
+            .map(|(serial, state)| (serial, short_device_state(&state).to_string()))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("abc".to_string(), "no permissions".to_string()),
+                ("def".to_string(), "unauthorized".to_string()),
+                ("ghi".to_string(), "bootloader".to_string()),
+            ]
+        );
+        assert!(device_state_hint(&device_states(&output)[0].1).is_some());
+        assert!(device_state_hint("bootloader").is_none());
+    }
+
+    #[test]
+    fn parse_runtime_permissions_reads_granted_for_user() {
+        let dumpsys = "\
+    Packages:
+      Package [com.example] (a1b2c3):
+    versionCode=42 minSdk=24 targetSdk=34
+    install permissions:
+      android.permission.INTERNET: granted=true
+    User 0: ceDataInode=4242 installed=true hidden=false
+      gids=[3003]
+      runtime permissions:
+        android.permission.CAMERA: granted=true, flags=[ USER_SET ]
+        android.permission.RECORD_AUDIO: granted=false, flags=[ USER_SET ]
+      disabledComponents:
+        com.example.Receiver
+    User 10: ceDataInode=0 installed=true hidden=false
+      runtime permissions:
+        android.permission.READ_CONTACTS: granted=true
+    Hidden system packages:
+      Package [com.example] (d4e5f6):
+    User 0: installed=true
+      runtime permissions:
+        android.permission.READ_SMS: granted=true
+    ";
+        assert_eq!(
+            parse_runtime_permissions(dumpsys, 0),
+            ["android.permission.CAMERA"]
+        );
+        assert_eq!(
+            parse_runtime_permissions(dumpsys, 10),
+            ["android.permission.READ_CONTACTS"]
+        );
+        assert!(parse_runtime_permissions(dumpsys, 1).is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+//! The error type returned by every command, so callers can tell failure
+//! kinds apart instead of matching on message text.
+
+use std::ffi::OsStr;
+use std::io;
+use std::process::Output;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("ADB command not found.")]
+    AdbNotFound,
+    #[error("Device is disconnected. Please check device connection.")]
+    NoDevice,
+    /// A device is attached but adb can't use it yet, e.g. because the USB
+    /// debugging prompt hasn't been accepted.
+    #[error("Device {serial} is not ready ({state}): {hint}.")]
+    DeviceNotReady {
+        serial: String,
+        state: String,
+        hint: &'static str,
+    },
+    /// adb ran but reported a failure.
+    #[error("adb {args} failed: {stderr}")]
+    AdbCommand { args: String, stderr: String },
+    /// `pm path` listed no APKs for the package, as it can for packages in
+    /// an odd state or right after boot.
+    #[error("pm path lists no APKs for {0}")]
+    NoApkPaths(String),
+    #[error("Backup not found: {0}")]
+    BackupNotFound(String),
+    /// The run finished, or stopped early, with some packages not backed
+    /// up, installed or uninstalled.
+    #[error("{0}")]
+    PackagesFailed(String),
+    /// Copying a backup to or from a `--remote` store failed, or no tool to
+    /// copy it with was found.
+    #[error("{0}")]
+    Transfer(String),
+    /// Ctrl-C stopped a backup after the packages in progress finished.
+    #[error("{0}")]
+    Interrupted(String),
+    /// `--strict` was given and the run printed this many warnings.
+    #[error("Strict mode: {0} warning(s) reported, failing the run.")]
+    StrictWarnings(usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// Anything without a variant of its own, described for the user.
+    #[error("{0}")]
+    Message(String),
+}
+
+pub type Result<T, E = AppError> = std::result::Result<T, E>;
+
+/// The process exit statuses, as listed in `--help`. Usage errors exit with
+/// 2, as clap does for every command line tool built on it.
+pub const EXIT_STATUS_HELP: &str = "\
+Exit status:
+    0  Success
+    1  Any other error, or warnings under --strict
+    2  Invalid command line
+    3  adb not found
+    4  No device connected, or the device isn't ready
+    5  Backup not found
+    6  Some packages failed to back up, install or uninstall
+    7  Copying a backup to or from a remote store failed
+  130  Interrupted with Ctrl-C";
+
+impl AppError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AppError::AdbNotFound => 3,
+            AppError::NoDevice | AppError::DeviceNotReady { .. } => 4,
+            AppError::BackupNotFound(_) => 5,
+            AppError::PackagesFailed(_) => 6,
+            AppError::Transfer(_) => 7,
+            AppError::Interrupted(_) => 130,
+            AppError::AdbCommand { .. }
+            | AppError::NoApkPaths(_)
+            | AppError::Io(_)
+            | AppError::Json(_)
+            | AppError::StrictWarnings(_)
+            | AppError::Message(_) => 1,
+        }
+    }
+
+    /// An [`AppError::AdbCommand`] for a finished adb call, using its stderr,
+    /// or stdout when stderr is empty.
+    pub fn adb_command<S: AsRef<OsStr>>(args: &[S], output: &Output) -> Self {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = if stderr.trim().is_empty() {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        } else {
+            stderr.trim().to_string()
+        };
+        AppError::AdbCommand {
+            args: args
+                .iter()
+                .map(|arg| arg.as_ref().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+            stderr: message,
+        }
+    }
+}
+
+impl From<String> for AppError {
+    fn from(message: String) -> Self {
+        AppError::Message(message)
+    }
+}
+
+impl From<&str> for AppError {
+    fn from(message: &str) -> Self {
+        AppError::Message(message.to_string())
+    }
+}
@@ -0,0 +1,104 @@
+//! Progress events for programs driving apktool, from
+//! `--progress-format json`.
+//!
+//! Each event is one JSON object on its own line on stderr, with an `event`
+//! field naming it. Other stderr output, such as warnings, is never valid
+//! JSON, so readers can skip lines that don't parse. Events and fields are
+//! only ever added, never renamed or removed:
+//!
+//! - `backup_started`: `backup`, `packages`
+//! - `install_started`: `packages`
+//! - `package_started`: `package`, `index` (from 1), `total`
+//! - `file_pulled`: `package`, `file`, `bytes`
+//! - `package_deferred`: `package`, `library` (install, retried later)
+//! - `package_completed`: `package`, `bytes` (backup only)
+//! - `package_failed`: `package`, `error`
+//! - `package_skipped`: `package`, `reason` (backup only)
+//! - `backup_completed`: `backup`, `succeeded`, `failed`, `skipped`,
+//!   `total_bytes`, `duration_secs`; `skipped` counts packages with no APKs
+//!   and those `--stop-on-error` never started
+//! - `install_completed`: `installed`, `failed`, `skipped`
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum ProgressFormat {
+    /// Only the usual output
+    #[default]
+    Human,
+    /// Also write JSON lines events to stderr
+    Json,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init(format: ProgressFormat) {
+    ENABLED.store(matches!(format, ProgressFormat::Json), Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    BackupStarted {
+        backup: &'a Path,
+        packages: usize,
+    },
+    InstallStarted {
+        packages: usize,
+    },
+    PackageStarted {
+        package: &'a str,
+        index: usize,
+        total: usize,
+    },
+    FilePulled {
+        package: &'a str,
+        file: &'a str,
+        bytes: u64,
+    },
+    PackageDeferred {
+        package: &'a str,
+        library: Option<&'a str>,
+    },
+    PackageCompleted {
+        package: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+    },
+    PackageFailed {
+        package: &'a str,
+        error: &'a str,
+    },
+    PackageSkipped {
+        package: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+    BackupCompleted {
+        backup: &'a Path,
+        succeeded: usize,
+        failed: usize,
+        skipped: usize,
+        total_bytes: u64,
+        duration_secs: f64,
+    },
+    InstallCompleted {
+        installed: usize,
+        failed: usize,
+        skipped: usize,
+    },
+}
+
+/// Writes `event` to stderr when events are on. The line is written in one
+/// go, so events from parallel workers don't interleave.
+pub fn emit(event: Event) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(line) = serde_json::to_string(&event) {
+        eprintln!("{}", line);
+    }
+}
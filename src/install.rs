@@ -0,0 +1,1553 @@
+//! The `install` subcommand and [`install`](fn@crate::install): checking a
+//! backup against the device and installing its packages one by one.
+
+use crate::archive::TempDir;
+use crate::config::Config;
+use crate::device::{
+    AdbRunner, DeviceTarget, MIN_INCREMENTAL_ADB_VERSION, MIN_INSTALL_MULTIPLE_ADB_VERSION,
+    connect_device, device_serial, get_device_output, get_device_target, get_version_codes,
+    pm_args, require_adb_version, supports_incremental_install,
+};
+use crate::error::{AppError, Result};
+use crate::events::Event;
+use crate::manifest::{Manifest, PackageEntry, sha256_file};
+use crate::output::Warnings;
+use crate::pull::{EXTERNAL_DATA, OBB_FILES, restore_app_data, restore_shared_dir};
+use crate::{
+    DEVICE_INFO_DIR, ReportList, axml, bundle, choose_backup, describe_created_by, events,
+    fetch_remote_backup, list_package_dirs, open_backup, output, parse_args, read_report_list,
+    remote, select_many_from,
+};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Options of the `install` subcommand.
+#[derive(Args)]
+pub struct InstallArgs {
+    /// Backup folder or archive name under `backup/`, or a path to one;
+    /// asks when omitted
+    #[arg(conflicts_with_all = ["bundle", "from_remote"])]
+    backup: Option<String>,
+    /// Install a downloaded .apks, .xapk or .apkm bundle instead of a backup
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["packages", "select", "verify"])]
+    bundle: Option<PathBuf>,
+    /// Install a backup folder or archive fetched from another machine over
+    /// SSH, e.g. one copied there with `backup --remote`
+    ///
+    /// The backup is copied to a scratch folder with rsync or scp and
+    /// deleted once installed.
+    #[arg(long, value_name = "[USER@]HOST:PATH", conflicts_with = "bundle")]
+    from_remote: Option<remote::Remote>,
+    /// Ask before installing each package
+    #[arg(long)]
+    confirm_each: bool,
+    /// Don't ask for confirmation before installing the backup
+    #[arg(short, long)]
+    yes: bool,
+    /// Only install this package from the backup (repeatable)
+    #[arg(long = "package", value_name = "PACKAGE", conflicts_with = "select")]
+    packages: Vec<String>,
+    /// Pick the packages to install from a list
+    #[arg(long)]
+    select: bool,
+    /// Only install the packages listed in a JSON report (`--json` output
+    /// of backup or install) or a backup's manifest.json
+    ///
+    /// Listed packages that aren't in the backup are reported and left out.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["bundle", "packages", "select"],
+          value_parser = read_report_list)]
+    from_report: Option<ReportList>,
+    /// With --from-report, only take the packages the report lists as
+    /// failed, to retry them
+    #[arg(long, requires = "from_report")]
+    failed_only: bool,
+    /// Skip packages that are already installed on the device, whatever
+    /// their version
+    #[arg(long, conflicts_with_all = ["bundle", "only_newer"])]
+    only_missing: bool,
+    /// Skip installed packages unless the backup has a newer version code
+    /// than the device
+    ///
+    /// Packages that aren't installed are installed as usual. The backup's
+    /// version code comes from its manifest, or from the base APK; a package
+    /// whose backed-up version can't be told is skipped.
+    #[arg(long, conflicts_with = "bundle")]
+    only_newer: bool,
+    /// Check APKs against the manifest checksums before installing anything
+    #[arg(long)]
+    verify: bool,
+    /// Print the adb commands that would be run, without installing
+    #[arg(long)]
+    dry_run: bool,
+    /// Extra flag for adb install, e.g. -r, -d or -g (repeatable)
+    ///
+    /// Incremental install is used on its own when adb and the device
+    /// support it; pass `--no-incremental` here to always transfer whole
+    /// APKs, or `--incremental` to require it.
+    #[arg(long = "install-flag", value_name = "FLAG", allow_hyphen_values = true)]
+    install_flags: Vec<String>,
+    /// Exit non-zero as soon as a package fails to install, instead of
+    /// continuing with the rest
+    #[arg(long)]
+    stop_on_error: bool,
+    /// Grant each package the runtime permissions recorded by
+    /// `backup --with-permissions` again after installing it
+    ///
+    /// Permissions the device won't let adb grant are skipped with a
+    /// warning.
+    #[arg(long)]
+    restore_permissions: bool,
+    /// Make the launcher recorded by `backup --capture-launcher` the
+    /// default again once the packages are installed
+    ///
+    /// Only the default is set, with `cmd package set-home-activity`; the
+    /// home screen layout isn't in the backup.
+    #[arg(long, conflicts_with = "bundle")]
+    restore_launcher: bool,
+    /// Install even if the backup came from a device with another ABI, or
+    /// has packages that need a newer Android version than this device runs
+    ///
+    /// Config splits for ABIs the device lacks are normally left out; with
+    /// --force a package with no matching ABI split gets all of them.
+    #[arg(long)]
+    force: bool,
+    /// Which adb command installs each package
+    ///
+    /// `auto` uses `install` for a single APK and `install-multiple` for
+    /// split APKs. `single` fails packages with more than one APK, since
+    /// splits can't be installed one by one.
+    #[arg(long, value_name = "MODE", default_value = "auto")]
+    install_mode: InstallMode,
+}
+
+impl InstallArgs {
+    /// [`BackupArgs::parse_from`](crate::BackupArgs::parse_from) for `install` options.
+    pub fn parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        parse_args("install", args)
+    }
+}
+
+pub(crate) fn run_install_mode(
+    adb: &dyn AdbRunner,
+    args: &InstallArgs,
+    config: &Config,
+) -> Result<()> {
+    let Some(report) = install(adb, args, config)? else {
+        return Ok(());
+    };
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else if report.stopped_after.is_none() {
+        report.print_summary();
+    }
+    report.check()
+}
+
+/// Installs a backup, or a bundle, as `install` does. Unlike the subcommand
+/// it never reads stdin: the backup must be named, `--select` and
+/// `--confirm-each` are refused, and the install isn't confirmed first.
+/// Returns `None` when nothing was installed on purpose, such as a dry run.
+/// Packages that failed are reported, not returned as an error.
+pub fn install(
+    adb: &dyn AdbRunner,
+    args: &InstallArgs,
+    config: &Config,
+) -> Result<Option<InstallReport>> {
+    if !config.interactive && (args.select || args.confirm_each) {
+        return Err("--select and --confirm-each ask on the terminal; name the packages with --package instead.".into());
+    }
+    connect_device(adb, &config.warnings)?;
+
+    if let Some(bundle) = &args.bundle {
+        if !bundle::is_bundle(bundle) {
+            return Err(format!(
+                "Not a bundle: {} (expected .{})",
+                bundle.display(),
+                bundle::BUNDLE_EXTENSIONS.join(", .")
+            )
+            .into());
+        }
+        let temp_dir = TempDir::new(&config.temp_dir, "apktool-bundle")?;
+        status!("Unpacking {:?}...", bundle.file_name().unwrap_or_default());
+        let package_dir = bundle::unpack(bundle, temp_dir.path())
+            .map_err(|e| format!("Failed to unpack {}: {}", bundle.display(), e))?;
+        return install_packages(
+            adb,
+            args,
+            &[package_dir],
+            &HashMap::new(),
+            &get_device_target(adb),
+            config,
+        );
+    }
+
+    let selected_backup = match (&args.from_remote, &args.backup) {
+        (Some(remote), _) => fetch_remote_backup(remote, &config.temp_dir)?,
+        (None, Some(name)) => open_backup(config, name)?,
+        (None, None) if !config.interactive => return Err("Name the backup to install.".into()),
+        (None, None) => match choose_backup(config, "install")? {
+            Some(selected_backup) => selected_backup,
+            None => return Ok(None),
+        },
+    };
+    let mut package_dirs = list_package_dirs(&selected_backup.path)?;
+
+    if !args.packages.is_empty() {
+        let missing: Vec<_> = args
+            .packages
+            .iter()
+            .filter(|package| !package_dirs.iter().any(|dir| dir.ends_with(package)))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("Not found in backup: {}", missing.join(", ")).into());
+        }
+        package_dirs.retain(|dir| args.packages.iter().any(|package| dir.ends_with(package)));
+    } else if let Some(report) = &args.from_report {
+        let wanted = report.selected(args.failed_only);
+        for package in wanted {
+            if !package_dirs.iter().any(|dir| dir.ends_with(package)) {
+                warning!(
+                    config.warnings,
+                    "Warning: {} (listed in {}) is not in the backup.",
+                    package,
+                    report.path.display()
+                );
+            }
+        }
+        package_dirs.retain(|dir| wanted.iter().any(|package| dir.ends_with(package)));
+        if package_dirs.is_empty() {
+            status!("Nothing to install.");
+            return Ok(None);
+        }
+    } else if args.select {
+        package_dirs = select_packages(package_dirs)?;
+    }
+
+    let manifest = Manifest::load(&selected_backup.path).ok().flatten();
+    if args.only_missing || args.only_newer {
+        package_dirs = skip_installed(adb, package_dirs, manifest.as_ref(), args.only_newer);
+        if package_dirs.is_empty() {
+            status!("Nothing to install.");
+            return Ok(None);
+        }
+    }
+
+    if args.verify {
+        verify_checksums(&selected_backup.path, &package_dirs)?;
+    }
+
+    let device = get_device_target(adb);
+    if let Some(manifest) = &manifest {
+        check_device_compatibility(manifest, &package_dirs, &device, args.force, config)?;
+    }
+    if !args.yes
+        && !args.dry_run
+        && !args.confirm_each
+        && config.interactive
+        && !confirm_backup_install(adb, manifest.as_ref(), &package_dirs, config)?
+    {
+        status!("Aborted.");
+        return Ok(None);
+    }
+
+    let entries: HashMap<String, PackageEntry> = manifest
+        .map(|manifest| {
+            manifest
+                .packages
+                .into_iter()
+                .map(|entry| (entry.name.clone(), entry))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let report = install_packages(adb, args, &package_dirs, &entries, &device, config)?;
+    if args.restore_launcher
+        && report
+            .as_ref()
+            .is_none_or(|report| report.stopped_after.is_none())
+    {
+        restore_launcher(adb, &selected_backup.path, args.dry_run, &config.warnings);
+    }
+    Ok(report)
+}
+
+/// Leaves out the package folders `--only-missing` or, with `only_newer`,
+/// `--only-newer` skips, saying why for each.
+fn skip_installed(
+    adb: &dyn AdbRunner,
+    package_dirs: Vec<PathBuf>,
+    manifest: Option<&Manifest>,
+    only_newer: bool,
+) -> Vec<PathBuf> {
+    let names: Vec<String> = package_dirs
+        .iter()
+        .map(|dir| dir.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    let installed = get_version_codes(adb, &names);
+
+    let mut kept = Vec::new();
+    for (dir, name) in package_dirs.into_iter().zip(names) {
+        let backup_version_code = || {
+            manifest
+                .and_then(|manifest| manifest.packages.iter().find(|entry| entry.name == name))
+                .and_then(|entry| entry.version_code)
+                .or_else(|| {
+                    let apks = order_split_apks(find_apk_files(&dir).ok()?);
+                    axml::read_apk_info(apks.first()?).ok()?.version_code
+                })
+        };
+        let reason = match installed.get(&name) {
+            None => None,
+            Some(_) if !only_newer => Some("already installed".to_string()),
+            Some(&device) => install_skip_reason(device, backup_version_code()),
+        };
+        match reason {
+            Some(reason) => status!("Skipping {}: {}", name, reason),
+            None => kept.push(dir),
+        }
+    }
+    kept
+}
+
+/// Why `--only-newer` leaves out an installed package, or `None` if the
+/// backup's version code is newer than the device's.
+fn install_skip_reason(device: u64, backup: Option<u64>) -> Option<String> {
+    match backup {
+        None => Some(format!(
+            "installed version {}, backed-up version unknown",
+            device
+        )),
+        Some(backup) if backup <= device => Some(format!(
+            "installed version {} is not older than the backup's {}",
+            device, backup
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Shows what is about to be installed, and where the backup came from if
+/// that isn't the connected device, then asks to go ahead.
+fn confirm_backup_install(
+    adb: &dyn AdbRunner,
+    manifest: Option<&Manifest>,
+    package_dirs: &[PathBuf],
+    config: &Config,
+) -> io::Result<bool> {
+    let apk_count: usize = package_dirs
+        .iter()
+        .map(|dir| find_apk_files(dir).map(|apks| apks.len()).unwrap_or(0))
+        .sum();
+    status!(
+        "About to install {} package(s) ({} APK file(s)).",
+        package_dirs.len(),
+        apk_count
+    );
+
+    if let Some(source) = manifest.map(|manifest| &manifest.device) {
+        let serial = device_serial(adb);
+        let model = get_device_output(adb, &["shell", "getprop", "ro.product.model"]);
+        let differs = |recorded: &Option<String>, current: &Option<String>| {
+            recorded.is_some() && current.is_some() && recorded != current
+        };
+        if differs(&source.serial, &serial) || differs(&source.model, &model) {
+            let describe = |model: &Option<String>, serial: &Option<String>| {
+                format!(
+                    "{} ({})",
+                    model.as_deref().unwrap_or("unknown model"),
+                    serial.as_deref().unwrap_or("unknown serial")
+                )
+            };
+            warning!(
+                config.warnings,
+                "Warning: The backup was taken on {}, but the connected device is {}.",
+                describe(&source.model, &source.serial),
+                describe(&model, &serial)
+            );
+        }
+    }
+    if let Some(tool) = manifest.and_then(|manifest| manifest.created_by.as_ref()) {
+        status!("Backup made by {}.", describe_created_by(tool));
+    }
+
+    prompt!("Install {} package(s)? [y/N]: ", package_dirs.len());
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Compares the device a backup was taken on against the current one.
+/// A different ABI, or packages whose minimum SDK is above the device's,
+/// stop the install unless `force` is set, in which case they are only
+/// warned about. A newer Android version on the source device alone is
+/// just a warning.
+fn check_device_compatibility(
+    manifest: &Manifest,
+    package_dirs: &[PathBuf],
+    device: &DeviceTarget,
+    force: bool,
+    config: &Config,
+) -> Result<()> {
+    let mut problems = Vec::new();
+    if let Some(abi) = &manifest.device.abi
+        && !device.abis.is_empty()
+        && !device.abis.contains(abi)
+    {
+        problems.push(format!(
+            "Backup is from a device with ABI {}, but this one supports {}.",
+            abi,
+            device.abis.join(", ")
+        ));
+    }
+    if let Some(sdk) = device.sdk {
+        if let Some(source_sdk) = manifest.device.sdk
+            && source_sdk > sdk
+        {
+            warning!(
+                config.warnings,
+                "Warning: Backup was taken on API {}, but this device runs API {}.",
+                source_sdk,
+                sdk
+            );
+        }
+        for dir in package_dirs {
+            let package = dir.file_name().unwrap().to_string_lossy();
+            let min_sdk = manifest
+                .packages
+                .iter()
+                .find(|entry| entry.name == package)
+                .and_then(|entry| entry.min_sdk);
+            if let Some(min_sdk) = min_sdk
+                && min_sdk > sdk
+            {
+                problems.push(format!(
+                    "{} needs API {}, but this device runs API {}.",
+                    package, min_sdk, sdk
+                ));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        if force {
+            warning!(config.warnings, "Warning: {}", problem);
+        } else {
+            failure!("✗ {}", problem);
+        }
+    }
+    if force {
+        Ok(())
+    } else {
+        Err("Backup doesn't match this device. Pass --force to install anyway.".into())
+    }
+}
+
+/// Every ABI a config split can target, as Android names them.
+pub(crate) const KNOWN_ABIS: [&str; 8] = [
+    "armeabi",
+    "armeabi-v7a",
+    "arm64-v8a",
+    "x86",
+    "x86_64",
+    "mips",
+    "mips64",
+    "riscv64",
+];
+
+pub(crate) const KNOWN_DENSITIES: [&str; 7] = [
+    "ldpi", "mdpi", "tvdpi", "hdpi", "xhdpi", "xxhdpi", "xxxhdpi",
+];
+
+/// The screen density a density config split is for, e.g. `xxhdpi` for
+/// `split_config.xxhdpi.apk`.
+fn split_density(apk: &Path) -> Option<&'static str> {
+    let name = apk.file_name()?.to_str()?;
+    let name = name.strip_prefix("split_")?.strip_suffix(".apk")?;
+    let suffix = name.rsplit(['.', '-']).next()?;
+    KNOWN_DENSITIES
+        .into_iter()
+        .find(|density| *density == suffix)
+}
+
+/// Which config splits `backup --keep-abi` and `--keep-density` keep. Empty
+/// lists keep everything.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SplitFilter<'a> {
+    pub(crate) abis: &'a [String],
+    pub(crate) densities: &'a [String],
+}
+
+impl SplitFilter<'_> {
+    /// Splits a package's device APK paths into those to back up and the
+    /// config splits left out. A kind of split is kept in full when none of
+    /// it matches, since the app couldn't be installed without one.
+    pub(crate) fn apply(&self, apk_paths: &[String]) -> (Vec<String>, Vec<String>) {
+        let mut kept = apk_paths.to_vec();
+        let mut dropped = Vec::new();
+        let kinds = [
+            (self.abis, split_abi as fn(&Path) -> _),
+            (self.densities, split_density),
+        ];
+        for (keep, qualifier) in kinds {
+            if keep.is_empty() {
+                continue;
+            }
+            let wanted = |path: &String| {
+                qualifier(Path::new(path)).map(|value| keep.iter().any(|keep| keep == value))
+            };
+            if !kept.iter().any(|path| wanted(path) == Some(true)) {
+                continue;
+            }
+            let (matching, left_out) = kept
+                .into_iter()
+                .partition(|path| wanted(path) != Some(false));
+            kept = matching;
+            dropped.extend(left_out);
+        }
+        (kept, dropped)
+    }
+}
+
+/// The ABI an ABI config split is for, e.g. `arm64-v8a` for
+/// `split_config.arm64_v8a.apk` or `split_base-arm64_v8a.apk`. Split names
+/// spell the ABI with underscores.
+fn split_abi(apk: &Path) -> Option<&'static str> {
+    let name = apk.file_name()?.to_str()?;
+    let name = name.strip_prefix("split_")?.strip_suffix(".apk")?;
+    let suffix = name.rsplit(['.', '-']).next()?;
+    KNOWN_ABIS
+        .into_iter()
+        .find(|abi| abi.replace('-', "_") == suffix)
+}
+
+/// Splits `apk_files` into those to install and the ABI config splits for
+/// ABIs not in `device_abis`. Nothing is dropped when the device's ABIs are
+/// unknown.
+fn drop_incompatible_splits(
+    apk_files: Vec<PathBuf>,
+    device_abis: &[String],
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    if device_abis.is_empty() {
+        return (apk_files, Vec::new());
+    }
+    apk_files.into_iter().partition(|apk| {
+        split_abi(apk).is_none_or(|abi| device_abis.iter().any(|device_abi| device_abi == abi))
+    })
+}
+
+/// Installs package folders in order, deferring those that need a shared
+/// library from later in the list. `entries` are the backup's manifest
+/// entries by package name, giving the installer to record, and `device`
+/// decides which ABI splits are kept.
+fn install_packages(
+    adb: &dyn AdbRunner,
+    args: &InstallArgs,
+    package_dirs: &[PathBuf],
+    entries: &HashMap<String, PackageEntry>,
+    device: &DeviceTarget,
+    config: &Config,
+) -> Result<Option<InstallReport>> {
+    let entry_of = |path: &Path| {
+        let package = path.file_name().unwrap().to_string_lossy();
+        entries.get(package.as_ref())
+    };
+    let installer_of = |path: &Path| entry_of(path).and_then(|entry| entry.installer.as_deref());
+    // An explicit choice in the flags wins over detection.
+    let incremental = !args
+        .install_flags
+        .iter()
+        .any(|flag| flag == "--incremental" || flag == "--no-incremental")
+        && supports_incremental_install(adb);
+    let options = InstallOptions {
+        flags: &args.install_flags,
+        incremental,
+        device_abis: &device.abis,
+        force: args.force,
+        restore_permissions: args.restore_permissions,
+        mode: args.install_mode,
+        warnings: &config.warnings,
+    };
+
+    if args.dry_run {
+        status!("Dry run: would run");
+        for path in package_dirs {
+            let apk_files = order_split_apks(find_apk_files(path)?);
+            if apk_files.is_empty() {
+                status!("  (skip {:?}: no APKs)", path.file_name().unwrap());
+                continue;
+            }
+            if let Some(error) = missing_apks(path, &apk_files, entry_of(path)) {
+                status!("  (skip {:?}: {})", path.file_name().unwrap(), error);
+                continue;
+            }
+            let (apk_files, _) = drop_incompatible_splits(apk_files, &device.abis);
+            if let Some(error) = args.install_mode.refuses(apk_files.len()) {
+                status!("  (skip {:?}: {})", path.file_name().unwrap(), error);
+                continue;
+            }
+            let mut flags = args.install_flags.clone();
+            if incremental {
+                flags.insert(0, "--incremental".to_string());
+            }
+            let command: Vec<_> = install_args(
+                &apk_files,
+                args.install_mode,
+                adb.user(),
+                installer_of(path),
+                &flags,
+            )
+            .iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+            status!("  adb {}", command.join(" "));
+        }
+        return Ok(None);
+    }
+
+    let installed_versions = if args.confirm_each {
+        let names: Vec<String> = package_dirs
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        get_version_codes(adb, &names)
+    } else {
+        HashMap::new()
+    };
+    // Folders installed, skipped or failed, which the deferred pass below
+    // leaves alone.
+    let mut settled = HashSet::new();
+    let mut deferred: Vec<(&PathBuf, Option<String>)> = Vec::new();
+    let mut report = InstallReport::default();
+    events::emit(Event::InstallStarted {
+        packages: package_dirs.len(),
+    });
+    for (index, path) in package_dirs.iter().enumerate() {
+        events::emit(Event::PackageStarted {
+            package: &path.file_name().unwrap().to_string_lossy(),
+            index: index + 1,
+            total: package_dirs.len(),
+        });
+        if args.confirm_each {
+            let package = path.file_name().unwrap().to_string_lossy();
+            let installed_version = installed_versions.get(package.as_ref()).copied();
+            match confirm_install(path, entry_of(path), installed_version, args.install_mode)? {
+                Confirmation::Install => {}
+                Confirmation::Skip => {
+                    settled.insert(path.clone());
+                    report.record(path, InstallStatus::Skipped, None);
+                    continue;
+                }
+                // Quitting also drops the retry of deferred packages below.
+                Confirmation::Quit => {
+                    for (path, _) in deferred {
+                        report.record(path, InstallStatus::Skipped, None);
+                    }
+                    report.emit_completed();
+                    return Ok(Some(report));
+                }
+            }
+        }
+
+        if entry_of(path).is_some_and(|entry| entry.system) {
+            warning!(
+                config.warnings,
+                "Warning: {:?} is a system app; it can only be installed as an update to the copy already on the device.",
+                path.file_name().unwrap()
+            );
+        }
+        match install_package(adb, path, entry_of(path), &options)? {
+            InstallOutcome::Installed { incremental } => {
+                settled.insert(path.clone());
+                report.record_installed(path, incremental);
+            }
+            InstallOutcome::MissingSharedLibrary(library) => {
+                events::emit(Event::PackageDeferred {
+                    package: &path.file_name().unwrap().to_string_lossy(),
+                    library: library.as_deref(),
+                });
+                status!(
+                    "Deferring {:?}: requires shared library {}",
+                    path.file_name().unwrap(),
+                    library.as_deref().unwrap_or("(unknown)")
+                );
+                deferred.push((path, library));
+            }
+            InstallOutcome::Failed(error) => {
+                settled.insert(path.clone());
+                report.record(path, InstallStatus::Failed, Some(error));
+                if args.stop_on_error {
+                    return Ok(Some(stop_install(report, path)));
+                }
+            }
+        }
+    }
+
+    // Packages that failed on a missing shared library are retried once the
+    // rest of the backup is in, after first trying any package in the backup
+    // that looks like the library's provider. Only other deferred packages
+    // can be providers: the rest were installed, skipped or failed already.
+    for (index, (path, library)) in deferred.iter().enumerate() {
+        if settled.contains(*path) {
+            continue;
+        }
+        if let Some(library) = library {
+            for provider in package_dirs {
+                let provider_name = provider.file_name().unwrap().to_string_lossy();
+                if provider == *path
+                    || settled.contains(provider)
+                    || !provider_name.starts_with(library.as_str())
+                {
+                    continue;
+                }
+                if args.confirm_each {
+                    status!("{} looks like the provider of {}.", provider_name, library);
+                    let installed_version = installed_versions.get(provider_name.as_ref()).copied();
+                    match confirm_install(
+                        provider,
+                        entry_of(provider),
+                        installed_version,
+                        args.install_mode,
+                    )? {
+                        Confirmation::Install => {}
+                        Confirmation::Skip => {
+                            settled.insert(provider.clone());
+                            report.record(provider, InstallStatus::Skipped, None);
+                            continue;
+                        }
+                        Confirmation::Quit => {
+                            for (path, _) in &deferred[index..] {
+                                if !settled.contains(*path) {
+                                    report.record(path, InstallStatus::Skipped, None);
+                                }
+                            }
+                            report.emit_completed();
+                            return Ok(Some(report));
+                        }
+                    }
+                }
+                status!(
+                    "Installing likely provider of {}: {}",
+                    library,
+                    provider_name
+                );
+                if let InstallOutcome::Installed { incremental } =
+                    install_package(adb, provider, entry_of(provider), &options)?
+                {
+                    settled.insert(provider.clone());
+                    report.record_installed(provider, incremental);
+                }
+            }
+        }
+        if settled.contains(*path) {
+            continue;
+        }
+
+        status!("Retrying {:?}", path.file_name().unwrap());
+        match install_package(adb, path, entry_of(path), &options)? {
+            InstallOutcome::Installed { incremental } => {
+                settled.insert((*path).clone());
+                report.record_installed(path, incremental);
+            }
+            InstallOutcome::MissingSharedLibrary(library) => {
+                let library = library.as_deref().unwrap_or("(unknown)");
+                failure!(
+                    "✗ Failed to install from {:?}: missing shared library {}",
+                    path.file_name().unwrap(),
+                    library
+                );
+                report.record(
+                    path,
+                    InstallStatus::Failed,
+                    Some(format!("missing shared library {}", library)),
+                );
+                if args.stop_on_error {
+                    return Ok(Some(stop_install(report, path)));
+                }
+            }
+            InstallOutcome::Failed(error) => {
+                report.record(path, InstallStatus::Failed, Some(error));
+                if args.stop_on_error {
+                    return Ok(Some(stop_install(report, path)));
+                }
+            }
+        }
+    }
+
+    report.emit_completed();
+    Ok(Some(report))
+}
+
+/// Ends an install run for `--stop-on-error` with the report so far.
+fn stop_install(mut report: InstallReport, path: &Path) -> InstallReport {
+    report.emit_completed();
+    report.stopped_after = Some(path.file_name().unwrap().to_string_lossy().to_string());
+    report
+}
+
+/// Per-package install results, printed under `--json` and returned by
+/// [`install`].
+#[derive(Serialize, Default)]
+pub struct InstallReport {
+    pub packages: Vec<InstalledPackage>,
+    /// The package whose failure stopped the run under `--stop-on-error`.
+    #[serde(skip)]
+    pub stopped_after: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub status: InstallStatus,
+    /// APK files passed to adb, in install order.
+    pub files: Vec<PathBuf>,
+    /// Whether the APKs were streamed with `adb install --incremental`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub incremental: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallStatus {
+    Installed,
+    Failed,
+    Skipped,
+}
+
+impl InstallReport {
+    /// Records the outcome for a package folder, replacing an earlier one
+    /// (e.g. when a deferred package is retried).
+    fn record(&mut self, path: &Path, status: InstallStatus, error: Option<String>) {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        events::emit(match status {
+            InstallStatus::Installed => Event::PackageCompleted {
+                package: &name,
+                bytes: None,
+            },
+            InstallStatus::Failed => Event::PackageFailed {
+                package: &name,
+                error: error.as_deref().unwrap_or_default(),
+            },
+            InstallStatus::Skipped => Event::PackageSkipped {
+                package: &name,
+                reason: None,
+            },
+        });
+        self.packages.retain(|package| package.name != name);
+        self.packages.push(InstalledPackage {
+            name,
+            status,
+            files: find_apk_files(path)
+                .map(order_split_apks)
+                .unwrap_or_default(),
+            incremental: false,
+            error,
+        });
+    }
+
+    fn record_installed(&mut self, path: &Path, incremental: bool) {
+        self.record(path, InstallStatus::Installed, None);
+        if let Some(package) = self.packages.last_mut() {
+            package.incremental = incremental;
+        }
+    }
+
+    /// How many packages ended up with `status`.
+    pub fn count(&self, status: InstallStatus) -> usize {
+        self.packages
+            .iter()
+            .filter(|package| package.status == status)
+            .count()
+    }
+
+    /// The error the `install` command exits with for this report, if any
+    /// package failed.
+    fn check(&self) -> Result<()> {
+        if let Some(package) = &self.stopped_after {
+            return Err(AppError::PackagesFailed(format!(
+                "Stopped after {} failed to install (--stop-on-error).",
+                package
+            )));
+        }
+        let failed = self.count(InstallStatus::Failed);
+        if failed > 0 {
+            return Err(AppError::PackagesFailed(format!(
+                "{} package(s) failed to install",
+                failed
+            )));
+        }
+        Ok(())
+    }
+
+    fn emit_completed(&self) {
+        events::emit(Event::InstallCompleted {
+            installed: self.count(InstallStatus::Installed),
+            failed: self.count(InstallStatus::Failed),
+            skipped: self.count(InstallStatus::Skipped),
+        });
+    }
+
+    fn print_summary(&self) {
+        let failed: Vec<_> = self
+            .packages
+            .iter()
+            .filter(|package| package.status == InstallStatus::Failed)
+            .map(|package| package.name.as_str())
+            .collect();
+        summary!(
+            "Installed {} of {} packages.",
+            self.count(InstallStatus::Installed),
+            self.packages.len() - self.count(InstallStatus::Skipped)
+        );
+        if !failed.is_empty() {
+            summary!("Failed: {}", failed.join(", "));
+        }
+    }
+}
+
+/// Re-hashes the APKs of the given package folders and fails on the first
+/// file that doesn't match the checksum recorded in the backup manifest.
+fn verify_checksums(backup_dir: &Path, package_dirs: &[PathBuf]) -> Result<()> {
+    let manifest =
+        Manifest::load(backup_dir)?.ok_or("Backup has no manifest.json to verify against.")?;
+
+    for dir in package_dirs {
+        let package = dir.file_name().unwrap().to_string_lossy();
+        let entry = manifest
+            .packages
+            .iter()
+            .find(|entry| entry.name == package)
+            .ok_or_else(|| format!("{} is not recorded in the manifest.", package))?;
+
+        for file in &entry.files {
+            let Some(expected) = &file.sha256 else {
+                return Err(format!("No checksum recorded for {}/{}.", package, file.name).into());
+            };
+            let actual = sha256_file(&dir.join(&file.name))
+                .map_err(|e| format!("Failed to read {}/{}: {}", package, file.name, e))?;
+            if &actual != expected {
+                return Err(format!("Checksum mismatch for {}/{}.", package, file.name).into());
+            }
+        }
+    }
+
+    status!("✓ Verified checksums of {} package(s)", package_dirs.len());
+    Ok(())
+}
+
+/// Lets the user pick a subset of package folders by number.
+fn select_packages(package_dirs: Vec<PathBuf>) -> io::Result<Vec<PathBuf>> {
+    let names: Vec<_> = package_dirs
+        .iter()
+        .map(|dir| dir.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    let Some(indices) = select_many_from(&names, "Select packages to install:")? else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+
+    Ok(package_dirs
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| indices.contains(i))
+        .map(|(_, dir)| dir)
+        .collect())
+}
+
+enum Confirmation {
+    Install,
+    Skip,
+    Quit,
+}
+
+/// Shows the planned action for a package folder, with the backed-up version
+/// and whether it installs, updates or downgrades the app, and asks whether
+/// to go ahead.
+fn confirm_install(
+    path: &Path,
+    entry: Option<&PackageEntry>,
+    installed_version: Option<u64>,
+    mode: InstallMode,
+) -> Result<Confirmation> {
+    let apk_count = find_apk_files(path)?.len();
+    let version = match (
+        entry.and_then(|entry| entry.version_name.as_deref()),
+        entry.and_then(|entry| entry.version_code),
+    ) {
+        (Some(name), Some(code)) => format!("{} ({})", name, code),
+        (None, Some(code)) => code.to_string(),
+        (Some(name), None) => name.to_string(),
+        (None, None) => "unknown version".to_string(),
+    };
+    let change = install_change(
+        installed_version,
+        entry.and_then(|entry| entry.version_code),
+    );
+    let action = match apk_count {
+        0 => "nothing to install (no APKs)".to_string(),
+        1 if !mode.uses_install_multiple(1) => "adb install (1 APK)".to_string(),
+        n if mode.uses_install_multiple(n) => format!("adb install-multiple ({} APKs)", n),
+        n => format!(
+            "nothing to install ({} split APKs need install-multiple)",
+            n
+        ),
+    };
+    prompt!(
+        "{} {}, {} -> {}\n",
+        path.file_name().unwrap().to_string_lossy(),
+        version,
+        change,
+        action
+    );
+
+    loop {
+        prompt!("Install? [y]es / [n]o / [s]kip / [q]uit: ");
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(Confirmation::Quit);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Confirmation::Install),
+            "n" | "no" | "s" | "skip" => return Ok(Confirmation::Skip),
+            "q" | "quit" => return Ok(Confirmation::Quit),
+            _ => eprintln!("Please answer y, n, s or q."),
+        }
+    }
+}
+
+/// Describes what installing version `backup` does to the installed
+/// `device` version, if any.
+fn install_change(device: Option<u64>, backup: Option<u64>) -> String {
+    match (device, backup) {
+        (None, _) => "new install".to_string(),
+        (Some(device), Some(backup)) if backup > device => format!("update from {}", device),
+        (Some(device), Some(backup)) if backup < device => {
+            format!("downgrade from {}", device)
+        }
+        (Some(_), Some(_)) => "reinstall of the same version".to_string(),
+        (Some(device), None) => format!("replaces installed version {}", device),
+    }
+}
+
+pub(crate) fn find_apk_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(path)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.extension().map(|ext| ext == "apk").unwrap_or(false))
+        .collect())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InstallMode {
+    /// `install` for one APK, `install-multiple` for more
+    Auto,
+    /// Always `install`
+    Single,
+    /// Always `install-multiple`, even for one APK
+    Multiple,
+}
+
+impl InstallMode {
+    fn uses_install_multiple(self, apk_count: usize) -> bool {
+        match self {
+            InstallMode::Auto => apk_count > 1,
+            InstallMode::Single => false,
+            InstallMode::Multiple => true,
+        }
+    }
+
+    /// Why this mode can't install `apk_count` APKs at once, if it can't.
+    fn refuses(self, apk_count: usize) -> Option<String> {
+        (matches!(self, InstallMode::Single) && apk_count > 1).then(|| {
+            format!(
+                "--install-mode single can't install {} split APKs one by one",
+                apk_count
+            )
+        })
+    }
+}
+
+/// Builds the `adb install` or `install-multiple` arguments for a package's
+/// APKs, already in [`order_split_apks`] order. `flags` from
+/// `--install-flag` go right after the subcommand.
+fn install_args(
+    apk_files: &[PathBuf],
+    mode: InstallMode,
+    user: Option<u32>,
+    installer: Option<&str>,
+    flags: &[String],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = if mode.uses_install_multiple(apk_files.len()) {
+        vec!["install-multiple".into()]
+    } else {
+        vec!["install".into()]
+    };
+    args.extend(flags.iter().map(OsString::from));
+    if let Some(user) = user {
+        args.extend(["--user".into(), user.to_string().into()]);
+    }
+    if let Some(installer) = installer {
+        args.extend(["-i".into(), installer.into()]);
+    }
+    args.extend(apk_files.iter().map(|apk| apk.as_os_str().to_owned()));
+    args
+}
+
+/// Puts the base APK first, followed by the config splits sorted by name,
+/// as `install-multiple` expects. The base is `base.apk` if present,
+/// otherwise the only file without a `split_` prefix, otherwise the largest.
+pub(crate) fn order_split_apks(mut apk_files: Vec<PathBuf>) -> Vec<PathBuf> {
+    apk_files.sort();
+    if apk_files.len() < 2 {
+        return apk_files;
+    }
+
+    let file_name = |apk: &PathBuf| apk.file_name().unwrap().to_string_lossy().to_string();
+    let non_split: Vec<usize> = (0..apk_files.len())
+        .filter(|&i| !file_name(&apk_files[i]).starts_with("split_"))
+        .collect();
+
+    let base = apk_files
+        .iter()
+        .position(|apk| file_name(apk) == "base.apk")
+        .or(match non_split.as_slice() {
+            [only] => Some(*only),
+            _ => None,
+        })
+        .or_else(|| {
+            (0..apk_files.len()).max_by_key(|&i| {
+                fs::metadata(&apk_files[i])
+                    .map(|meta| meta.len())
+                    .unwrap_or(0)
+            })
+        });
+
+    if let Some(base) = base {
+        let base = apk_files.remove(base);
+        apk_files.insert(0, base);
+    }
+    apk_files
+}
+
+/// Settings shared by every [`install_package`] call in a run.
+struct InstallOptions<'a> {
+    /// Extra `--install-flag`s.
+    flags: &'a [String],
+    /// Try `adb install --incremental` first, falling back to a full
+    /// transfer if it fails.
+    incremental: bool,
+    /// ABIs the device supports, for dropping config splits for others.
+    /// Empty if unknown.
+    device_abis: &'a [String],
+    /// Install a package's ABI splits even when none matches the device.
+    force: bool,
+    /// Grant the permissions recorded in the package's manifest entry.
+    restore_permissions: bool,
+    mode: InstallMode,
+    warnings: &'a Warnings,
+}
+
+enum InstallOutcome {
+    Installed {
+        incremental: bool,
+    },
+    /// Holds the error text for the `--json` report.
+    Failed(String),
+    /// The install failed with `INSTALL_FAILED_MISSING_SHARED_LIBRARY`. Holds
+    /// the library name when adb reported it.
+    MissingSharedLibrary(Option<String>),
+}
+
+/// Why a package folder can't be installed as a whole, if it can't: an APK
+/// its manifest entry lists is missing, or without an entry, `apk_files`
+/// (in [`order_split_apks`] order) has no base APK. `install-multiple`
+/// would fail on either without saying which file is missing.
+pub(crate) fn missing_apks(
+    path: &Path,
+    apk_files: &[PathBuf],
+    entry: Option<&PackageEntry>,
+) -> Option<String> {
+    match entry {
+        Some(entry) => {
+            let missing: Vec<_> = entry
+                .files
+                .iter()
+                .filter(|file| !path.join(&file.name).is_file())
+                .map(|file| file.name.as_str())
+                .collect();
+            (!missing.is_empty()).then(|| format!("missing split {}", missing.join(", ")))
+        }
+        None => apk_files
+            .first()
+            .and_then(|apk| apk.file_name())
+            .is_some_and(|name| name.to_string_lossy().starts_with("split_"))
+            .then(|| "no base APK found".to_string()),
+    }
+}
+
+/// Installs the APKs in a backup package folder, printing the result unless
+/// the failure is a missing shared library, which the caller handles.
+/// `entry` is the package's manifest entry, if the backup has one: every APK
+/// it lists must be present, and its installer is passed as
+/// `-i <installer>` so the app keeps its original install source.
+fn install_package(
+    adb: &dyn AdbRunner,
+    path: &Path,
+    entry: Option<&PackageEntry>,
+    options: &InstallOptions,
+) -> Result<InstallOutcome> {
+    let flags = options.flags;
+    let installer = entry.and_then(|entry| entry.installer.as_deref());
+    let apk_files = order_split_apks(find_apk_files(path)?);
+
+    if apk_files.is_empty() {
+        warning!(options.warnings, "No APKs found in {:?}", path);
+        return Ok(InstallOutcome::Failed("no APKs found".to_string()));
+    }
+
+    if let Some(error) = missing_apks(path, &apk_files, entry) {
+        failure!(
+            "✗ Failed to install from {:?}: {}",
+            path.file_name().unwrap(),
+            error
+        );
+        return Ok(InstallOutcome::Failed(error));
+    }
+    if let Some(entry) = entry.filter(|entry| !entry.dropped_splits.is_empty()) {
+        status!(
+            "  Backed up without {}; the device must match the kept splits",
+            entry.dropped_splits.join(", ")
+        );
+    }
+
+    let had_abi_splits = apk_files.iter().any(|apk| split_abi(apk).is_some());
+    let (kept, dropped) = drop_incompatible_splits(apk_files.clone(), options.device_abis);
+    let apk_files = if had_abi_splits && !kept.iter().any(|apk| split_abi(apk).is_some()) {
+        // Nothing left for this device's ABIs; --force hands them all to
+        // adb as they are.
+        if !options.force {
+            let error = format!(
+                "no ABI split matches this device ({})",
+                options.device_abis.join(", ")
+            );
+            failure!(
+                "✗ Failed to install from {:?}: {}",
+                path.file_name().unwrap(),
+                error
+            );
+            return Ok(InstallOutcome::Failed(error));
+        }
+        apk_files
+    } else {
+        if !dropped.is_empty() {
+            let names: Vec<_> = dropped
+                .iter()
+                .map(|apk| apk.file_name().unwrap().to_string_lossy())
+                .collect();
+            status!("  Skipping splits for other ABIs: {}", names.join(", "));
+        }
+        kept
+    };
+
+    if let Some(error) = options.mode.refuses(apk_files.len()) {
+        failure!(
+            "✗ Failed to install from {:?}: {}",
+            path.file_name().unwrap(),
+            error
+        );
+        return Ok(InstallOutcome::Failed(error));
+    }
+
+    let required = if flags.iter().any(|flag| flag == "--incremental") {
+        Some((MIN_INCREMENTAL_ADB_VERSION, "Incremental install"))
+    } else if options.mode.uses_install_multiple(apk_files.len()) {
+        Some((MIN_INSTALL_MULTIPLE_ADB_VERSION, "Installing split APKs"))
+    } else {
+        None
+    };
+    if let Some((minimum, feature)) = required
+        && let Err(e) = require_adb_version(adb, minimum, feature)
+    {
+        failure!(
+            "✗ Failed to install from {:?}: {}",
+            path.file_name().unwrap(),
+            e
+        );
+        return Ok(InstallOutcome::Failed(e));
+    }
+
+    let installed = |incremental: bool| {
+        status!(
+            "✓ Installed package from {:?}{}",
+            path.file_name().unwrap(),
+            if incremental { " (incremental)" } else { "" }
+        );
+        let package_name = path.file_name().unwrap().to_string_lossy().to_string();
+        restore_shared_dir(adb, &EXTERNAL_DATA, &package_name, path);
+        restore_shared_dir(adb, &OBB_FILES, &package_name, path);
+        restore_app_data(adb, &package_name, path);
+        if options.restore_permissions
+            && let Some(entry) = entry
+        {
+            restore_permissions(adb, &package_name, &entry.permissions, options.warnings);
+        }
+        InstallOutcome::Installed { incremental }
+    };
+
+    if options.incremental {
+        let mut incremental_flags = vec!["--incremental".to_string()];
+        incremental_flags.extend_from_slice(flags);
+        match adb.run(&install_args(
+            &apk_files,
+            options.mode,
+            adb.user(),
+            installer,
+            &incremental_flags,
+        )) {
+            Ok(output) if output.status.success() => return Ok(installed(true)),
+            _ => status!(
+                "  Incremental install of {:?} failed, transferring the full APKs",
+                path.file_name().unwrap()
+            ),
+        }
+    }
+
+    let output = adb.run(&install_args(
+        &apk_files,
+        options.mode,
+        adb.user(),
+        installer,
+        flags,
+    ));
+
+    match output {
+        Ok(output) if output.status.success() => Ok(installed(false)),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if let Some(library) = find_missing_shared_library(&stdout, &stderr) {
+                return Ok(InstallOutcome::MissingSharedLibrary(library));
+            }
+            failure!(
+                "✗ Failed to install from {:?}\nstdout: {}\nstderr: {}",
+                path.file_name().unwrap(),
+                stdout.trim(),
+                stderr.trim()
+            );
+            let error = if stderr.trim().is_empty() {
+                stdout.trim()
+            } else {
+                stderr.trim()
+            };
+            Ok(InstallOutcome::Failed(error.to_string()))
+        }
+        Err(e) => {
+            failure!("✗ Failed to execute adb install: {}", e);
+            Ok(InstallOutcome::Failed(e.to_string()))
+        }
+    }
+}
+
+/// Detects `INSTALL_FAILED_MISSING_SHARED_LIBRARY` in adb output and pulls
+/// out the library name from messages like
+/// "requires unavailable shared library com.example.lib; failing!".
+fn find_missing_shared_library(stdout: &str, stderr: &str) -> Option<Option<String>> {
+    let text = format!("{}\n{}", stdout, stderr);
+    if !text.contains("INSTALL_FAILED_MISSING_SHARED_LIBRARY") {
+        return None;
+    }
+
+    let library = text.split("shared library ").nth(1).and_then(|rest| {
+        rest.split(|c: char| c.is_whitespace() || c == ';')
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+    });
+    Some(library)
+}
+
+/// Grants `permissions` to a freshly installed package with `pm grant`,
+/// warning about any the device refuses, e.g. ones that aren't runtime
+/// permissions on this Android version.
+fn restore_permissions(
+    adb: &dyn AdbRunner,
+    package: &str,
+    permissions: &[String],
+    warnings: &Warnings,
+) {
+    if permissions.is_empty() {
+        return;
+    }
+
+    let mut refused = Vec::new();
+    for permission in permissions {
+        let args = pm_args(adb, &["grant"], &[package, permission]);
+        match adb.run(&args) {
+            Ok(output) if output.status.success() => {}
+            _ => refused.push(permission.as_str()),
+        }
+    }
+    status!(
+        "  Granted {} of {} permission(s)",
+        permissions.len() - refused.len(),
+        permissions.len()
+    );
+    if !refused.is_empty() {
+        warning!(
+            warnings,
+            "Warning: Could not grant {} to {}",
+            refused.join(", "),
+            package
+        );
+    }
+}
+
+/// The launcher component `backup --capture-launcher` recorded in
+/// `device_info/launcher.txt`, if any.
+fn saved_launcher(backup: &Path) -> Option<String> {
+    let contents = fs::read_to_string(backup.join(DEVICE_INFO_DIR).join("launcher.txt")).ok()?;
+    contents
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("component="))
+        .filter(|component| component.contains('/'))
+        .map(str::to_string)
+}
+
+/// For `--restore-launcher`: sets the backup's recorded launcher as the
+/// default home activity, or with `dry_run` prints the command. A backup
+/// without one, or a device that refuses, gets a warning.
+fn restore_launcher(adb: &dyn AdbRunner, backup: &Path, dry_run: bool, warnings: &Warnings) {
+    let Some(component) = saved_launcher(backup) else {
+        warning!(
+            warnings,
+            "Warning: The backup has no launcher recorded; back up with --capture-launcher to keep it."
+        );
+        return;
+    };
+    let args = pm_args(adb, &["set-home-activity"], &[&component]);
+    if dry_run {
+        status!("  adb {}", args.join(" "));
+        return;
+    }
+    match adb.run(&args) {
+        Ok(output) if output.status.success() => {
+            status!("Restored default launcher: {}", component)
+        }
+        Ok(output) => warning!(
+            warnings,
+            "Warning: Could not set {} as the launcher: {}",
+            component,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warning!(
+            warnings,
+            "Warning: Could not set {} as the launcher: {}",
+            component,
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeAdb;
+
+    #[test]
+    fn split_filter_keeps_matching_config_splits() {
+        let paths: Vec<String> = [
+            "/data/app/x/base.apk",
+            "/data/app/x/split_config.arm64_v8a.apk",
+            "/data/app/x/split_config.armeabi_v7a.apk",
+            "/data/app/x/split_config.xxhdpi.apk",
+            "/data/app/x/split_config.mdpi.apk",
+            "/data/app/x/split_feature.apk",
+        ]
+        .map(String::from)
+        .to_vec();
+        let abis = ["arm64-v8a".to_string()];
+        let densities = ["xxhdpi".to_string()];
+        let filter = SplitFilter {
+            abis: &abis,
+            densities: &densities,
+        };
+        let (kept, dropped) = filter.apply(&paths);
+        assert_eq!(
+            kept,
+            [&paths[0], &paths[1], &paths[3], &paths[5]].map(String::clone)
+        );
+        assert_eq!(dropped, [&paths[2], &paths[4]].map(String::clone));
+
+        // No split for a kept density: all density splits stay.
+        let densities = ["ldpi".to_string()];
+        let filter = SplitFilter {
+            abis: &[],
+            densities: &densities,
+        };
+        let (kept, dropped) = filter.apply(&paths);
+        assert_eq!(kept, paths);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn only_newer_installs_only_newer_backups() {
+        assert_eq!(install_skip_reason(10, Some(11)), None);
+        assert!(install_skip_reason(10, Some(10)).is_some());
+        assert!(install_skip_reason(10, Some(9)).is_some());
+        assert!(install_skip_reason(10, None).is_some());
+    }
+
+    #[test]
+    fn confirm_each_names_the_kind_of_install() {
+        assert_eq!(install_change(None, Some(3)), "new install");
+        assert_eq!(install_change(Some(2), Some(3)), "update from 2");
+        assert_eq!(install_change(Some(4), Some(3)), "downgrade from 4");
+        assert_eq!(
+            install_change(Some(3), Some(3)),
+            "reinstall of the same version"
+        );
+    }
+
+    #[test]
+    fn restore_launcher_sets_the_captured_component() {
+        let backup = TempDir::new(&std::env::temp_dir(), "apktool-test-launcher").unwrap();
+        let warnings = Warnings::default();
+        let adb = FakeAdb::default().with(
+            "shell pm set-home-activity com.example.home/.Launcher",
+            "Success\n",
+        );
+        restore_launcher(&adb, backup.path(), false, &warnings);
+        assert_eq!(warnings.count(), 1, "no launcher.txt should warn");
+
+        fs::create_dir(backup.path().join(DEVICE_INFO_DIR)).unwrap();
+        fs::write(
+            backup.path().join(DEVICE_INFO_DIR).join("launcher.txt"),
+            "# Best-effort capture.\ncomponent=com.example.home/.Launcher\npackage=com.example.home\nenabled=true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            saved_launcher(backup.path()).as_deref(),
+            Some("com.example.home/.Launcher")
+        );
+        restore_launcher(&adb, backup.path(), false, &warnings);
+        assert_eq!(warnings.count(), 1);
+        restore_launcher(&FakeAdb::default(), backup.path(), false, &warnings);
+        assert_eq!(warnings.count(), 2, "a refused launcher should warn");
+    }
+}
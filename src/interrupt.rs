@@ -0,0 +1,75 @@
+//! Ctrl-C handling for backups, so an interrupted run still leaves a
+//! manifest behind for `backup --resume`.
+//!
+//! While [`catch`] is in effect the first Ctrl-C only sets a flag that the
+//! backup polls between packages; adb is started in its own process group so
+//! the terminal's SIGINT doesn't kill the pulls in flight. A second Ctrl-C
+//! exits at once. Elsewhere, and on platforms without signals, Ctrl-C keeps
+//! its default behaviour.
+
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Exit status for a second Ctrl-C, as shells report a process killed by
+/// SIGINT.
+const FORCED_EXIT_STATUS: i32 = 130;
+
+static CATCHING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Catches Ctrl-C until the returned guard is dropped.
+pub fn catch() -> CatchGuard {
+    INTERRUPTS.store(0, Ordering::Relaxed);
+    CATCHING.store(true, Ordering::Relaxed);
+    #[cfg(unix)]
+    // SAFETY: the handler only touches atomics and calls async-signal-safe
+    // functions.
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+    }
+    CatchGuard(())
+}
+
+/// Whether Ctrl-C was pressed since [`catch`].
+pub fn requested() -> bool {
+    INTERRUPTS.load(Ordering::Relaxed) > 0
+}
+
+/// Keeps a command about to be spawned out of the terminal's SIGINT while
+/// Ctrl-C is caught, so it runs to completion.
+pub fn shield(command: &mut Command) {
+    #[cfg(unix)]
+    if CATCHING.load(Ordering::Relaxed) {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    #[cfg(not(unix))]
+    let _ = command;
+}
+
+pub struct CatchGuard(());
+
+impl Drop for CatchGuard {
+    fn drop(&mut self) {
+        CATCHING.store(false, Ordering::Relaxed);
+        #[cfg(unix)]
+        // SAFETY: restores the default disposition.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_: libc::c_int) {
+    const MESSAGE: &[u8] =
+        b"\nStopping after the packages in progress. Press Ctrl-C again to quit now.\n";
+    if INTERRUPTS.fetch_add(1, Ordering::Relaxed) > 0 {
+        // SAFETY: _exit is async-signal-safe.
+        unsafe { libc::_exit(FORCED_EXIT_STATUS) };
+    }
+    // SAFETY: write is async-signal-safe, and MESSAGE outlives the call.
+    unsafe {
+        libc::write(libc::STDERR_FILENO, MESSAGE.as_ptr().cast(), MESSAGE.len());
+    }
+}
@@ -0,0 +1,3185 @@
+//! Back up and restore third-party APKs over adb.
+//!
+//! The `apktool` binary is a thin wrapper around [`cli_main`]. Programs
+//! embedding the tool call [`backup`](fn@backup) and
+//! [`install`](fn@install) instead, which run the same steps as the
+//! subcommands but hand back a [`BackupReport`] or [`InstallReport`] rather
+//! than printing a JSON summary or failing when a package failed. Progress
+//! is still printed as the subcommands print it; [`output::init`] can make
+//! that quiet, but nothing is ever read from stdin. The device, and the user
+//! on it, are those of the [`AdbRunner`], normally a [`SystemAdb`].
+//!
+//! ```no_run
+//! use apktool::{BackupArgs, ConfigFile, SystemAdb};
+//!
+//! let adb = SystemAdb::new().with_serial("emulator-5554");
+//! let args = BackupArgs::parse_from(["--new", "--with-permissions"]).unwrap();
+//! let config = apktool::resolve_config(ConfigFile::load()?, Some(&args))?;
+//! if let Some(report) = apktool::backup(&adb, &args, &config)? {
+//!     for package in &report.packages {
+//!         println!("{}: {:?}", package.name, package.status);
+//!     }
+//! }
+//! # Ok::<(), apktool::error::AppError>(())
+//! ```
+
+/// Like `println!`, but goes to stderr under `--json` so stdout only carries
+/// the JSON document. Silent under `--quiet`.
+macro_rules! status {
+    ($($arg:tt)*) => {{
+        if !$crate::output::quiet() {
+            summary!($($arg)*);
+        }
+    }};
+}
+
+/// [`status!`] for final summaries, which `--quiet` still prints.
+macro_rules! summary {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        if $crate::output::json() {
+            eprintln!("{}", $crate::output::paint(&line, true));
+        } else {
+            println!("{}", $crate::output::paint(&line, false));
+        }
+    }};
+}
+
+/// Prints a failure to stderr, in red when color is on.
+macro_rules! failure {
+    ($($arg:tt)*) => {{
+        eprintln!("{}", $crate::output::paint(&format!($($arg)*), true));
+    }};
+}
+
+/// `print!` counterpart of [`status!`] for prompts and the menus they ask
+/// about, which `--quiet` still shows.
+macro_rules! prompt {
+    ($($arg:tt)*) => {{
+        if $crate::output::json() {
+            eprint!($($arg)*);
+        } else {
+            print!($($arg)*);
+        }
+    }};
+}
+
+/// Prints a warning to stderr and counts it in the run's [`Warnings`] for
+/// `--strict`.
+macro_rules! warning {
+    ($warnings:expr, $($arg:tt)*) => {{
+        $warnings.add();
+        failure!($($arg)*);
+    }};
+}
+
+mod archive;
+mod axml;
+mod backup;
+mod bundle;
+pub mod config;
+mod device;
+pub mod error;
+pub mod events;
+mod install;
+mod interrupt;
+pub mod manifest;
+pub mod output;
+mod progress;
+mod pull;
+mod remote;
+mod runlog;
+mod store;
+#[cfg(test)]
+mod testing;
+
+use archive::{TempDir, archive_backup_name, is_archive};
+use axml::ApkInfo;
+pub use backup::{BackupArgs, BackupReport, backup};
+use backup::{
+    DEFAULT_NAME_FORMAT, FREE_SPACE_MARGIN_BYTES, PackageLog, Since, available_space,
+    backup_folder_name, base_chain, check_temp_dir, package_entry, parse_name_format, parse_since,
+    prune_store, read_base_apk_info, rotate_backups, run_backup_mode, tool_info,
+};
+use bundle::BundleFormat;
+use chrono::{Local, NaiveDateTime};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+pub use config::{BackupOrder, Config, ConfigFile};
+pub use device::{AdbRunner, SystemAdb};
+use device::{
+    MIN_ADB_VERSION, TcpConnection, adb_info, adb_path_arg, choose_device, connect_device,
+    device_state_hint, device_states, dumpsys_package, get_dumpsys_version_code, get_package_paths,
+    get_packages, get_third_party_packages, get_version_codes, long_version, needs_device,
+    parse_dumpsys_version_name, pm_args, short_device_state, stream_packages,
+};
+use error::{AppError, Result};
+pub use install::{InstallArgs, InstallReport, InstallStatus, InstalledPackage, install};
+use install::{SplitFilter, find_apk_files, missing_apks, order_split_apks, run_install_mode};
+use manifest::{
+    ApkFile, DeviceInfo, Manifest, PackageEntry, PackageStatus, SCHEMA_VERSION, ToolInfo,
+    sha256_file,
+};
+use output::Warnings;
+use pull::{PullArgs, PullOptions, extract_apk, run_pull_mode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
+
+const BACKUP_DIR: &str = "backup";
+const DEFAULT_RETRIES: u32 = 3;
+const DEVICE_INFO_DIR: &str = "device_info";
+/// Width of the package column in `list --stream`, which can't size it to
+/// the longest name before printing the first row.
+const STREAM_LIST_WIDTH: usize = 40;
+const DEVICE_BENCH_FILE: &str = "/data/local/tmp/apktool_bench.bin";
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Back up and restore third-party APKs over adb",
+    after_help = error::EXIT_STATUS_HELP
+)]
+struct Cli {
+    /// Exit non-zero if any warning was printed
+    ///
+    /// Every warning is promoted. For backup: external data or OBB files
+    /// that couldn't be checked or pulled, including scoped storage denials;
+    /// app data that couldn't be pulled; a failed launcher capture; granted
+    /// permissions or install times that couldn't be read; APKs that couldn't
+    /// be stat'ed; a --from-file package that isn't installed; the exec-out
+    /// fallback; a name that differs from an existing backup only by
+    /// whitespace; and a --dedup store that couldn't be cleaned up. A backup
+    /// failed this way is recorded as such in its manifest and JSON report.
+    /// For install: a package folder with no APKs, permissions that couldn't
+    /// be granted again, a system app, and a different source device or
+    /// Android version, or an incompatibility overridden with --force. For
+    /// export, a package missing from the manifest; for check, a package
+    /// that couldn't be pulled again. For any command, an adb older than the
+    /// supported minimum.
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Serial of the device to use when several are attached
+    #[arg(short, long, global = true)]
+    serial: Option<String>,
+
+    /// Connect to a device over TCP/IP with `adb connect` first
+    ///
+    /// The device is used as if passed to --serial, and disconnected again on
+    /// exit unless it was already connected.
+    #[arg(
+        long,
+        global = true,
+        value_name = "HOST:PORT",
+        conflicts_with = "serial"
+    )]
+    connect: Option<String>,
+
+    /// Wait for a device to be attached and authorized instead of failing
+    /// right away
+    ///
+    /// Gives up after SECONDS if given as `--wait-for-device=SECONDS`,
+    /// exiting with the no-device status.
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    wait_for_device: Option<Option<u64>>,
+
+    /// Android user (e.g. a work profile) whose apps to back up or install
+    ///
+    /// Given as `--user=ID`. Without an id, the users on the device are
+    /// listed to pick from. App data is read from and restored to that user
+    /// as well.
+    #[arg(
+        long,
+        global = true,
+        value_name = "ID",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    user: Option<Option<u32>>,
+
+    /// Folder holding the backups [default: backup]
+    ///
+    /// Relative paths are resolved against the working directory. Overrides
+    /// `backup-dir` in apktool.toml.
+    #[arg(long, global = true, value_name = "PATH", env = "APKTOOL_BACKUP_DIR")]
+    backup_dir: Option<PathBuf>,
+
+    /// Folder for scratch files [default: the system temp folder]
+    ///
+    /// Archives are unpacked here, and `backup --stream` writes its partial
+    /// archive here. The folder must exist and be writable.
+    #[arg(long, global = true, value_name = "PATH")]
+    temp_dir: Option<PathBuf>,
+
+    /// adb binary to run instead of the one on PATH
+    #[arg(long, global = true, value_name = "PATH", env = "ADB")]
+    adb_path: Option<PathBuf>,
+
+    /// Kill any adb command that runs longer than this many seconds
+    ///
+    /// Transfers count too: a pull, a streamed pull or an `exec-in` push of
+    /// app data is killed once it takes longer, so allow for the largest
+    /// package.
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Append the run's adb commands and their output to this file
+    /// [default: <backup dir>/apktool.log]
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Also print each adb command and its output to stderr
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Color ✓ lines green and ✗ lines red
+    #[arg(long, global = true, value_name = "WHEN", default_value = "auto")]
+    color: output::ColorChoice,
+
+    /// Only print failures, warnings and final summaries
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Order of the backups offered for selection
+    #[arg(long, global = true, value_name = "ORDER", default_value = "newest")]
+    sort_backups: BackupOrder,
+
+    /// Also report progress as JSON lines events on stderr
+    ///
+    /// For programs driving apktool; the output on stdout is unchanged. Each
+    /// line is an object whose `event` is backup_started, install_started,
+    /// package_started, file_pulled, package_deferred, package_completed,
+    /// package_failed, package_skipped, backup_completed or
+    /// install_completed.
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "human")]
+    progress_format: events::ProgressFormat,
+
+    /// Print a JSON summary to stdout and send progress output to stderr
+    ///
+    /// backup and install exit non-zero if any package failed.
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Back up installed third-party packages
+    ///
+    /// On a terminal, type p and press Enter to pause once the packages in
+    /// progress are done, then press Enter again to resume.
+    Backup(Box<BackupArgs>),
+    /// Install packages from a backup
+    Install(InstallArgs),
+    /// Measure adb pull throughput
+    Bench(BenchArgs),
+    /// Show installed packages without backing anything up
+    List(ListArgs),
+    /// Uninstall the packages contained in a backup
+    Uninstall(UninstallArgs),
+    /// Delete old backups, keeping the most recent ones
+    Prune(PruneArgs),
+    /// Delete interrupted backups and leftover partial archives
+    ///
+    /// Partial archives written in the last 10 minutes are kept, since a
+    /// --stream backup may still be adding to them.
+    Clean(CleanArgs),
+    /// Compare installed app versions against a backup
+    Verify(VerifyArgs),
+    /// Find package folders in a backup that are empty or incomplete
+    Check(CheckArgs),
+    /// Check without a device that each package in a backup would install
+    Validate(ValidateArgs),
+    /// Delete a backup
+    Delete(DeleteArgs),
+    /// Rename a backup
+    Rename(RenameArgs),
+    /// Write a package from a backup to an .apks or .xapk bundle other tools
+    /// can install
+    Export(ExportArgs),
+    /// List packages added, removed or changed between two backups
+    Diff(DiffArgs),
+    /// Pull one installed package's APKs, outside of any backup
+    Pull(PullArgs),
+    /// Check adb, the device and the backup folder, for bug reports
+    Doctor,
+    /// Print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions(CompletionsArgs),
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Backup folder or archive name under `backup/`, or a path to one
+    backup: String,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// Backup folder or archive name under `backup/`, or a path to one
+    backup: String,
+    /// Delete the package folders found, and their manifest entries
+    ///
+    /// Only backup folders can be cleaned, not archives.
+    #[arg(long)]
+    clean: bool,
+    /// With --clean, pull the packages again from the device instead,
+    /// deleting only those it no longer has
+    #[arg(long, requires = "clean")]
+    repull: bool,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// Backup folder or archive name under `backup/`, or a path to one
+    backup: String,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Older backup folder or archive name under `backup/`, or a path to one
+    old: String,
+    /// Newer backup folder or archive name under `backup/`, or a path to one
+    new: String,
+    /// Also compare each APK's checksum, to catch changes that kept the
+    /// version code
+    #[arg(long)]
+    files: bool,
+    /// Copy the packages added or changed in NEW into a new differential
+    /// backup NAME under `backup/`, with OLD as its base
+    ///
+    /// Files are hard-linked where possible and copied otherwise. Packages
+    /// removed in NEW still come from OLD when the export is installed.
+    #[arg(long, value_name = "NAME")]
+    export: Option<String>,
+}
+
+#[derive(Args)]
+struct DeleteArgs {
+    /// Backup folder or archive to delete; asks when omitted
+    backup: Option<String>,
+    /// Don't ask for confirmation
+    #[arg(short, long)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct RenameArgs {
+    /// Current backup folder or archive name
+    old: String,
+    /// New name; `$date` is replaced with the current timestamp
+    new: String,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Backup folder or archive name under `backup/`, or a path to one
+    backup: String,
+    /// Package to export
+    package: String,
+    /// Bundle file to write, e.g. `app.apks`
+    output: PathBuf,
+    /// Bundle format [default: apks for a `.apks` output, else xapk]
+    #[arg(long, value_enum)]
+    format: Option<BundleFormat>,
+}
+
+#[derive(Args)]
+struct CompletionsArgs {
+    shell: clap_complete::Shell,
+}
+
+#[derive(Args)]
+struct PruneArgs {
+    /// Number of backups to keep
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    keep: u32,
+}
+
+#[derive(Args)]
+struct CleanArgs {
+    /// Also delete backups made before then: a duration back from now such
+    /// as 30d, or a date such as 2024-05-01
+    #[arg(long, value_name = "WHEN", value_parser = parse_since)]
+    older_than: Option<Since>,
+    /// Allow deleting the newest backup too, if it is interrupted or older
+    /// than --older-than
+    #[arg(long)]
+    include_latest: bool,
+    /// Only list what would be deleted
+    #[arg(long)]
+    dry_run: bool,
+    /// Don't ask for confirmation
+    #[arg(short, long)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct UninstallArgs {
+    /// Keep app data and cache (pm uninstall -k)
+    #[arg(long)]
+    keep_data: bool,
+    /// Don't ask for confirmation
+    #[arg(short, long)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Also list system packages
+    #[arg(long)]
+    system: bool,
+    /// Look up each package's version name too (one adb call per package)
+    #[arg(long)]
+    details: bool,
+    /// Print each package as soon as the device reports it
+    ///
+    /// Packages come out in the device's order rather than sorted, and with
+    /// --json as one JSON object per line.
+    #[arg(long)]
+    stream: bool,
+    /// Only list packages the device has a newer version of than this
+    /// backup, by version code
+    #[arg(long, value_name = "BACKUP", conflicts_with = "stream")]
+    outdated: Option<String>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Package to pull (defaults to the first third-party package)
+    #[arg(long, conflicts_with = "size")]
+    package: Option<String>,
+    /// Number of pulls
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    runs: u32,
+    /// Pull this many MiB of generated data instead of a package
+    #[arg(long, value_name = "MIB")]
+    size: Option<u64>,
+}
+
+/// Package names read from a `backup --from-file` list.
+#[derive(Clone)]
+struct PackageList {
+    path: PathBuf,
+    packages: Vec<String>,
+}
+
+fn read_package_list(path: &str) -> Result<PackageList, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let packages = parse_package_list(&contents).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(PackageList {
+        path: PathBuf::from(path),
+        packages,
+    })
+}
+
+/// The packages of a JSON report or manifest, for `--from-report`.
+#[derive(Clone)]
+struct ReportList {
+    path: PathBuf,
+    packages: Vec<String>,
+    /// The packages whose status is `failed`.
+    failed: Vec<String>,
+}
+
+impl ReportList {
+    fn selected(&self, failed_only: bool) -> &[String] {
+        if failed_only {
+            &self.failed
+        } else {
+            &self.packages
+        }
+    }
+}
+
+/// The parts of a backup report, install report or manifest that
+/// `--from-report` reads. They all list packages with a name and status.
+#[derive(Deserialize)]
+struct ReportFile {
+    packages: Vec<ReportEntry>,
+}
+
+#[derive(Deserialize)]
+struct ReportEntry {
+    name: String,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+fn read_report_list(path: &str) -> Result<ReportList, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let report: ReportFile = serde_json::from_str(&contents)
+        .map_err(|e| format!("{}: not a report or manifest: {}", path, e))?;
+    let mut packages: Vec<String> = Vec::new();
+    let mut failed = Vec::new();
+    for entry in report.packages {
+        if entry.status.as_deref() == Some("failed") && !failed.contains(&entry.name) {
+            failed.push(entry.name.clone());
+        }
+        if !packages.contains(&entry.name) {
+            packages.push(entry.name);
+        }
+    }
+    Ok(ReportList {
+        path: PathBuf::from(path),
+        packages,
+        failed,
+    })
+}
+
+/// Reads one package name per line, skipping blank lines and anything after
+/// a `#`. Repeated names are listed once.
+fn parse_package_list(contents: &str) -> Result<Vec<String>, String> {
+    let mut packages: Vec<String> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let name = line.split('#').next().unwrap_or_default().trim();
+        if name.is_empty() {
+            continue;
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+        {
+            return Err(format!(
+                "line {}: {:?} is not a package name",
+                number + 1,
+                name
+            ));
+        }
+        if !packages.iter().any(|package| package == name) {
+            packages.push(name.to_string());
+        }
+    }
+    Ok(packages)
+}
+
+fn parse_args<A, I, T>(name: &'static str, args: I) -> Result<A, clap::Error>
+where
+    A: Args + FromArgMatches,
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let command = A::augment_args(clap::Command::new(name).no_binary_name(true));
+    A::from_arg_matches(&command.try_get_matches_from(args)?)
+}
+
+/// Runs the command line tool: parses the process arguments, runs the
+/// subcommand and reports any error on stderr.
+pub fn cli_main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    // The long version runs adb, so it has to know --adb-path before clap
+    // has parsed anything.
+    let mut version_adb = SystemAdb::new();
+    if let Some(adb_path) = adb_path_arg(std::env::args_os().skip(1)) {
+        version_adb = version_adb.with_path(adb_path);
+    }
+    let matches = Cli::command()
+        .long_version(long_version(&version_adb))
+        .get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Commands::Completions(args) = &cli.command {
+        clap_complete::generate(
+            args.shell,
+            &mut Cli::command(),
+            "apktool",
+            &mut io::stdout(),
+        );
+        return Ok(());
+    }
+    let mut adb = SystemAdb::new();
+    if let Some(adb_path) = &cli.adb_path {
+        adb = adb.with_path(adb_path);
+    }
+    if let Some(serial) = &cli.serial {
+        adb = adb.with_serial(serial);
+    }
+    if let Some(wait) = cli.wait_for_device {
+        adb = adb.with_wait_for_device(wait.map(Duration::from_secs));
+    }
+    if let Some(Some(user)) = cli.user {
+        adb = adb.with_user(user);
+    }
+    let config_file = ConfigFile::load().map_err(|e| e.to_string())?;
+    if let Some(timeout) = cli.timeout.or(config_file.timeout) {
+        adb = adb.with_timeout(Duration::from_secs(timeout));
+    }
+    let backup_args = match &cli.command {
+        Commands::Backup(args) => Some(args.as_ref()),
+        _ => None,
+    };
+    let mut config = resolve_config(config_file, backup_args)?;
+    if let Some(backup_dir) = &cli.backup_dir {
+        config.backup_dir = backup_dir.clone();
+    }
+    config.strict = cli.strict;
+    if let Some(temp_dir) = &cli.temp_dir {
+        // doctor reports a bad temp dir among its other checks.
+        if !matches!(cli.command, Commands::Doctor) {
+            check_temp_dir(temp_dir, 0)?;
+        }
+        config.temp_dir = temp_dir.clone();
+    }
+    config.backup_order = cli.sort_backups;
+    config.interactive = true;
+    output::init(cli.color, cli.quiet, cli.json);
+    events::init(cli.progress_format);
+    runlog::set_verbose(cli.verbose);
+    let log_file = cli
+        .log_file
+        .clone()
+        .unwrap_or_else(|| config.backup_dir.join(runlog::LOG_FILE));
+    // doctor reports a missing backup dir rather than making one for its log.
+    let skip_log = matches!(cli.command, Commands::Doctor)
+        && cli.log_file.is_none()
+        && !config.backup_dir.is_dir();
+    if !skip_log && let Err(e) = runlog::open(&log_file) {
+        eprintln!("Warning: Could not open log file {:?}: {}", log_file, e);
+    }
+
+    let connection = match &cli.connect {
+        Some(address) => {
+            let connection = TcpConnection::open(&adb, address)?;
+            adb = adb.with_serial(&connection.serial);
+            Some(connection)
+        }
+        None => None,
+    };
+    if needs_device(&cli.command) {
+        adb = choose_device(adb, cli.user == Some(None))?;
+    }
+
+    let adb = &adb;
+    match &cli.command {
+        Commands::Backup(args) => run_backup_mode(adb, args, &config)?,
+        Commands::Install(args) => run_install_mode(adb, args, &config)?,
+        Commands::Bench(args) => run_bench_mode(adb, args, &config)?,
+        Commands::List(args) => run_list_mode(adb, args, &config)?,
+        Commands::Uninstall(args) => run_uninstall_mode(adb, args, &config)?,
+        Commands::Verify(args) => run_verify_mode(adb, args, &config)?,
+        Commands::Check(args) => run_check_mode(adb, args, &config)?,
+        Commands::Validate(args) => run_validate_mode(args, &config)?,
+        Commands::Delete(args) => run_delete_mode(args, &config)?,
+        Commands::Rename(args) => run_rename_mode(args, &config)?,
+        Commands::Export(args) => run_export_mode(args, &config)?,
+        Commands::Diff(args) => run_diff_mode(args, &config)?,
+        Commands::Pull(args) => run_pull_mode(adb, args, &config)?,
+        Commands::Doctor => run_doctor_mode(adb, &config)?,
+        Commands::Prune(args) => {
+            rotate_backups(&config.backup_dir, args.keep, None, &config.warnings)?
+        }
+        Commands::Clean(args) => run_clean_mode(args, &config)?,
+        Commands::Completions(_) => unreachable!("handled before reading the config"),
+    }
+
+    drop(connection);
+    let warnings = config.warnings.count();
+    if cli.strict && warnings > 0 {
+        return Err(AppError::StrictWarnings(warnings));
+    }
+
+    Ok(())
+}
+
+/// Applies backup flags over the config file. Flags given on the command
+/// line replace the file's value; repeatable ones like `--include` replace
+/// its whole list.
+pub fn resolve_config(file: ConfigFile, backup_args: Option<&BackupArgs>) -> Result<Config> {
+    let compile = |patterns: Vec<String>, key: &str| -> Result<Vec<Regex>, String> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid {} pattern in config file: {}", key, e))
+            })
+            .collect()
+    };
+
+    let mut config = Config {
+        backup_dir: file.backup_dir.unwrap_or_else(|| PathBuf::from(BACKUP_DIR)),
+        jobs: file.jobs,
+        retries: file.retries.unwrap_or(DEFAULT_RETRIES),
+        name_format: file
+            .name_format
+            .map(|format| parse_name_format(&format))
+            .transpose()
+            .map_err(|e| format!("Invalid name-format in config file: {}", e))?,
+        includes: compile(file.include, "include")?,
+        excludes: compile(file.exclude, "exclude")?,
+        strict: false,
+        temp_dir: std::env::temp_dir(),
+        backup_order: BackupOrder::Newest,
+        warnings: Warnings::default(),
+        interactive: false,
+    };
+
+    if let Some(args) = backup_args {
+        config.jobs = args.jobs.or(config.jobs);
+        config.retries = args.retries.unwrap_or(config.retries);
+        config.name_format = args.name_format.clone().or(config.name_format.take());
+        if !args.includes.is_empty() {
+            config.includes = args.includes.clone();
+        }
+        if !args.excludes.is_empty() {
+            config.excludes = args.excludes.clone();
+        }
+    }
+    Ok(config)
+}
+
+/// A backup picked by [`choose_backup`].
+struct SelectedBackup {
+    path: PathBuf,
+    /// Scratch folder holding an unpacked archive or a copy fetched from a
+    /// remote store, removed on drop.
+    _unpacked: Option<TempDir>,
+}
+
+impl SelectedBackup {
+    /// Whether this is a scratch copy, such as an unpacked archive, so
+    /// changes to it are lost.
+    fn is_archive(&self) -> bool {
+        self._unpacked.is_some()
+    }
+}
+
+/// Asks the user to pick a backup folder or archive under the backup dir,
+/// unpacking archives to a scratch folder. Returns `None` if there are no
+/// backups.
+fn choose_backup(config: &Config, action: &str) -> Result<Option<SelectedBackup>> {
+    let Some(selected_backup) = pick_backup(config, action)? else {
+        return Ok(None);
+    };
+
+    if is_archive(&selected_backup) {
+        // The archive unpacks to at least its own size.
+        check_temp_dir(&config.temp_dir, fs::metadata(&selected_backup)?.len())?;
+        let temp_dir = TempDir::new(&config.temp_dir, &format!("apktool-{}", action))?;
+        status!("Unpacking {:?}...", selected_backup.file_name().unwrap());
+        let unpacked = archive::unpack(&selected_backup, temp_dir.path())?;
+        Ok(Some(SelectedBackup {
+            path: unpacked,
+            _unpacked: Some(temp_dir),
+        }))
+    } else {
+        Ok(Some(SelectedBackup {
+            path: selected_backup,
+            _unpacked: None,
+        }))
+    }
+}
+
+/// Copies a backup from a remote store to a scratch folder for `install
+/// --from-remote`, unpacking it if it is an archive.
+fn fetch_remote_backup(remote: &remote::Remote, temp_root: &Path) -> Result<SelectedBackup> {
+    let tool = remote::Tool::find().ok_or_else(|| {
+        AppError::Transfer("--from-remote needs rsync or scp, but neither was found.".to_string())
+    })?;
+    let temp_dir = TempDir::new(temp_root, "apktool-remote")?;
+    status!("Copying {} from the remote...", remote);
+    let fetched = remote::fetch(tool, remote, temp_dir.path())
+        .map_err(|e| AppError::Transfer(format!("Could not copy {}: {}", remote, e)))?;
+    let path = if is_archive(&fetched) {
+        check_temp_dir(temp_root, fs::metadata(&fetched)?.len())?;
+        status!("Unpacking {:?}...", fetched.file_name().unwrap());
+        archive::unpack(&fetched, temp_dir.path())?
+    } else if fetched.is_dir() {
+        fetched
+    } else {
+        return Err(format!("{} is not a backup folder or archive.", remote).into());
+    };
+    Ok(SelectedBackup {
+        path,
+        _unpacked: Some(temp_dir),
+    })
+}
+
+/// Asks the user to pick a backup folder or archive under the backup dir and
+/// returns its path as is. Returns `None` if there are no backups.
+fn pick_backup(config: &Config, action: &str) -> Result<Option<PathBuf>> {
+    let backup_root = config.backup_dir.as_path();
+    if !backup_root.exists() {
+        return Err(AppError::BackupNotFound(format!(
+            "{} (run 'backup' first)",
+            backup_root.display()
+        )));
+    }
+
+    let mut entries: Vec<_> = fs::read_dir(backup_root)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|path| (path.is_dir() && !store::is_store(path)) || is_archive(path))
+        .collect();
+
+    if entries.is_empty() {
+        eprintln!("No backups found.");
+        return Ok(None);
+    }
+
+    sort_backups(&mut entries, config.backup_order);
+    let names = file_names(&entries);
+    let Some(index) = select_from(&names, &format!("Select backup to {}:", action))? else {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    };
+
+    Ok(Some(entries.swap_remove(index)))
+}
+
+/// Sorts backup paths for a selection list in the `--sort-backups` order.
+fn sort_backups(paths: &mut [PathBuf], order: BackupOrder) {
+    match order {
+        BackupOrder::Name => paths.sort(),
+        BackupOrder::Newest | BackupOrder::Oldest => {
+            paths.sort_by_cached_key(|path| (backup_time(path), path.clone()));
+            if let BackupOrder::Newest = order {
+                paths.reverse();
+            }
+        }
+    }
+}
+
+/// When a backup was made, in seconds since the Unix epoch: the last
+/// `YYYYMMDDhhmmss` timestamp in its name, which for a differential backup
+/// is when it was made rather than its base, or else its mtime.
+fn backup_time(path: &Path) -> Option<i64> {
+    let name = path.file_name()?.to_string_lossy();
+    let from_name = name
+        .rsplit(|c: char| !c.is_ascii_digit())
+        .filter(|digits| digits.len() == 14)
+        .filter_map(|digits| NaiveDateTime::parse_from_str(digits, DEFAULT_NAME_FORMAT).ok())
+        .find_map(|time| time.and_local_timezone(Local).earliest())
+        .map(|time| time.timestamp());
+    from_name.or_else(|| {
+        let modified = fs::metadata(path).ok()?.modified().ok()?;
+        let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        i64::try_from(since_epoch.as_secs()).ok()
+    })
+}
+
+fn file_names(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+        .collect()
+}
+
+/// Opens a backup given by name under the backup dir or by path, unpacking
+/// archives like [`choose_backup`] does.
+fn open_backup(config: &Config, name: &str) -> Result<SelectedBackup> {
+    let path = if Path::new(name).exists() {
+        PathBuf::from(name)
+    } else {
+        config.backup_dir.join(name)
+    };
+
+    if is_archive(&path) {
+        check_temp_dir(&config.temp_dir, fs::metadata(&path)?.len())?;
+        let temp_dir = TempDir::new(&config.temp_dir, "apktool-open")?;
+        let unpacked = archive::unpack(&path, temp_dir.path())?;
+        Ok(SelectedBackup {
+            path: unpacked,
+            _unpacked: Some(temp_dir),
+        })
+    } else if path.is_dir() {
+        Ok(SelectedBackup {
+            path,
+            _unpacked: None,
+        })
+    } else {
+        Err(AppError::BackupNotFound(name.to_string()))
+    }
+}
+
+/// Package folders of a backup, i.e. every subfolder except [`DEVICE_INFO_DIR`].
+fn list_package_dirs(backup_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(backup_dir)?
+        .filter_map(Result::ok)
+        .filter(|e| e.path().is_dir() && e.file_name() != DEVICE_INFO_DIR)
+        .map(|e| e.path())
+        .collect())
+}
+
+/// Looks up a backup folder or archive by name under `backup_root`. The
+/// `.tar.zst` extension of an archive may be left out.
+fn find_backup(backup_root: &Path, name: &str) -> Result<PathBuf> {
+    let path = backup_root.join(name);
+    if path.is_dir() || is_archive(&path) {
+        return Ok(path);
+    }
+    let archive_path = backup_root.join(format!("{}{}", name, archive::ARCHIVE_EXTENSION));
+    if is_archive(&archive_path) {
+        return Ok(archive_path);
+    }
+    Err(AppError::BackupNotFound(name.to_string()))
+}
+
+fn run_delete_mode(args: &DeleteArgs, config: &Config) -> Result<()> {
+    let path = match &args.backup {
+        Some(name) => find_backup(&config.backup_dir, name)?,
+        None => match pick_backup(config, "delete")? {
+            Some(path) => path,
+            None => return Ok(()),
+        },
+    };
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+    if !args.yes {
+        prompt!("Delete backup {}? [y/N]: ", name);
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            status!("Aborted.");
+            return Ok(());
+        }
+    }
+
+    if path.is_dir() {
+        fs::remove_dir_all(&path)?;
+    } else {
+        fs::remove_file(&path)?;
+    }
+    status!("✓ Deleted {}", name);
+    prune_store(&config.backup_dir, &config.warnings);
+    Ok(())
+}
+
+/// Renames a backup folder or archive, and updates the manifests of
+/// differential backups that use it as their base.
+fn run_rename_mode(args: &RenameArgs, config: &Config) -> Result<()> {
+    let backup_root = config.backup_dir.as_path();
+    let old_path = find_backup(backup_root, &args.old)?;
+
+    let old_name = archive_backup_name(&old_path);
+    let timestamp = Local::now().format(DEFAULT_NAME_FORMAT).to_string();
+    let new_name = args.new.trim().replace("$date", &timestamp);
+    let new_name = new_name
+        .strip_suffix(archive::ARCHIVE_EXTENSION)
+        .unwrap_or(&new_name)
+        .to_string();
+    if new_name.is_empty() {
+        return Err("New name is empty.".into());
+    }
+    let archive_name = format!("{}{}", new_name, archive::ARCHIVE_EXTENSION);
+    // Same check as naming a new backup, for both forms so a folder and an
+    // archive never share a backup name.
+    backup_folder_name(
+        backup_root,
+        &new_name,
+        &timestamp,
+        "",
+        false,
+        &config.warnings,
+    )?;
+    backup_folder_name(
+        backup_root,
+        &archive_name,
+        &timestamp,
+        "",
+        false,
+        &config.warnings,
+    )?;
+    let new_file_name = if is_archive(&old_path) {
+        archive_name
+    } else {
+        new_name.clone()
+    };
+
+    fs::rename(&old_path, backup_root.join(&new_file_name))?;
+    status!(
+        "✓ Renamed {} to {}",
+        old_path.file_name().unwrap().to_string_lossy(),
+        new_file_name
+    );
+
+    for entry in fs::read_dir(backup_root)?.filter_map(Result::ok) {
+        let dir = entry.path();
+        let Ok(Some(mut manifest)) = Manifest::load(&dir) else {
+            continue;
+        };
+        if manifest.base.as_deref() == Some(old_name.as_str()) {
+            manifest.base = Some(new_name.clone());
+            manifest.save(&dir)?;
+            status!("  Updated base of {}", entry.file_name().to_string_lossy());
+        }
+    }
+
+    Ok(())
+}
+
+/// Packs one package of a backup into a bundle after checking that every
+/// APK the manifest lists is there.
+fn run_export_mode(args: &ExportArgs, config: &Config) -> Result<()> {
+    if args.output.exists() {
+        return Err(format!("{} already exists.", args.output.display()).into());
+    }
+
+    let backup = open_backup(config, &args.backup)?;
+    let package_dir = backup.path.join(&args.package);
+    if !package_dir.is_dir() {
+        return Err(format!("Not found in backup: {}", args.package).into());
+    }
+
+    let entry = Manifest::load(&backup.path)?.and_then(|manifest| {
+        manifest
+            .packages
+            .into_iter()
+            .find(|entry| entry.name == args.package)
+    });
+    let apk_files = match &entry {
+        Some(entry) => {
+            if entry.status == PackageStatus::Failed {
+                return Err(format!(
+                    "{} failed to back up: {}",
+                    args.package,
+                    entry.error.as_deref().unwrap_or("unknown error")
+                )
+                .into());
+            }
+            let missing: Vec<_> = entry
+                .files
+                .iter()
+                .filter(|file| !package_dir.join(&file.name).is_file())
+                .map(|file| file.name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                return Err(format!(
+                    "{} is missing APKs listed in the manifest: {}",
+                    args.package,
+                    missing.join(", ")
+                )
+                .into());
+            }
+            entry
+                .files
+                .iter()
+                .map(|file| package_dir.join(&file.name))
+                .collect()
+        }
+        None => {
+            warning!(
+                config.warnings,
+                "Warning: {} is not in the backup's manifest, so its splits can't be checked.",
+                args.package
+            );
+            find_apk_files(&package_dir)?
+        }
+    };
+
+    let apk_files = order_split_apks(apk_files);
+    let Some(base) = apk_files.first() else {
+        return Err(format!("No APKs found for {}.", args.package).into());
+    };
+    if base
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("split_"))
+    {
+        return Err(format!("No base APK found for {}.", args.package).into());
+    }
+
+    let info = axml::read_apk_info(base).unwrap_or_else(|_| ApkInfo {
+        version_code: entry.as_ref().and_then(|entry| entry.version_code),
+        version_name: entry.as_ref().and_then(|entry| entry.version_name.clone()),
+        min_sdk: entry.as_ref().and_then(|entry| entry.min_sdk),
+        target_sdk: entry.as_ref().and_then(|entry| entry.target_sdk),
+        ..Default::default()
+    });
+    let format = args
+        .format
+        .unwrap_or_else(|| BundleFormat::for_path(&args.output));
+    if let Err(e) = bundle::pack(&apk_files, &info, &args.package, &args.output, format) {
+        let _ = fs::remove_file(&args.output);
+        return Err(format!("Failed to write {}: {}", args.output.display(), e).into());
+    }
+
+    status!(
+        "✓ Exported {} ({} APK(s)) to {}",
+        args.package,
+        apk_files.len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// One line of the `doctor` report.
+#[derive(Serialize)]
+struct DoctorCheck {
+    name: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+#[derive(Serialize)]
+struct DoctorReport {
+    apktool_version: &'static str,
+    checks: Vec<DoctorCheck>,
+}
+
+/// Runs every environment check and prints a pass/fail line for each. Fails
+/// if any check did, so scripts can tell.
+fn run_doctor_mode(adb: &SystemAdb, config: &Config) -> Result<()> {
+    let mut checks = Vec::new();
+    let mut check = |name, passed, detail: String| {
+        checks.push(DoctorCheck {
+            name,
+            passed,
+            detail,
+        })
+    };
+
+    let adb_found = match adb_info(adb) {
+        None => {
+            let detail = match &adb.path {
+                Some(path) => format!("could not run {:?}", path),
+                None => "adb not found on PATH".to_string(),
+            };
+            check("adb", false, detail);
+            false
+        }
+        Some(info) => {
+            let release = info
+                .platform_tools
+                .as_ref()
+                .map(|release| format!(" (platform-tools {})", release))
+                .unwrap_or_default();
+            match info.version {
+                Some(version) if version < MIN_ADB_VERSION => check(
+                    "adb",
+                    false,
+                    format!(
+                        "{}{} is older than {}; please update platform-tools",
+                        version, release, MIN_ADB_VERSION
+                    ),
+                ),
+                Some(version) => check("adb", true, format!("{}{}", version, release)),
+                None => check("adb", false, "could not read the adb version".to_string()),
+            }
+            true
+        }
+    };
+
+    if adb_found {
+        match adb.run_global(&["devices"]) {
+            Ok(output) => {
+                let devices = device_states(&output);
+                let listing = devices
+                    .iter()
+                    .map(|(serial, state)| match device_state_hint(state) {
+                        Some(hint) => {
+                            format!("{} ({}: {})", serial, short_device_state(state), hint)
+                        }
+                        None => format!("{} ({})", serial, state),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let ready: Vec<_> = devices
+                    .iter()
+                    .filter(|(_, state)| state == "device")
+                    .map(|(serial, _)| serial)
+                    .collect();
+                let (passed, detail) = match adb.serial() {
+                    Some(serial) if ready.iter().any(|ready| *ready == serial) => (true, listing),
+                    Some(serial) => (
+                        false,
+                        format!("{} is not ready; found: {}", serial, listing),
+                    ),
+                    None if devices.is_empty() => (false, "no device attached".to_string()),
+                    None if ready.len() == 1 => (true, listing),
+                    None if ready.is_empty() => (false, format!("no device ready: {}", listing)),
+                    None => (
+                        false,
+                        format!("{} devices ready, pass --serial: {}", ready.len(), listing),
+                    ),
+                };
+                check("device", passed, detail);
+            }
+            Err(e) => check("device", false, format!("adb devices failed: {}", e)),
+        }
+    }
+
+    // Nothing is created: a missing backup dir is checked through the
+    // nearest folder above it that exists, where the first backup makes it.
+    let backup_dir = &config.backup_dir;
+    let existing = backup_dir
+        .ancestors()
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("."));
+    let probe = existing.join(format!(".apktool-doctor-{}", std::process::id()));
+    let writable = fs::write(&probe, b"").and_then(|()| fs::remove_file(&probe));
+    let missing = existing != backup_dir.as_path();
+    match writable {
+        Ok(()) if missing => check(
+            "backup dir",
+            true,
+            format!(
+                "{} doesn't exist yet; it can be created in {}",
+                backup_dir.display(),
+                existing.display()
+            ),
+        ),
+        Ok(()) => check(
+            "backup dir",
+            true,
+            format!("{} is writable", backup_dir.display()),
+        ),
+        Err(e) if missing => check(
+            "backup dir",
+            false,
+            format!(
+                "{} doesn't exist, and {} isn't writable: {}",
+                backup_dir.display(),
+                existing.display(),
+                e
+            ),
+        ),
+        Err(e) => check(
+            "backup dir",
+            false,
+            format!("cannot write to {}: {}", backup_dir.display(), e),
+        ),
+    }
+
+    match available_space(existing) {
+        Ok(available) => check(
+            "free space",
+            available > FREE_SPACE_MARGIN_BYTES,
+            format!("{:.1} MiB available", available as f64 / (1024.0 * 1024.0)),
+        ),
+        Err(e) => check("free space", false, format!("could not measure: {}", e)),
+    }
+
+    match check_temp_dir(&config.temp_dir, 0) {
+        Ok(()) => check(
+            "temp dir",
+            true,
+            format!("{} is writable", config.temp_dir.display()),
+        ),
+        Err(e) => check("temp dir", false, e.to_string()),
+    }
+
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    if output::json() {
+        let report = DoctorReport {
+            apktool_version: env!("CARGO_PKG_VERSION"),
+            checks,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        status!("apktool {}", env!("CARGO_PKG_VERSION"));
+        for check in &checks {
+            if check.passed {
+                status!("✓ {}: {}", check.name, check.detail);
+            } else {
+                failure!("✗ {}: {}", check.name, check.detail);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} check(s) failed", failed).into());
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum VersionMatch {
+    Matching,
+    Older,
+    Newer,
+    Missing,
+    /// Installed, but a version code is missing on one side.
+    Unknown,
+}
+
+impl VersionMatch {
+    fn label(self) -> &'static str {
+        match self {
+            VersionMatch::Matching => "matching",
+            VersionMatch::Older => "older",
+            VersionMatch::Newer => "newer",
+            VersionMatch::Missing => "missing",
+            VersionMatch::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VerifiedPackage {
+    name: String,
+    status: VersionMatch,
+    backup_version_code: Option<u64>,
+    installed_version_code: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl DiffKind {
+    fn marker(self) -> char {
+        match self {
+            DiffKind::Added => '+',
+            DiffKind::Removed => '-',
+            DiffKind::Changed => '~',
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DiffedPackage {
+    name: String,
+    change: DiffKind,
+    old_version_code: Option<u64>,
+    new_version_code: Option<u64>,
+    old_version_name: Option<String>,
+    new_version_name: Option<String>,
+    /// APK files that differ, with `--files`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    files: Vec<DiffedFile>,
+}
+
+#[derive(Serialize)]
+struct DiffedFile {
+    name: String,
+    change: DiffKind,
+}
+
+/// Compares the package lists of two backups' manifests, and with
+/// `--export` copies the differences into a new backup. Doesn't need a
+/// device.
+fn run_diff_mode(args: &DiffArgs, config: &Config) -> Result<()> {
+    let old_chain = open_backup_chain(config, &args.old)?;
+    let new_chain = open_backup_chain(config, &args.new)?;
+    let old_packages = chain_packages(&old_chain);
+    let new_packages = chain_packages(&new_chain);
+    let old: HashMap<&String, &PackageEntry> = old_packages
+        .iter()
+        .map(|(name, (_, entry))| (name, entry))
+        .collect();
+    let new: HashMap<&String, &PackageEntry> = new_packages
+        .iter()
+        .map(|(name, (_, entry))| (name, entry))
+        .collect();
+
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).copied().collect();
+    names.sort();
+    names.dedup();
+
+    let mut unchanged = 0;
+    let mut packages = Vec::new();
+    for name in names {
+        let (change, files) = match (old.get(name), new.get(name)) {
+            (None, Some(_)) => (DiffKind::Added, Vec::new()),
+            (Some(_), None) => (DiffKind::Removed, Vec::new()),
+            (Some(old), Some(new)) => {
+                let files = if args.files {
+                    diff_files(&old.files, &new.files)
+                } else {
+                    Vec::new()
+                };
+                let version_changed =
+                    (old.version_code, &old.version_name) != (new.version_code, &new.version_name);
+                if !version_changed && files.is_empty() {
+                    unchanged += 1;
+                    continue;
+                }
+                (DiffKind::Changed, files)
+            }
+            (None, None) => unreachable!("name comes from one of the backups"),
+        };
+        packages.push(DiffedPackage {
+            name: name.clone(),
+            change,
+            old_version_code: old.get(name).and_then(|entry| entry.version_code),
+            new_version_code: new.get(name).and_then(|entry| entry.version_code),
+            old_version_name: old.get(name).and_then(|entry| entry.version_name.clone()),
+            new_version_name: new.get(name).and_then(|entry| entry.version_name.clone()),
+            files,
+        });
+    }
+
+    if let Some(name) = &args.export {
+        let exported: Vec<(&PathBuf, &PackageEntry)> = packages
+            .iter()
+            .filter(|package| package.change != DiffKind::Removed)
+            .map(|package| {
+                let (dir, entry) = &new_packages[&package.name];
+                (dir, entry)
+            })
+            .collect();
+        let base = &old_chain[0].0.path;
+        let device = &new_chain[0].1.device;
+        export_diff(config, name, base, device, &exported)?;
+    }
+
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&packages)?);
+        return Ok(());
+    }
+
+    let version = |code: Option<u64>, name: &Option<String>| match (code, name) {
+        (Some(code), Some(name)) => format!("{} ({})", name, code),
+        (Some(code), None) => code.to_string(),
+        (None, Some(name)) => name.clone(),
+        (None, None) => "-".to_string(),
+    };
+    for package in &packages {
+        let old_version = version(package.old_version_code, &package.old_version_name);
+        let new_version = version(package.new_version_code, &package.new_version_name);
+        match package.change {
+            DiffKind::Added => status!("+ {}  {}", package.name, new_version),
+            DiffKind::Removed => status!("- {}  {}", package.name, old_version),
+            DiffKind::Changed => {
+                status!("~ {}  {} -> {}", package.name, old_version, new_version)
+            }
+        }
+        for file in &package.files {
+            status!("    {} {}", file.change.marker(), file.name);
+        }
+    }
+    let count = |kind| packages.iter().filter(|p| p.change == kind).count();
+    summary!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        count(DiffKind::Added),
+        count(DiffKind::Removed),
+        count(DiffKind::Changed),
+        unchanged
+    );
+    Ok(())
+}
+
+/// Writes the package folders `packages` into a new differential backup
+/// `name` whose base is `base`, for `diff --export`.
+fn export_diff(
+    config: &Config,
+    name: &str,
+    base: &Path,
+    device: &DeviceInfo,
+    packages: &[(&PathBuf, &PackageEntry)],
+) -> Result<()> {
+    if packages.is_empty() {
+        status!("Nothing to export: no package was added or changed.");
+        return Ok(());
+    }
+    let timestamp = Local::now().format(DEFAULT_NAME_FORMAT).to_string();
+    let name = backup_folder_name(
+        &config.backup_dir,
+        name,
+        &timestamp,
+        "",
+        false,
+        &config.warnings,
+    )?;
+    if name.is_empty() {
+        return Err("Export name is empty.".into());
+    }
+    let target_dir = config.backup_dir.join(&name);
+    fs::create_dir_all(&target_dir)?;
+    let copied = packages
+        .iter()
+        .try_for_each(|(dir, entry)| link_or_copy_dir(dir, &target_dir.join(&entry.name)));
+    if let Err(e) = copied {
+        let _ = fs::remove_dir_all(&target_dir);
+        return Err(e.into());
+    }
+    let manifest = Manifest {
+        schema_version: SCHEMA_VERSION,
+        created_at: Local::now().to_rfc3339(),
+        created_by: Some(tool_info(None)),
+        base: Some(archive_backup_name(base)),
+        device: device.clone(),
+        partial: false,
+        warnings: 0,
+        strict_failed: false,
+        packages: packages.iter().map(|(_, entry)| (*entry).clone()).collect(),
+    };
+    manifest.save(&target_dir)?;
+    status!(
+        "✓ Exported {} package(s) to {:?}, based on {:?}",
+        packages.len(),
+        target_dir,
+        archive_backup_name(base)
+    );
+    Ok(())
+}
+
+/// Recreates the folder `source` at `dest`, hard-linking each file where
+/// the filesystem allows and copying it otherwise.
+fn link_or_copy_dir(source: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let dest_path = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_or_copy_dir(&entry.path(), &dest_path)?;
+        } else if fs::hard_link(entry.path(), &dest_path).is_err() {
+            fs::copy(entry.path(), &dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// A backup and the bases it builds on, newest first, each opened with
+/// [`open_backup`] and with its manifest. A base seen before ends the walk,
+/// as in [`base_chain`].
+fn open_backup_chain(config: &Config, name: &str) -> Result<Vec<(SelectedBackup, Manifest)>> {
+    let mut chain: Vec<(SelectedBackup, Manifest)> = Vec::new();
+    let mut seen: Vec<PathBuf> = Vec::new();
+    let mut next = Some(name.to_string());
+    while let Some(current) = next.take() {
+        let in_base = |e: AppError| -> AppError {
+            if current == name {
+                e
+            } else {
+                format!("Base backup {} of {}: {}", current, name, e).into()
+            }
+        };
+        let path = if Path::new(&current).exists() {
+            PathBuf::from(&current)
+        } else {
+            find_backup(&config.backup_dir, &current).map_err(in_base)?
+        };
+        if seen.contains(&path) {
+            break;
+        }
+        let backup = open_backup(config, &path.to_string_lossy()).map_err(in_base)?;
+        let manifest = Manifest::load(&backup.path)
+            .map_err(AppError::from)
+            .and_then(|manifest| {
+                manifest
+                    .ok_or_else(|| format!("{} has no manifest.json to compare.", current).into())
+            })
+            .map_err(in_base)?;
+        next = manifest.base.clone();
+        seen.push(path);
+        chain.push((backup, manifest));
+    }
+    Ok(chain)
+}
+
+/// The packages a chain from [`open_backup_chain`] holds, by name, with the
+/// folder each is in. A differential backup also stands for the packages of
+/// its base it didn't replace. Packages that failed to back up are left out.
+fn chain_packages(
+    chain: &[(SelectedBackup, Manifest)],
+) -> HashMap<String, (PathBuf, PackageEntry)> {
+    let mut packages = HashMap::new();
+    for (backup, manifest) in chain.iter().rev() {
+        packages.extend(
+            manifest
+                .packages
+                .iter()
+                .filter(|entry| entry.status == PackageStatus::Ok)
+                .map(|entry| {
+                    let dir = backup.path.join(&entry.name);
+                    (entry.name.clone(), (dir, entry.clone()))
+                }),
+        );
+    }
+    packages
+}
+
+/// APK files added, removed or with a different checksum. Files without a
+/// recorded checksum on either side only count when added or removed.
+fn diff_files(old: &[ApkFile], new: &[ApkFile]) -> Vec<DiffedFile> {
+    let mut names: Vec<&str> = old
+        .iter()
+        .chain(new)
+        .map(|file| file.name.as_str())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let find = |files: &[ApkFile]| files.iter().find(|file| file.name == name).cloned();
+            let change = match (find(old), find(new)) {
+                (None, Some(_)) => DiffKind::Added,
+                (Some(_), None) => DiffKind::Removed,
+                (Some(old), Some(new)) => match (old.sha256, new.sha256) {
+                    (Some(old), Some(new)) if old != new => DiffKind::Changed,
+                    _ => return None,
+                },
+                (None, None) => return None,
+            };
+            Some(DiffedFile {
+                name: name.to_string(),
+                change,
+            })
+        })
+        .collect()
+}
+
+/// Compares the version codes recorded in a backup against the ones
+/// installed on the device. Read-only.
+fn run_verify_mode(adb: &dyn AdbRunner, args: &VerifyArgs, config: &Config) -> Result<()> {
+    connect_device(adb, &config.warnings)?;
+
+    let backup = open_backup(config, &args.backup)?;
+    let manifest = Manifest::load(&backup.path)?;
+    let mut names: Vec<String> = list_package_dirs(&backup.path)?
+        .iter()
+        .map(|dir| dir.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+
+    let mut installed: HashSet<String> = get_third_party_packages(adb)?.into_iter().collect();
+    // Backups made with --include-system hold system packages too.
+    if manifest
+        .as_ref()
+        .is_some_and(|manifest| manifest.packages.iter().any(|entry| entry.system))
+    {
+        installed.extend(get_packages(adb, "-s")?);
+    }
+    let version_codes = get_version_codes(adb, &names);
+
+    let packages: Vec<VerifiedPackage> = names
+        .into_iter()
+        .map(|name| {
+            let backup_version_code = manifest.as_ref().and_then(|manifest| {
+                manifest
+                    .packages
+                    .iter()
+                    .find(|entry| entry.name == name)
+                    .and_then(|entry| entry.version_code)
+            });
+            let installed_version_code = version_codes.get(&name).copied();
+            let status = if !installed.contains(&name) {
+                VersionMatch::Missing
+            } else {
+                match (installed_version_code, backup_version_code) {
+                    (Some(device), Some(backup)) if device == backup => VersionMatch::Matching,
+                    (Some(device), Some(backup)) if device < backup => VersionMatch::Older,
+                    (Some(_), Some(_)) => VersionMatch::Newer,
+                    _ => VersionMatch::Unknown,
+                }
+            };
+            VerifiedPackage {
+                name,
+                status,
+                backup_version_code,
+                installed_version_code,
+            }
+        })
+        .collect();
+
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&packages)?);
+        return Ok(());
+    }
+
+    match manifest.as_ref() {
+        None => eprintln!("Backup has no manifest.json, so its version codes are unknown."),
+        Some(Manifest {
+            created_by: Some(tool),
+            ..
+        }) => status!("Backup made by {}.", describe_created_by(tool)),
+        Some(_) => {}
+    }
+
+    let width = packages
+        .iter()
+        .map(|package| package.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("PACKAGE".len());
+    let code = |code: Option<u64>| code.map(|c| c.to_string()).unwrap_or_else(|| "-".into());
+    status!(
+        "{:<width$}  {:>12}  {:>12}  STATUS",
+        "PACKAGE",
+        "BACKUP",
+        "INSTALLED"
+    );
+    for package in &packages {
+        status!(
+            "{:<width$}  {:>12}  {:>12}  {}",
+            package.name,
+            code(package.backup_version_code),
+            code(package.installed_version_code),
+            package.status.label()
+        );
+    }
+
+    let count = |kind| packages.iter().filter(|p| p.status == kind).count();
+    status!(
+        "{} matching, {} older, {} newer, {} missing, {} unknown",
+        count(VersionMatch::Matching),
+        count(VersionMatch::Older),
+        count(VersionMatch::Newer),
+        count(VersionMatch::Missing),
+        count(VersionMatch::Unknown)
+    );
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct FolderProblem {
+    package: String,
+    problem: String,
+    /// What `--clean` did about it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<CleanAction>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum CleanAction {
+    Removed,
+    Repulled,
+}
+
+/// What is wrong with a backup package folder, if anything: it has no
+/// APKs, lacks files its manifest entry lists or holds more, its backup
+/// failed, or the backup has a manifest that doesn't list it. Without a
+/// manifest, a folder only needs a base APK.
+fn folder_problem(
+    path: &Path,
+    manifest: Option<&Manifest>,
+    entry: Option<&PackageEntry>,
+) -> io::Result<Option<String>> {
+    let apk_files = order_split_apks(find_apk_files(path)?);
+    if apk_files.is_empty() {
+        return Ok(Some("no APK files".to_string()));
+    }
+    if manifest.is_some() {
+        let Some(entry) = entry else {
+            return Ok(Some("not in the manifest".to_string()));
+        };
+        if entry.status != PackageStatus::Ok {
+            let status = match entry.status {
+                PackageStatus::Skipped => "skipped",
+                _ => "failed",
+            };
+            return Ok(Some(format!("the manifest records it as {}", status)));
+        }
+        if apk_files.len() != entry.files.len() {
+            return Ok(Some(format!(
+                "has {} APK(s), the manifest lists {}",
+                apk_files.len(),
+                entry.files.len()
+            )));
+        }
+    }
+    Ok(missing_apks(path, &apk_files, entry))
+}
+
+/// Looks for package folders left empty or incomplete, e.g. by a backup
+/// that crashed, and with `--clean` deletes them or pulls them again.
+fn run_check_mode(adb: &dyn AdbRunner, args: &CheckArgs, config: &Config) -> Result<()> {
+    let backup = open_backup(config, &args.backup)?;
+    if args.clean && backup.is_archive() {
+        return Err("Archives can't be cleaned; check the backup folder instead.".into());
+    }
+    let mut manifest = Manifest::load(&backup.path)?;
+    let mut dirs = list_package_dirs(&backup.path)?;
+    dirs.sort();
+
+    let mut problems = Vec::new();
+    for dir in &dirs {
+        let package = dir.file_name().unwrap().to_string_lossy().to_string();
+        let entry = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.packages.iter().find(|entry| entry.name == package));
+        if let Some(problem) = folder_problem(dir, manifest.as_ref(), entry)? {
+            problems.push(FolderProblem {
+                package,
+                problem,
+                action: None,
+            });
+        }
+    }
+
+    if args.clean && !problems.is_empty() {
+        let names: Vec<String> = problems.iter().map(|p| p.package.clone()).collect();
+        let version_codes = if args.repull {
+            connect_device(adb, &config.warnings)?;
+            get_version_codes(adb, &names)
+        } else {
+            HashMap::new()
+        };
+        let mut repulled = Vec::new();
+        for problem in &mut problems {
+            let package_dir = backup.path.join(&problem.package);
+            fs::remove_dir_all(&package_dir)?;
+            let old = manifest.as_ref().and_then(|manifest| {
+                manifest
+                    .packages
+                    .iter()
+                    .find(|entry| entry.name == problem.package)
+            });
+            let entry = args
+                .repull
+                .then(|| {
+                    repull_package(
+                        adb,
+                        &backup.path,
+                        &problem.package,
+                        version_codes.get(&problem.package).copied(),
+                        old,
+                        config,
+                    )
+                })
+                .flatten();
+            match entry {
+                Some(entry) => {
+                    problem.action = Some(CleanAction::Repulled);
+                    repulled.push(entry);
+                }
+                None => {
+                    let _ = fs::remove_dir_all(&package_dir);
+                    problem.action = Some(CleanAction::Removed);
+                }
+            }
+        }
+        if let Some(manifest) = &mut manifest {
+            manifest
+                .packages
+                .retain(|entry| !names.contains(&entry.name));
+            manifest.merge_packages(repulled);
+            manifest.save(&backup.path)?;
+        }
+    }
+
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&problems)?);
+    } else {
+        for problem in &problems {
+            match problem.action {
+                Some(CleanAction::Repulled) => {
+                    status!("  ✓ {}: {}, pulled again", problem.package, problem.problem)
+                }
+                Some(CleanAction::Removed) => {
+                    status!("  ✓ {}: {}, removed", problem.package, problem.problem)
+                }
+                None => failure!("  ✗ {}: {}", problem.package, problem.problem),
+            }
+        }
+        status!(
+            "{} of {} package folder(s) empty or incomplete.",
+            problems.len(),
+            dirs.len()
+        );
+    }
+
+    if !args.clean && !problems.is_empty() {
+        return Err(format!(
+            "{} package folder(s) would fail to install; run with --clean to fix them",
+            problems.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks offline that each package folder in a backup is a split set
+/// `install-multiple` would take: complete per [`folder_problem`], matching
+/// its manifest checksums, and consistent per [`split_set_problems`].
+fn run_validate_mode(args: &ValidateArgs, config: &Config) -> Result<()> {
+    let backup = open_backup(config, &args.backup)?;
+    let manifest = Manifest::load(&backup.path)?;
+    let mut dirs = list_package_dirs(&backup.path)?;
+    dirs.sort();
+
+    let mut problems = Vec::new();
+    for dir in &dirs {
+        let package = dir.file_name().unwrap().to_string_lossy().to_string();
+        let entry = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.packages.iter().find(|entry| entry.name == package));
+        let mut found: Vec<String> = folder_problem(dir, manifest.as_ref(), entry)?
+            .into_iter()
+            .collect();
+        if found.is_empty() {
+            for file in entry.into_iter().flat_map(|entry| &entry.files) {
+                let Some(expected) = &file.sha256 else {
+                    continue;
+                };
+                match sha256_file(&dir.join(&file.name)) {
+                    Ok(actual) if &actual == expected => {}
+                    Ok(_) => found.push(format!("{} doesn't match its checksum", file.name)),
+                    Err(e) => found.push(format!("can't read {}: {}", file.name, e)),
+                }
+            }
+            let mut apks = Vec::new();
+            for path in order_split_apks(find_apk_files(dir)?) {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                match axml::read_apk_info(&path) {
+                    Ok(info) => apks.push((name, info)),
+                    Err(e) => found.push(format!("can't read the manifest of {}: {}", name, e)),
+                }
+            }
+            found.extend(split_set_problems(&apks));
+        }
+        if !found.is_empty() {
+            problems.push(FolderProblem {
+                package,
+                problem: found.join("; "),
+                action: None,
+            });
+        }
+    }
+
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&problems)?);
+    } else {
+        for problem in &problems {
+            failure!("  ✗ {}: {}", problem.package, problem.problem);
+        }
+        status!(
+            "{} of {} package(s) would fail to install.",
+            problems.len(),
+            dirs.len()
+        );
+    }
+
+    if !problems.is_empty() {
+        return Err(AppError::PackagesFailed(format!(
+            "{} package(s) would fail to install",
+            problems.len()
+        )));
+    }
+    Ok(())
+}
+
+/// What would make `install-multiple` refuse a package's APKs, given each
+/// file name with its parsed manifest: there must be exactly one base, every
+/// split must share the base's package name and versionCode, split names
+/// must be unique, a config split's feature split must be present, and the
+/// split types an APK requires must be provided by the set.
+fn split_set_problems(apks: &[(String, ApkInfo)]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let bases: Vec<_> = apks
+        .iter()
+        .filter(|(_, info)| info.split.is_none())
+        .collect();
+    let base = match bases[..] {
+        [] => {
+            problems.push("no base APK".to_string());
+            None
+        }
+        [(_, base)] => Some(base),
+        _ => {
+            let names: Vec<_> = bases.iter().map(|(name, _)| name.as_str()).collect();
+            problems.push(format!("more than one base APK: {}", names.join(", ")));
+            None
+        }
+    };
+
+    if let Some(base) = base {
+        for (name, info) in apks {
+            if info.package != base.package {
+                problems.push(format!(
+                    "{} is for package {}, the base for {}",
+                    name,
+                    info.package.as_deref().unwrap_or("(none)"),
+                    base.package.as_deref().unwrap_or("(none)")
+                ));
+            }
+            if info.version_code != base.version_code {
+                let code = |code: Option<u64>| code.map_or("none".to_string(), |c| c.to_string());
+                problems.push(format!(
+                    "{} has versionCode {}, the base has {}",
+                    name,
+                    code(info.version_code),
+                    code(base.version_code)
+                ));
+            }
+        }
+    }
+
+    let mut splits = HashSet::new();
+    for (name, info) in apks {
+        if let Some(split) = &info.split
+            && !splits.insert(split.as_str())
+        {
+            problems.push(format!("{} repeats split {}", name, split));
+        }
+    }
+    for (name, info) in apks {
+        if let Some(feature) = &info.config_for_split
+            && !feature.is_empty()
+            && !splits.contains(feature.as_str())
+        {
+            problems.push(format!(
+                "{} is a config split for {}, which is missing",
+                name, feature
+            ));
+        }
+    }
+
+    let provided: HashSet<&str> = apks
+        .iter()
+        .flat_map(|(_, info)| &info.split_types)
+        .map(String::as_str)
+        .collect();
+    for (name, info) in apks {
+        let missing: Vec<_> = info
+            .required_split_types
+            .iter()
+            .filter(|kind| !provided.contains(kind.as_str()))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            problems.push(format!(
+                "{} requires split type(s) {}, which no split provides",
+                name,
+                missing.join(", ")
+            ));
+        }
+    }
+    problems
+}
+
+/// Pulls a package into `backup_dir` again for `check --repull`, keeping
+/// what its old manifest entry knew that pulling doesn't. Returns `None`
+/// if the device couldn't provide it, after printing why.
+fn repull_package(
+    adb: &dyn AdbRunner,
+    backup_dir: &Path,
+    package: &str,
+    version_code: Option<u64>,
+    old: Option<&PackageEntry>,
+    config: &Config,
+) -> Option<PackageEntry> {
+    let options = PullOptions {
+        preserve_timestamps: false,
+        record_stats: false,
+        splits: SplitFilter::default(),
+        retry_pm_path: false,
+        split_names: true,
+        retries: config.retries,
+        store: None,
+        stream: None,
+        progress: None,
+    };
+    let mut log = PackageLog::default();
+    let result = extract_apk(adb, package, backup_dir, &options, &mut log).map(|(files, _)| files);
+    let apk_info = result
+        .as_ref()
+        .ok()
+        .and_then(|files| read_base_apk_info(&backup_dir.join(package), files, &mut log));
+    log.print(&config.warnings);
+    if let Err(e) = &result {
+        warning!(config.warnings, "Could not pull {} again: {}", package, e);
+        return None;
+    }
+
+    let mut entry = package_entry(
+        package,
+        &backup_dir.join(package),
+        version_code,
+        old.and_then(|old| old.installer.clone()),
+        apk_info.unwrap_or_default(),
+        &result,
+    );
+    if let Some(old) = old {
+        entry.system = old.system;
+        entry.permissions = old.permissions.clone();
+    }
+    Some(entry)
+}
+
+fn run_uninstall_mode(adb: &dyn AdbRunner, args: &UninstallArgs, config: &Config) -> Result<()> {
+    connect_device(adb, &config.warnings)?;
+
+    let Some(selected_backup) = choose_backup(config, "uninstall")? else {
+        return Ok(());
+    };
+    let packages: Vec<String> = list_package_dirs(&selected_backup.path)?
+        .iter()
+        .map(|dir| dir.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+
+    if packages.is_empty() {
+        eprintln!("No packages found in backup.");
+        return Ok(());
+    }
+
+    if !args.yes {
+        status!("The following packages will be uninstalled:");
+        for package in &packages {
+            status!("  {}", package);
+        }
+        if args.keep_data {
+            status!("App data and cache will be kept.");
+        }
+        prompt!("Uninstall {} package(s)? [y/N]: ", packages.len());
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            status!("Aborted.");
+            return Ok(());
+        }
+    }
+
+    let mut failed = Vec::new();
+    for package in &packages {
+        let output = if args.keep_data {
+            adb.run(&pm_args(adb, &["uninstall"], &["-k", package]))
+        } else {
+            let mut uninstall = vec!["uninstall".to_string()];
+            if let Some(user) = adb.user() {
+                uninstall.extend(["--user".to_string(), user.to_string()]);
+            }
+            uninstall.push(package.clone());
+            adb.run(&uninstall)
+        };
+
+        match output {
+            Ok(output) if output.status.success() => status!("✓ Uninstalled {}", package),
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                failure!(
+                    "✗ Failed to uninstall {}\nstdout: {}\nstderr: {}",
+                    package,
+                    stdout.trim(),
+                    stderr.trim()
+                );
+                failed.push(package.as_str());
+            }
+            Err(e) => {
+                failure!("✗ Failed to execute adb uninstall: {}", e);
+                failed.push(package.as_str());
+            }
+        }
+    }
+
+    summary!(
+        "Uninstalled {} of {} packages.",
+        packages.len() - failed.len(),
+        packages.len()
+    );
+    if !failed.is_empty() {
+        summary!("Failed: {}", failed.join(", "));
+        return Err(AppError::PackagesFailed(format!(
+            "{} package(s) failed to uninstall",
+            failed.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Shows `items` as a numbered list under `title` and asks for one of them.
+/// Typing text instead of a number narrows the list to the items containing
+/// it, ignoring case; an empty line shows the whole list again. Numbers
+/// always refer to the full list. Returns the chosen index, or `None` at end
+/// of input.
+fn select_from<T: AsRef<str>>(items: &[T], title: &str) -> io::Result<Option<usize>> {
+    run_menu(items, title, "Enter number", |input| {
+        match input.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= items.len() => Some(n - 1),
+            _ => None,
+        }
+    })
+}
+
+/// Like [`select_from`], but takes several numbers separated by spaces or
+/// commas.
+fn select_many_from<T: AsRef<str>>(items: &[T], title: &str) -> io::Result<Option<Vec<usize>>> {
+    run_menu(
+        items,
+        title,
+        "Enter numbers separated by spaces or commas",
+        |input| {
+            let indices: Vec<usize> = input
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|part| !part.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?;
+            (!indices.is_empty() && indices.iter().all(|&n| n >= 1 && n <= items.len()))
+                .then(|| indices.iter().map(|n| n - 1).collect())
+        },
+    )
+}
+
+/// The prompt loop behind [`select_from`] and [`select_many_from`]. Input
+/// that `parse` accepts is the answer; anything else is taken as a filter.
+fn run_menu<T: AsRef<str>, R>(
+    items: &[T],
+    title: &str,
+    prompt: &str,
+    parse: impl Fn(&str) -> Option<R>,
+) -> io::Result<Option<R>> {
+    let show = |filter: &str| {
+        let filter = filter.to_lowercase();
+        let mut shown = 0;
+        for (i, item) in items.iter().enumerate() {
+            if item.as_ref().to_lowercase().contains(&filter) {
+                prompt!("{}: {}\n", i + 1, item.as_ref());
+                shown += 1;
+            }
+        }
+        shown
+    };
+
+    prompt!("{}\n", title);
+    show("");
+
+    loop {
+        prompt!("{} (or text to filter): ", prompt);
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let input = line.trim();
+        if let Some(answer) = parse(input) {
+            return Ok(Some(answer));
+        }
+        if show(input) == 0 {
+            eprintln!(
+                "Invalid selection. No entries match {:?}; press Enter to list them all.",
+                input
+            );
+        }
+    }
+}
+
+/// Measures pull throughput by repeatedly pulling the same file into a
+/// scratch directory, outside of the backup tree.
+fn run_bench_mode(adb: &dyn AdbRunner, args: &BenchArgs, config: &Config) -> Result<()> {
+    connect_device(adb, &config.warnings)?;
+
+    let runs = args.runs;
+
+    let remote_path = if let Some(size_mib) = args.size {
+        status!("Generating {} MiB of test data on the device...", size_mib);
+        let output = adb.run(&[
+            "shell",
+            "dd",
+            "if=/dev/urandom",
+            &format!("of={}", DEVICE_BENCH_FILE),
+            "bs=1048576",
+            &format!("count={}", size_mib),
+        ])?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to generate test data: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        DEVICE_BENCH_FILE.to_string()
+    } else {
+        let package = match &args.package {
+            Some(package) => package.clone(),
+            None => get_third_party_packages(adb)?
+                .into_iter()
+                .next()
+                .ok_or("No third-party packages to benchmark with. Use --size instead.")?,
+        };
+        // pm path lists the base APK first, which is the largest file for most apps.
+        let paths = get_package_paths(adb, &package)?;
+        status!("Benchmarking with {} ({})", package, paths[0]);
+        paths[0].clone()
+    };
+
+    let scratch_dir = TempDir::new(&config.temp_dir, "apktool-bench")?;
+    let local_path = scratch_dir.path().join("bench.bin");
+
+    let mut throughputs = Vec::new();
+    for run in 1..=runs {
+        let started = Instant::now();
+        let output = adb.run(&[
+            OsStr::new("pull"),
+            OsStr::new(&remote_path),
+            local_path.as_os_str(),
+        ])?;
+        let elapsed = started.elapsed().as_secs_f64();
+
+        if !output.status.success() {
+            warning!(
+                config.warnings,
+                "  Run {} failed: {}",
+                run,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            continue;
+        }
+
+        let bytes = fs::metadata(&local_path)?.len();
+        let mib_per_sec = bytes as f64 / (1024.0 * 1024.0) / elapsed.max(f64::EPSILON);
+        status!(
+            "  Run {}/{}: {:.1} MiB in {:.2}s ({:.1} MiB/s)",
+            run,
+            runs,
+            bytes as f64 / (1024.0 * 1024.0),
+            elapsed,
+            mib_per_sec
+        );
+        throughputs.push(mib_per_sec);
+    }
+
+    drop(scratch_dir);
+    if args.size.is_some() {
+        let _ = adb.run(&["shell", "rm", "-f", DEVICE_BENCH_FILE]);
+    }
+
+    if throughputs.is_empty() {
+        return Err("All benchmark runs failed.".into());
+    }
+
+    let count = throughputs.len() as f64;
+    let min = throughputs.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = throughputs
+        .iter()
+        .cloned()
+        .fold(f64::NEG_INFINITY, f64::max);
+    let avg = throughputs.iter().sum::<f64>() / count;
+    let variance = throughputs.iter().map(|t| (t - avg).powi(2)).sum::<f64>() / count;
+    status!(
+        "Throughput: min {:.1} / avg {:.1} / max {:.1} MiB/s (stddev {:.1})",
+        min,
+        avg,
+        max,
+        variance.sqrt()
+    );
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ListedPackage {
+    name: String,
+    system: bool,
+    version_code: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_name: Option<String>,
+    /// The version code in the `--outdated` backup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_version_code: Option<u64>,
+}
+
+/// Prints the packages installed on the device. Read-only: nothing is
+/// written to disk.
+fn run_list_mode(adb: &dyn AdbRunner, args: &ListArgs, config: &Config) -> Result<()> {
+    connect_device(adb, &config.warnings)?;
+    if args.stream {
+        return stream_list(adb, args);
+    }
+
+    let mut names: Vec<(String, bool)> = get_third_party_packages(adb)?
+        .into_iter()
+        .map(|name| (name, false))
+        .collect();
+    if args.system {
+        names.extend(
+            get_packages(adb, "-s")?
+                .into_iter()
+                .map(|name| (name, true)),
+        );
+    }
+    names.sort();
+
+    // With --outdated, only packages in the backup, whose version codes
+    // come from its manifest and those of its bases.
+    let backup_versions: Option<HashMap<String, Option<u64>>> = match &args.outdated {
+        Some(backup) => {
+            let chain = open_backup_chain(config, backup)?;
+            let versions = chain_packages(&chain)
+                .into_iter()
+                .map(|(name, (_, entry))| (name, entry.version_code))
+                .collect();
+            Some(versions)
+        }
+        None => None,
+    };
+    if let Some(backup_versions) = &backup_versions {
+        names.retain(|(name, _)| backup_versions.contains_key(name));
+    }
+
+    let all_names: Vec<String> = names.iter().map(|(name, _)| name.clone()).collect();
+    let version_codes = get_version_codes(adb, &all_names);
+    if let Some(backup_versions) = &backup_versions {
+        names.retain(|(name, _)| {
+            let device = version_codes.get(name);
+            let backup = backup_versions.get(name).copied().flatten();
+            device
+                .zip(backup)
+                .is_some_and(|(device, backup)| *device > backup)
+        });
+    }
+
+    let packages: Vec<ListedPackage> = names
+        .into_iter()
+        .map(|(name, system)| ListedPackage {
+            backup_version_code: backup_versions
+                .as_ref()
+                .and_then(|versions| versions.get(&name).copied().flatten()),
+            version_code: version_codes.get(&name).copied(),
+            version_name: if args.details {
+                dumpsys_package(adb, &name).and_then(|dumpsys| parse_dumpsys_version_name(&dumpsys))
+            } else {
+                None
+            },
+            name,
+            system,
+        })
+        .collect();
+
+    if output::json() {
+        println!("{}", serde_json::to_string_pretty(&packages)?);
+        return Ok(());
+    }
+
+    let width = packages
+        .iter()
+        .map(|package| package.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("PACKAGE".len());
+    status!("{}", list_header(args, width));
+    for package in &packages {
+        status!("{}", list_row(args, package, width));
+    }
+    match &args.outdated {
+        Some(backup) => status!("{} package(s) newer than in {}", packages.len(), backup),
+        None => status!("{} package(s)", packages.len()),
+    }
+
+    Ok(())
+}
+
+/// `list --stream`: prints each package as `pm` reports it. Version codes
+/// still come from one lookup up front, which is a single adb call on
+/// current devices.
+fn stream_list(adb: &dyn AdbRunner, args: &ListArgs) -> Result<()> {
+    let json = output::json();
+    let version_codes = get_version_codes(adb, &[]);
+    if !json {
+        status!("{}", list_header(args, STREAM_LIST_WIDTH));
+    }
+
+    let mut count = 0;
+    let filters: &[(&str, bool)] = if args.system {
+        &[("-3", false), ("-s", true)]
+    } else {
+        &[("-3", false)]
+    };
+    for &(filter, system) in filters {
+        stream_packages(adb, filter, &mut |name| {
+            let package = ListedPackage {
+                version_code: version_codes
+                    .get(&name)
+                    .copied()
+                    .or_else(|| get_dumpsys_version_code(adb, &name)),
+                version_name: if args.details {
+                    dumpsys_package(adb, &name)
+                        .and_then(|dumpsys| parse_dumpsys_version_name(&dumpsys))
+                } else {
+                    None
+                },
+                name,
+                system,
+                backup_version_code: None,
+            };
+            if json {
+                println!("{}", serde_json::to_string(&package)?);
+            } else {
+                status!("{}", list_row(args, &package, STREAM_LIST_WIDTH));
+            }
+            count += 1;
+            Ok(())
+        })?;
+    }
+    if !json {
+        status!("{} package(s)", count);
+    }
+
+    Ok(())
+}
+
+fn list_header(args: &ListArgs, width: usize) -> String {
+    let mut header = format!("{:<width$}  {:>12}", "PACKAGE", "VERSION CODE");
+    if args.outdated.is_some() {
+        header.push_str(&format!("  {:>12}", "IN BACKUP"));
+    }
+    if args.details {
+        header.push_str("  VERSION NAME");
+    }
+    if args.system {
+        header.push_str("  TYPE");
+    }
+    header.trim_end().to_string()
+}
+
+fn list_row(args: &ListArgs, package: &ListedPackage, width: usize) -> String {
+    let version_code = package
+        .version_code
+        .map(|code| code.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let mut line = format!("{:<width$}  {:>12}", package.name, version_code);
+    if args.outdated.is_some() {
+        let backup_version_code = package
+            .backup_version_code
+            .map(|code| code.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        line.push_str(&format!("  {:>12}", backup_version_code));
+    }
+    if args.details {
+        line.push_str(&format!(
+            "  {:<12}",
+            package.version_name.as_deref().unwrap_or("-")
+        ));
+    }
+    if args.system {
+        line.push_str(if package.system {
+            "  system"
+        } else {
+            "  third-party"
+        });
+    }
+    line.trim_end().to_string()
+}
+
+/// Deletes backups that Ctrl-C stopped, `.part` files left by streamed
+/// backups that never finished, and with `--older-than` old backups, after
+/// listing them. The newest backup and bases of kept differential backups
+/// are kept.
+/// How recently a partial archive must have been written for `clean` to
+/// leave it alone as one a running backup is still adding to.
+const PART_FILE_IN_USE: Duration = Duration::from_secs(10 * 60);
+
+fn run_clean_mode(args: &CleanArgs, config: &Config) -> Result<()> {
+    let backup_root = config.backup_dir.as_path();
+    if !backup_root.exists() {
+        status!("Nothing to clean.");
+        return Ok(());
+    }
+    let cutoff = args.older_than.map(|since| match since {
+        Since::Ago(duration) => (Local::now() - duration).timestamp(),
+        Since::At(at) => at
+            .and_local_timezone(Local)
+            .earliest()
+            .map_or(i64::MIN, |at| at.timestamp()),
+    });
+
+    let mut backups: Vec<PathBuf> = fs::read_dir(backup_root)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|path| (path.is_dir() && !store::is_store(path)) || is_archive(path))
+        .collect();
+    // Newest first.
+    backups.sort_by_cached_key(|path| Reverse((backup_time(path), path.clone())));
+    let latest = backups.first().cloned();
+
+    let mut doomed: Vec<(PathBuf, &str)> = Vec::new();
+    for path in &backups {
+        if latest.as_ref() == Some(path) && !args.include_latest {
+            continue;
+        }
+        let partial = path.is_dir()
+            && Manifest::load(path)
+                .ok()
+                .flatten()
+                .is_some_and(|manifest| manifest.partial);
+        if partial {
+            doomed.push((path.clone(), "interrupted"));
+        } else if cutoff.is_some_and(|cutoff| backup_time(path).is_some_and(|time| time < cutoff)) {
+            doomed.push((path.clone(), "old"));
+        }
+    }
+    // A kept differential backup still needs its bases.
+    let bases: HashSet<PathBuf> = backups
+        .iter()
+        .filter(|path| !doomed.iter().any(|(doomed, _)| doomed == *path))
+        .flat_map(|path| base_chain(path).into_iter().skip(1))
+        .map(|(dir, _)| dir)
+        .collect();
+    doomed.retain(|(path, _)| {
+        let keep = bases.contains(path);
+        if keep {
+            status!(
+                "Keeping {} (a base of a kept differential backup)",
+                path.file_name().unwrap().to_string_lossy()
+            );
+        }
+        !keep
+    });
+    let part_suffix = format!("{}.part", archive::ARCHIVE_EXTENSION);
+    for dir in [backup_root, config.temp_dir.as_path()] {
+        let part_files: Vec<PathBuf> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().contains(&part_suffix))
+            })
+            .collect();
+        // A `--stream` backup writes its `.part` archive and, while an APK
+        // comes in, a `.part.<n>` scratch file beside it.
+        let archive_of = |path: &Path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            let end = name.find(&part_suffix).unwrap() + part_suffix.len();
+            name[..end].to_string()
+        };
+        let recently_written: HashSet<String> = part_files
+            .iter()
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified.elapsed().unwrap_or_default() < PART_FILE_IN_USE)
+            })
+            .map(|path| archive_of(path))
+            .collect();
+        for path in part_files {
+            if recently_written.contains(&archive_of(&path)) {
+                status!(
+                    "Keeping {} (written in the last {} minutes; a --stream backup may still be running)",
+                    path.display(),
+                    PART_FILE_IN_USE.as_secs() / 60
+                );
+            } else {
+                doomed.push((path, "unfinished archive"));
+            }
+        }
+    }
+
+    if doomed.is_empty() {
+        status!("Nothing to clean.");
+        return Ok(());
+    }
+    let mut total = 0;
+    for (path, reason) in &doomed {
+        let size = disk_size(path);
+        total += size;
+        let date = backup_time(path)
+            .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+            .map(|time| {
+                time.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "-".to_string());
+        status!(
+            "  {}  {:>10}  {} ({})",
+            date,
+            progress::format_size(size),
+            path.display(),
+            reason
+        );
+    }
+    summary!(
+        "{} item(s), {} to reclaim.",
+        doomed.len(),
+        progress::format_size(total)
+    );
+    if args.dry_run {
+        status!("Dry run: nothing deleted.");
+        return Ok(());
+    }
+    if !args.yes {
+        prompt!("Delete them? [y/N]: ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            status!("Aborted.");
+            return Ok(());
+        }
+    }
+
+    for (path, _) in &doomed {
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    status!(
+        "✓ Deleted {} item(s), reclaimed {}",
+        doomed.len(),
+        progress::format_size(total)
+    );
+    prune_store(backup_root, &config.warnings);
+    Ok(())
+}
+
+/// Bytes used by a file, or everything in a folder.
+fn disk_size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|e| disk_size(&e.path()))
+            .sum(),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Describes the tools that wrote a backup, for install and verify.
+fn describe_created_by(tool: &ToolInfo) -> String {
+    format!(
+        "apktool {} with adb {} on {}",
+        tool.apktool,
+        tool.adb.as_deref().unwrap_or("unknown"),
+        tool.host_os
+    )
+}
+
+/// Quotes `value` for the device's shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::StreamArchive;
+    use crate::events::Event;
+    use crate::testing::{FakeAdb, fake_entry, fake_manifest};
+    use std::fs::File;
+    use std::io::Read;
+
+    #[test]
+    fn find_backup_reports_missing_backup() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-find-backup").unwrap();
+        assert!(matches!(
+            find_backup(root.path(), "missing"),
+            Err(AppError::BackupNotFound(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn open_backup_chain_stops_at_a_looping_base() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-looping-base").unwrap();
+        for (name, base, package) in [("a", "b", "com.example.a"), ("b", "a", "com.example.b")] {
+            let dir = root.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            let manifest = Manifest {
+                base: Some(base.to_string()),
+                ..fake_manifest(vec![fake_entry(package, PackageStatus::Ok, &[])])
+            };
+            manifest.save(&dir).unwrap();
+        }
+
+        let mut config = resolve_config(ConfigFile::default(), None).unwrap();
+        config.backup_dir = root.path().to_path_buf();
+        let chain = open_backup_chain(&config, "a").unwrap();
+        let mut packages: Vec<_> = chain_packages(&chain).into_keys().collect();
+        packages.sort();
+        assert_eq!(packages, ["com.example.a", "com.example.b"]);
+    }
+
+    #[test]
+    fn clean_deletes_interrupted_backups_but_not_the_latest() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-clean").unwrap();
+        for (name, partial) in [
+            ("b-20200101000000", false),
+            ("b-20210101000000", true),
+            ("b-20220101000000", true),
+        ] {
+            let dir = root.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            let manifest = Manifest {
+                partial,
+                ..fake_manifest(Vec::new())
+            };
+            manifest.save(&dir).unwrap();
+        }
+        let stale = File::create(root.path().join("b.tar.zst.part")).unwrap();
+        stale
+            .set_modified(SystemTime::now() - 2 * PART_FILE_IN_USE)
+            .unwrap();
+        drop(stale);
+        fs::write(root.path().join("c.tar.zst.part"), "x").unwrap();
+        fs::write(root.path().join("c.tar.zst.part.0"), "x").unwrap();
+
+        let mut config = resolve_config(ConfigFile::default(), None).unwrap();
+        config.backup_dir = root.path().to_path_buf();
+        config.temp_dir = root.path().join("temp");
+        let args = CleanArgs {
+            older_than: None,
+            include_latest: false,
+            dry_run: false,
+            yes: true,
+        };
+        run_clean_mode(&args, &config).unwrap();
+
+        assert!(root.path().join("b-20200101000000").is_dir());
+        assert!(!root.path().join("b-20210101000000").exists());
+        assert!(root.path().join("b-20220101000000").is_dir());
+        assert!(!root.path().join("b.tar.zst.part").exists());
+        assert!(root.path().join("c.tar.zst.part").exists());
+        assert!(root.path().join("c.tar.zst.part.0").exists());
+    }
+
+    #[test]
+    fn diff_export_copies_only_changed_packages() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-diff-export").unwrap();
+        for (name, packages) in [
+            ("a", &[("com.example.one", 1)][..]),
+            ("b", &[("com.example.one", 1), ("com.example.two", 1)][..]),
+        ] {
+            let dir = root.path().join(name);
+            let mut entries = Vec::new();
+            for (package, version) in packages {
+                fs::create_dir_all(dir.join(package)).unwrap();
+                fs::write(dir.join(package).join("base.apk"), package).unwrap();
+                entries.push(PackageEntry {
+                    version_code: Some(*version),
+                    ..fake_entry(package, PackageStatus::Ok, &["base.apk"])
+                });
+            }
+            let manifest = Manifest {
+                device: DeviceInfo {
+                    serial: Some("S".to_string()),
+                    ..DeviceInfo::default()
+                },
+                ..fake_manifest(entries)
+            };
+            manifest.save(&dir).unwrap();
+        }
+
+        let mut config = resolve_config(ConfigFile::default(), None).unwrap();
+        config.backup_dir = root.path().to_path_buf();
+        let args = DiffArgs {
+            old: "a".to_string(),
+            new: "b".to_string(),
+            files: false,
+            export: Some("c".to_string()),
+        };
+        run_diff_mode(&args, &config).unwrap();
+
+        let exported = root.path().join("c");
+        let manifest = Manifest::load(&exported).unwrap().unwrap();
+        assert_eq!(manifest.base.as_deref(), Some("a"));
+        assert_eq!(manifest.device.serial.as_deref(), Some("S"));
+        let names: Vec<_> = manifest.packages.iter().map(|entry| &entry.name).collect();
+        assert_eq!(names, ["com.example.two"]);
+        assert!(exported.join("com.example.two").join("base.apk").is_file());
+        assert!(!exported.join("com.example.one").exists());
+    }
+
+    #[test]
+    fn backup_time_uses_last_timestamp_in_name() {
+        let time = |name: &str| backup_time(Path::new("/nonexistent").join(name).as_path());
+        let made = time("20240101120000_diff_20250401000000");
+        assert!(made.is_some());
+        assert_eq!(made, time("20250401000000"));
+        assert_eq!(time("pixel-20250401000000.tar.zst"), made);
+        // Not a date, and no folder to fall back on.
+        assert_eq!(time("20251341000000"), None);
+        assert_eq!(time("manual"), None);
+    }
+
+    #[test]
+    fn progress_events_are_tagged_json_lines() {
+        let event = Event::PackageStarted {
+            package: "com.example",
+            index: 2,
+            total: 5,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"package_started","package":"com.example","index":2,"total":5}"#
+        );
+        let event = Event::PackageCompleted {
+            package: "com.example",
+            bytes: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"package_completed","package":"com.example"}"#
+        );
+    }
+
+    #[test]
+    fn package_list_skips_blanks_comments_and_repeats() {
+        let list = "# apps I keep\ncom.example.one\n\n  com.example.two  # work\ncom.example.one\n";
+        assert_eq!(
+            parse_package_list(list).unwrap(),
+            ["com.example.one", "com.example.two"]
+        );
+        assert!(parse_package_list("com.example.one\nnot a package\n").is_err());
+    }
+
+    #[test]
+    fn read_report_list_takes_packages_and_failures() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-report").unwrap();
+        let path = root.path().join("report.json");
+        fs::write(
+            &path,
+            r#"{"packages": [
+                {"name": "com.example.one", "status": "installed", "files": []},
+                {"name": "com.example.two", "status": "failed", "files": [], "error": "boom"}
+            ]}"#,
+        )
+        .unwrap();
+        let report = read_report_list(&path.to_string_lossy()).unwrap();
+        assert_eq!(
+            report.selected(false),
+            ["com.example.one", "com.example.two"]
+        );
+        assert_eq!(report.selected(true), ["com.example.two"]);
+
+        fs::write(&path, r#"{"schema_version": 2}"#).unwrap();
+        assert!(read_report_list(&path.to_string_lossy()).is_err());
+    }
+
+    #[test]
+    fn manifest_load_upgrades_v1_and_refuses_newer_versions() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-schema").unwrap();
+        fs::write(
+            root.path().join(manifest::MANIFEST_FILE),
+            r#"{"schema_version": 1, "created_at": "", "device": {"serial": null, "model": null},
+                "packages": [{"name": "com.example.one", "status": "ok", "files": ["base.apk"], "total_bytes": 0}]}"#,
+        )
+        .unwrap();
+        let manifest = Manifest::load(root.path()).unwrap().unwrap();
+        assert_eq!(manifest.schema_version, SCHEMA_VERSION);
+        assert_eq!(manifest.packages[0].files[0].name, "base.apk");
+        assert_eq!(manifest.packages[0].files[0].sha256, None);
+
+        fs::write(
+            root.path().join(manifest::MANIFEST_FILE),
+            format!(
+                r#"{{"schema_version": {}, "created_at": "", "device": {{}}, "packages": []}}"#,
+                SCHEMA_VERSION + 1
+            ),
+        )
+        .unwrap();
+        let error = Manifest::load(root.path()).err().unwrap();
+        assert!(error.to_string().contains("upgrade apktool"), "{}", error);
+    }
+
+    #[test]
+    fn folder_problem_flags_empty_and_incomplete_folders() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-check").unwrap();
+        let manifest = fake_manifest(vec![
+            fake_entry(
+                "com.example.split",
+                PackageStatus::Ok,
+                &["base.apk", "split_config.en.apk"],
+            ),
+            fake_entry("com.example.failed", PackageStatus::Failed, &[]),
+        ]);
+        let problem = |package: &str, files: &[&str]| {
+            let dir = root.path().join(package);
+            fs::create_dir_all(&dir).unwrap();
+            for file in files {
+                fs::write(dir.join(file), "").unwrap();
+            }
+            let entry = manifest.packages.iter().find(|entry| entry.name == package);
+            folder_problem(&dir, Some(&manifest), entry).unwrap()
+        };
+
+        assert_eq!(
+            problem("com.example.split", &["base.apk"]).unwrap(),
+            "has 1 APK(s), the manifest lists 2"
+        );
+        assert_eq!(problem("com.example.split", &["split_config.en.apk"]), None);
+        assert_eq!(problem("com.example.failed", &[]).unwrap(), "no APK files");
+        assert_eq!(
+            problem("com.example.failed", &["base.apk"]).unwrap(),
+            "the manifest records it as failed"
+        );
+        assert_eq!(
+            problem("com.example.stray", &["base.apk"]).unwrap(),
+            "not in the manifest"
+        );
+    }
+
+    #[test]
+    fn split_set_problems_flag_sets_install_multiple_would_refuse() {
+        let apk = |name: &str, package: &str, split: Option<&str>, version_code| {
+            (
+                name.to_string(),
+                ApkInfo {
+                    package: Some(package.to_string()),
+                    split: split.map(String::from),
+                    version_code: Some(version_code),
+                    ..Default::default()
+                },
+            )
+        };
+        let mut base = apk("base.apk", "com.example", None, 7);
+        base.1.required_split_types = vec!["language".to_string()];
+        let mut language = apk("split_config.en.apk", "com.example", Some("config.en"), 7);
+        language.1.split_types = vec!["language".to_string()];
+        assert!(split_set_problems(&[base.clone(), language.clone()]).is_empty());
+
+        assert_eq!(
+            split_set_problems(std::slice::from_ref(&language)),
+            ["no base APK"]
+        );
+        assert_eq!(
+            split_set_problems(std::slice::from_ref(&base)),
+            ["base.apk requires split type(s) language, which no split provides"]
+        );
+        let stale = apk("split_config.de.apk", "com.example", Some("config.de"), 6);
+        let other = apk("split_other.apk", "com.other", Some("other"), 7);
+        assert_eq!(
+            split_set_problems(&[base.clone(), language.clone(), stale, other]),
+            [
+                "split_config.de.apk has versionCode 6, the base has 7",
+                "split_other.apk is for package com.other, the base for com.example",
+            ]
+        );
+        let mut feature_config = apk(
+            "split_config.xxhdpi.apk",
+            "com.example",
+            Some("maps.config.xxhdpi"),
+            7,
+        );
+        feature_config.1.config_for_split = Some("maps".to_string());
+        assert_eq!(
+            split_set_problems(&[base, language, feature_config]),
+            ["split_config.xxhdpi.apk is a config split for maps, which is missing"]
+        );
+    }
+
+    #[test]
+    fn stream_archive_keeps_only_complete_entries() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-stream").unwrap();
+        let backup_dir = root.path().join("backup");
+        fs::create_dir_all(&backup_dir).unwrap();
+        fs::write(backup_dir.join(manifest::MANIFEST_FILE), "{}").unwrap();
+
+        let scratch = root.path().join("scratch");
+        fs::create_dir_all(&scratch).unwrap();
+        let archive = StreamArchive::create(&backup_dir, 3, &scratch).unwrap();
+        let entry = Path::new("com.example").join("base.apk");
+        let data = vec![7; 1000];
+        archive
+            .append(&entry, 1000, &mut data.as_slice(), &archive.scratch_path())
+            .unwrap();
+        let short = Path::new("com.example").join("split_a.apk");
+        assert!(
+            archive
+                .append(&short, 1000, &mut &data[..10], &archive.scratch_path())
+                .is_err()
+        );
+        let archive_path = archive.finish(&backup_dir).unwrap();
+        assert_eq!(archive_path.parent(), Some(root.path()));
+        assert_eq!(fs::read_dir(&scratch).unwrap().count(), 0);
+
+        let unpacked = TempDir::new(&std::env::temp_dir(), "apktool-test-stream-unpack").unwrap();
+        let unpacked_dir = archive::unpack(&archive_path, unpacked.path()).unwrap();
+        assert_eq!(fs::read(unpacked_dir.join(&entry)).unwrap(), data);
+        assert!(!unpacked_dir.join(&short).exists());
+        assert!(unpacked_dir.join(manifest::MANIFEST_FILE).is_file());
+    }
+
+    #[test]
+    fn bundle_pack_writes_sai_apks_that_unpack_again() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-bundle").unwrap();
+        let apks = vec![
+            root.path().join("base.apk"),
+            root.path().join("split_config.en.apk"),
+        ];
+        for apk in &apks {
+            fs::write(apk, apk.file_name().unwrap().as_encoded_bytes()).unwrap();
+        }
+        let info = ApkInfo {
+            version_code: Some(7),
+            ..Default::default()
+        };
+        let output = root.path().join("app.apks");
+        bundle::pack(
+            &apks,
+            &info,
+            "com.example",
+            &output,
+            BundleFormat::for_path(&output),
+        )
+        .unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut meta = String::new();
+        zip.by_name("meta.sai_v2.json")
+            .unwrap()
+            .read_to_string(&mut meta)
+            .unwrap();
+        let meta: serde_json::Value = serde_json::from_str(&meta).unwrap();
+        assert_eq!(meta["package"], "com.example");
+        assert_eq!(meta["version_code"], 7);
+        assert_eq!(meta["split_apk"], true);
+        assert!(zip.by_name("manifest.json").is_err());
+
+        let unpacked = root.path().join("unpacked");
+        let package_dir = bundle::unpack(&output, &unpacked).unwrap();
+        assert_eq!(fs::read(package_dir.join("base.apk")).unwrap(), b"base.apk");
+        assert!(package_dir.join("split_config.en.apk").is_file());
+    }
+
+    #[test]
+    fn remote_reads_host_and_path() {
+        let remote: remote::Remote = "me@server:/srv/apks/".parse().unwrap();
+        assert_eq!(remote.to_string(), "me@server:/srv/apks");
+        assert_eq!(remote.name(), Some("apks"));
+        assert!("server:".parse::<remote::Remote>().is_err());
+        assert!(":/srv".parse::<remote::Remote>().is_err());
+        assert!("./local:dir".parse::<remote::Remote>().is_err());
+        assert!("-oProxyCommand=x:/srv".parse::<remote::Remote>().is_err());
+    }
+
+    #[test]
+    fn library_entry_points_fail_instead_of_prompting() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-no-prompt").unwrap();
+        let mut config = resolve_config(ConfigFile::default(), None).unwrap();
+        config.backup_dir = root.path().to_path_buf();
+        let adb = FakeAdb::default();
+
+        let args = BackupArgs::parse_from(["--dry-run"]).unwrap();
+        let error = backup(&adb, &args, &config).err().unwrap();
+        assert!(error.to_string().contains("--auto-base"), "{}", error);
+
+        let args = InstallArgs::parse_from(["--yes"]).unwrap();
+        let error = install(&adb, &args, &config).err().unwrap();
+        assert!(error.to_string().contains("Name the backup"), "{}", error);
+        let args = InstallArgs::parse_from(["--select", "x"]).unwrap();
+        assert!(install(&adb, &args, &config).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Version 2 records a SHA-256 per APK file; version 1 only had file names.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Machine-readable record of what a backup folder contains, written to
+/// `manifest.json` at its root.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub schema_version: u32,
+    /// When the backup ran, in RFC 3339.
+    pub created_at: String,
+    /// The apktool and adb that wrote the backup. Missing from manifests
+    /// written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<ToolInfo>,
+    /// Folder name of the base backup for differential backups, which only
+    /// hold packages that changed since the base.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    pub device: DeviceInfo,
+    /// Ctrl-C stopped the backup before every package was started, so it
+    /// lacks packages that `backup --resume` can still add.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    /// Warnings printed before the manifest was written.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub warnings: usize,
+    /// The backup ran under `--strict` and printed warnings, so it failed
+    /// even if every package was backed up.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict_failed: bool,
+    pub packages: Vec<PackageEntry>,
+}
+
+pub(crate) fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ToolInfo {
+    /// apktool's crate version.
+    pub apktool: String,
+    /// `adb version`'s version, with the platform-tools release when adb
+    /// prints one, e.g. `1.0.41 (platform-tools 34.0.5-10900879)`.
+    pub adb: Option<String>,
+    /// Host OS and architecture, e.g. `linux x86_64`.
+    pub host_os: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct DeviceInfo {
+    pub serial: Option<String>,
+    pub model: Option<String>,
+    /// Primary ABI (`ro.product.cpu.abi`), e.g. `arm64-v8a`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abi: Option<String>,
+    /// API level (`ro.build.version.sdk`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdk: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PackageEntry {
+    pub name: String,
+    pub status: PackageStatus,
+    /// Version code of the backed-up APK, or the installed version code the
+    /// device reported when the APK couldn't be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_code: Option<u64>,
+    /// Version name, min and target SDK read from the base APK's manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_sdk: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_sdk: Option<u32>,
+    /// Package that installed the app (e.g. `com.android.vending`), if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installer: Option<String>,
+    /// Preinstalled with the system image, backed up with `--include-system`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub system: bool,
+    /// Runtime permissions the app had been granted, recorded with
+    /// `--with-permissions` and re-granted by `install --restore-permissions`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    /// APK files inside the package folder.
+    pub files: Vec<ApkFile>,
+    /// Config splits the device had that `--keep-abi` or `--keep-density`
+    /// left out, so the package was backed up as a reduced set on purpose.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped_splits: Vec<String>,
+    pub total_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApkFile {
+    pub name: String,
+    /// Hex SHA-256 of the file as pulled. Missing for schema version 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Size and modification time (RFC 3339) of the APK on the device, as
+    /// `stat` reported them. The size is recorded with `--record-file-stats`
+    /// or `--stream`, and the time with `--record-file-stats` or
+    /// `--preserve-timestamps`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_modified_at: Option<String>,
+    /// Split name from the APK's own manifest. Missing for the base APK and
+    /// for backups made before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split: Option<String>,
+    /// The file's name on the device, when it was saved under its split
+    /// name instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageStatus {
+    Ok,
+    Failed,
+    /// Not backed up because `pm path` listed no APKs for it.
+    Skipped,
+}
+
+impl Manifest {
+    /// Reads the manifest of a backup folder, if it has one.
+    pub fn load(backup_dir: &Path) -> io::Result<Option<Self>> {
+        let path = backup_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+        let mut value: Value = serde_json::from_str(&contents).map_err(invalid)?;
+        let version = value
+            .get("schema_version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| invalid(serde::de::Error::missing_field("schema_version")))?;
+        if version > u64::from(SCHEMA_VERSION) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has schema version {}, but this apktool only reads up to {}; upgrade apktool to use this backup",
+                    path.display(),
+                    version,
+                    SCHEMA_VERSION
+                ),
+            ));
+        }
+        if version == 1 {
+            upgrade_v1(&mut value);
+        }
+        serde_json::from_value(value).map(Some).map_err(invalid)
+    }
+
+    pub fn save(&self, backup_dir: &Path) -> io::Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(backup_dir.join(MANIFEST_FILE), contents)
+    }
+
+    /// Adds package entries, replacing any existing entries for the same
+    /// packages.
+    pub fn merge_packages(&mut self, packages: Vec<PackageEntry>) {
+        self.packages
+            .retain(|existing| !packages.iter().any(|new| new.name == existing.name));
+        self.packages.extend(packages);
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+/// Turns a version 1 manifest into version 2, whose files are objects
+/// rather than plain names. The hashes version 2 adds stay missing.
+fn upgrade_v1(manifest: &mut Value) {
+    let packages = manifest
+        .get_mut("packages")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+    for package in packages {
+        let files = package
+            .get_mut("files")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten();
+        for file in files {
+            if let Value::String(name) = file {
+                *file = json!({ "name": name });
+            }
+        }
+    }
+    manifest["schema_version"] = json!(SCHEMA_VERSION);
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut reader = Sha256Reader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.hex_digest())
+}
+
+/// Hashes everything read through it, for files that are copied somewhere
+/// without ever being on disk whole.
+pub struct Sha256Reader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Sha256Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hex-encoded SHA-256 of what has been read so far.
+    pub fn hex_digest(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
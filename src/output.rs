@@ -0,0 +1,89 @@
+//! Terminal output settings from `--color`, `--quiet` and `--json`.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color output going to a terminal, unless NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR_STDOUT: AtomicBool = AtomicBool::new(false);
+static COLOR_STDERR: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn init(color: ColorChoice, quiet: bool, json: bool) {
+    let (stdout, stderr) = match color {
+        ColorChoice::Always => (true, true),
+        ColorChoice::Never => (false, false),
+        ColorChoice::Auto if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) => {
+            (false, false)
+        }
+        ColorChoice::Auto => (io::stdout().is_terminal(), io::stderr().is_terminal()),
+    };
+    COLOR_STDOUT.store(stdout, Ordering::Relaxed);
+    COLOR_STDERR.store(stderr, Ordering::Relaxed);
+    QUIET.store(quiet, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Whether only failures and final summaries should be printed.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Whether stdout is reserved for a JSON document, so progress goes to
+/// stderr.
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Counts the warnings a run prints, for `--strict`.
+#[derive(Default)]
+pub struct Warnings(AtomicUsize);
+
+impl Warnings {
+    /// How many warnings have been printed so far.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Colors a line by its leading marker: green for `✓`, red for `✗` and
+/// yellow for `Warning:`. Indentation is left uncolored, and so is anything
+/// when color is off for the stream the line goes to.
+pub fn paint(line: &str, stderr: bool) -> Cow<'_, str> {
+    let enabled = if stderr { &COLOR_STDERR } else { &COLOR_STDOUT };
+    if !enabled.load(Ordering::Relaxed) {
+        return Cow::Borrowed(line);
+    }
+
+    let text = line.trim_start();
+    let color = if text.starts_with('✓') {
+        GREEN
+    } else if text.starts_with('✗') {
+        RED
+    } else if text.starts_with("Warning:") {
+        YELLOW
+    } else {
+        return Cow::Borrowed(line);
+    };
+    let indent = &line[..line.len() - text.len()];
+    Cow::Owned(format!("{}{}{}{}", indent, color, text, RESET))
+}
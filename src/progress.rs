@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const BAR_WIDTH: usize = 24;
+/// Longest status line drawn, so it doesn't wrap on narrow terminals.
+const MAX_LINE_WIDTH: usize = 120;
+
+/// Byte progress of a backup's pulls, drawn as one status line. Pulls are
+/// tracked by watching the size of the local file grow against the size the
+/// device reported for it.
+pub struct TransferProgress {
+    /// On-device size of each APK, by device path.
+    sizes: HashMap<String, u64>,
+    total_bytes: u64,
+    finished_bytes: AtomicU64,
+    active: Mutex<Vec<ActivePull>>,
+}
+
+struct ActivePull {
+    local_path: PathBuf,
+    size: u64,
+}
+
+impl TransferProgress {
+    pub fn new(sizes: HashMap<String, u64>) -> Self {
+        let total_bytes = sizes.values().sum();
+        Self {
+            sizes,
+            total_bytes,
+            finished_bytes: AtomicU64::new(0),
+            active: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Marks a pull of `device_path` into `local_path` as running.
+    pub fn start(&self, device_path: &str, local_path: &Path) {
+        let size = self.sizes.get(device_path).copied().unwrap_or(0);
+        self.active.lock().unwrap().push(ActivePull {
+            local_path: local_path.to_path_buf(),
+            size,
+        });
+    }
+
+    /// Marks a pull as done, whether or not it succeeded, so the overall bar
+    /// still reaches the end.
+    pub fn finish(&self, local_path: &Path) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(index) = active.iter().position(|pull| pull.local_path == local_path) {
+            let pull = active.remove(index);
+            self.finished_bytes.fetch_add(pull.size, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a file that didn't need pulling as done.
+    pub fn skip(&self, device_path: &str) {
+        let size = self.sizes.get(device_path).copied().unwrap_or(0);
+        self.finished_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// The status line: an overall bar followed by each running pull.
+    pub fn render(&self) -> String {
+        let active = self.active.lock().unwrap();
+        let pulled = |pull: &ActivePull| {
+            fs::metadata(&pull.local_path)
+                .map(|metadata| metadata.len().min(pull.size))
+                .unwrap_or(0)
+        };
+
+        let done =
+            self.finished_bytes.load(Ordering::Relaxed) + active.iter().map(pulled).sum::<u64>();
+        let fraction = if self.total_bytes == 0 {
+            0.0
+        } else {
+            (done as f64 / self.total_bytes as f64).min(1.0)
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+
+        let mut line = format!(
+            "[{}{}] {:>3}% {}/{}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            (fraction * 100.0) as u32,
+            format_size(done),
+            format_size(self.total_bytes)
+        );
+        for pull in active.iter() {
+            let name = pull.local_path.file_name().unwrap_or_default();
+            line.push_str(&format!(
+                " | {} {}/{}",
+                name.to_string_lossy(),
+                format_size(pulled(pull)),
+                format_size(pull.size)
+            ));
+        }
+
+        line.chars().take(MAX_LINE_WIDTH).collect()
+    }
+}
+
+/// Erases the status line so other output can be printed in its place.
+pub fn clear_line() {
+    print!("\r\x1b[2K");
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const KIB: f64 = 1024.0;
+    let bytes = bytes as f64;
+    if bytes < KIB * KIB {
+        format!("{:.1} KiB", bytes / KIB)
+    } else if bytes < KIB * KIB * KIB {
+        format!("{:.1} MiB", bytes / (KIB * KIB))
+    } else {
+        format!("{:.2} GiB", bytes / (KIB * KIB * KIB))
+    }
+}
+
+/// Formats a run time as `45s`, `6m13s` or `1h02m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, secs / 60 % 60)
+    }
+}
@@ -0,0 +1,963 @@
+//! Copying packages off the device for `backup` and `pull`: APKs, their
+//! shared storage folders and app data, and putting the last two back on
+//! install.
+
+use crate::archive::{StreamArchive, TempDir};
+use crate::backup::PackageLog;
+use crate::bundle::BundleFormat;
+use crate::config::Config;
+use crate::device::{
+    AdbRunner, DeviceFileStat, PM_PATH_RETRY_DELAY, connect_device, device_has_root,
+    get_device_output, get_package_paths, stat_device_files,
+};
+use crate::error::{AppError, Result};
+use crate::events::Event;
+use crate::install::{SplitFilter, order_split_apks};
+use crate::manifest::{ApkFile, Sha256Reader, sha256_file};
+use crate::progress::TransferProgress;
+use crate::store::ObjectStore;
+use crate::{axml, bundle, capitalize, events, shell_quote};
+use clap::Args;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const EXTERNAL_DATA_DIR: &str = "external_data";
+const DEVICE_EXTERNAL_DATA_ROOT: &str = "/sdcard/Android/data";
+const OBB_DIR: &str = "obb";
+const DEVICE_OBB_ROOT: &str = "/sdcard/Android/obb";
+/// Archive of `/data/data/<package>` written by `--with-data`.
+const APP_DATA_FILE: &str = "app_data.tar";
+const DEVICE_APP_DATA_ROOT: &str = "/data/data";
+const PULL_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Args)]
+pub(crate) struct PullArgs {
+    /// Package to pull
+    package: String,
+    /// Where to put the APKs. An app with a single APK is written to this
+    /// file (default `<package>.apk`); split APKs go into this folder
+    /// (default `<package>/`). An existing folder takes the APKs directly.
+    #[arg(long, value_name = "PATH")]
+    out: Option<PathBuf>,
+    /// Pull with `adb pull -a` so the files keep their on-device mtime
+    #[arg(long)]
+    preserve_timestamps: bool,
+    /// Write the APKs into one bundle file instead, `<package>.apks` or
+    /// `<package>.xapk` unless --out names the file
+    #[arg(long, value_enum)]
+    format: Option<BundleFormat>,
+}
+
+/// Pulls a package's APKs into `work_dir/<package>`, leaving out the
+/// config splits `options.splits` filters, and returns the pulled files and
+/// the file names of those left out.
+pub(crate) fn extract_apk(
+    adb: &dyn AdbRunner,
+    package_name: &str,
+    work_dir: &Path,
+    options: &PullOptions,
+    log: &mut PackageLog,
+) -> Result<(Vec<ApkFile>, Vec<String>)> {
+    let apk_paths = match get_package_paths(adb, package_name) {
+        Err(AppError::NoApkPaths(_)) if options.retry_pm_path => {
+            log.info("    pm path listed no APKs, asking again".to_string());
+            thread::sleep(PM_PATH_RETRY_DELAY);
+            get_package_paths(adb, package_name)?
+        }
+        result => result?,
+    };
+    let (apk_paths, dropped) = options.splits.apply(&apk_paths);
+    let dropped = split_file_names(&dropped);
+    if !dropped.is_empty() {
+        log.info(format!("    Leaving out {}", dropped.join(", ")));
+    }
+    let files = pull_apks(adb, &apk_paths, &work_dir.join(package_name), options, log)?;
+    Ok((files, dropped))
+}
+
+/// The split name in an APK's manifest, `None` for a base APK or one whose
+/// manifest can't be read.
+pub(crate) fn apk_split_name(apk: &Path) -> Option<String> {
+    axml::read_apk_info(apk).ok()?.split
+}
+
+pub(crate) fn split_file_names(apk_paths: &[String]) -> Vec<String> {
+    apk_paths
+        .iter()
+        .filter_map(|path| Path::new(path).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect()
+}
+
+/// Settings for pulling a package's APKs with [`pull_apks`].
+pub(crate) struct PullOptions<'a> {
+    /// Pass `-a` to `adb pull` to keep the device's timestamps, and record
+    /// the mtime in the manifest.
+    pub(crate) preserve_timestamps: bool,
+    /// `stat` the APKs on the device and record their size and mtime.
+    pub(crate) record_stats: bool,
+    /// Config splits to leave on the device.
+    pub(crate) splits: SplitFilter<'a>,
+    /// Ask `pm path` again when it lists no APKs.
+    pub(crate) retry_pm_path: bool,
+    /// Save each APK as `base.apk` or `split_<name>.apk`, after the split
+    /// its manifest names, rather than under its name on the device.
+    pub(crate) split_names: bool,
+    /// How many times to retry a failed pull.
+    pub(crate) retries: u32,
+    /// Store to deduplicate pulled APKs into, for `--dedup`.
+    pub(crate) store: Option<&'a ObjectStore>,
+    /// Archive to stream APKs into instead of `package_dir`, for `--stream`.
+    pub(crate) stream: Option<&'a StreamTarget>,
+    pub(crate) progress: Option<&'a TransferProgress>,
+}
+
+impl PullOptions<'_> {
+    /// The manifest fields recorded from a device `stat`: the size with
+    /// `record_stats`, and the mtime with it or with `preserve_timestamps`.
+    pub(crate) fn recorded_stat(
+        &self,
+        stat: Option<&DeviceFileStat>,
+    ) -> (Option<u64>, Option<String>) {
+        let size = stat.filter(|_| self.record_stats).map(|stat| stat.size);
+        let modified_at = stat
+            .filter(|_| self.record_stats || self.preserve_timestamps)
+            .and_then(DeviceFileStat::modified_at);
+        (size, modified_at)
+    }
+}
+
+/// Pulls APKs from the device into `package_dir` under their own file
+/// names, numbering any repeats, and hashes each one. With
+/// `options.split_names`, each pulled APK is then renamed after the split
+/// its manifest names, unless another file already has that name.
+fn pull_apks(
+    adb: &dyn AdbRunner,
+    apk_paths: &[String],
+    package_dir: &Path,
+    options: &PullOptions,
+    log: &mut PackageLog,
+) -> Result<Vec<ApkFile>> {
+    if !package_dir.exists() && options.stream.is_none() {
+        fs::create_dir_all(package_dir)?;
+    }
+
+    log.info(format!("    Extracting {} APK file...", apk_paths.len()));
+
+    let stats = if options.record_stats || options.preserve_timestamps || options.stream.is_some() {
+        let stats = stat_device_files(adb, apk_paths);
+        if stats.len() < apk_paths.len() {
+            log.warn("    Warning: Could not stat every APK on the device".to_string());
+        }
+        stats
+    } else {
+        HashMap::new()
+    };
+    let mut pulled_files = Vec::new();
+
+    for (index, apk_path) in apk_paths.iter().enumerate() {
+        let apk_file = Path::new(apk_path)
+            .file_name()
+            .ok_or("Failed to get APK file name.")?;
+        let apk_filename = apk_file.to_string_lossy();
+
+        let local_apk_path = package_dir.join(apk_file);
+        let taken = |path: &Path| {
+            path.exists()
+                || pulled_files
+                    .iter()
+                    .any(|file: &ApkFile| path.file_name() == Some(OsStr::new(&file.name)))
+        };
+
+        let mut final_local_path = if taken(&local_apk_path) {
+            let mut name = local_apk_path.file_stem().unwrap().to_os_string();
+            name.push(format!("_{}", index + 1));
+            if let Some(extension) = local_apk_path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            package_dir.join(name)
+        } else {
+            local_apk_path
+        };
+
+        if let Some(stream) = options.stream {
+            let name = final_local_path.file_name().unwrap().to_string_lossy();
+            let package = package_dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let size = stats
+                .get(apk_path)
+                .ok_or_else(|| format!("Could not stat {} on the device.", apk_path))?
+                .size;
+            let sha256 = stream_apk(
+                adb,
+                apk_path,
+                &Path::new(package.as_ref()).join(name.as_ref()),
+                size,
+                stream,
+                options,
+                log,
+            )
+            .map_err(|e| AppError::AdbCommand {
+                args: match stream.exec_out {
+                    true => format!("exec-out cat {}", apk_path),
+                    false => format!("pull {}", apk_path),
+                },
+                stderr: e,
+            })?;
+            log.info(format!(
+                "      [{}/{}] {}",
+                index + 1,
+                apk_paths.len(),
+                apk_filename
+            ));
+            events::emit(Event::FilePulled {
+                package: &package,
+                file: &apk_filename,
+                bytes: size,
+            });
+            let (_, device_modified_at) = options.recorded_stat(stats.get(apk_path));
+            // The APK is only in the archive now, so its split can't be read;
+            // --stream requires --no-splits-merge for the same reason.
+            pulled_files.push(ApkFile {
+                name: name.to_string(),
+                sha256: Some(sha256),
+                device_size: Some(size),
+                device_modified_at,
+                split: None,
+                device_name: None,
+            });
+            continue;
+        }
+
+        let mut pull_args = vec![OsStr::new("pull")];
+        if options.preserve_timestamps {
+            pull_args.push(OsStr::new("-a"));
+        }
+        pull_args.extend([OsStr::new(apk_path), final_local_path.as_os_str()]);
+        if let Some(progress) = options.progress {
+            progress.start(apk_path, &final_local_path);
+        }
+        let pulled = pull_file(
+            adb,
+            &pull_args,
+            &final_local_path,
+            options.retries,
+            PULL_RETRY_BACKOFF,
+            log,
+        );
+        if let Some(progress) = options.progress {
+            progress.finish(&final_local_path);
+        }
+        pulled.map_err(|e| AppError::AdbCommand {
+            args: format!("pull {}", apk_path),
+            stderr: e,
+        })?;
+
+        // An APK whose manifest can't be read says nothing about which split
+        // it is, so it keeps its name.
+        let info = axml::read_apk_info(&final_local_path).ok();
+        let split = info.as_ref().and_then(|info| info.split.clone());
+        let mut device_name = None;
+        let split_file = match &split {
+            Some(split) => format!("split_{}.apk", split),
+            None => "base.apk".to_string(),
+        };
+        let split_path = package_dir.join(&split_file);
+        if options.split_names
+            && info.is_some()
+            && final_local_path != split_path
+            && !split_path.exists()
+        {
+            fs::rename(&final_local_path, &split_path)?;
+            device_name = Some(apk_filename.to_string());
+            final_local_path = split_path;
+        }
+
+        match &device_name {
+            Some(_) => log.info(format!(
+                "      [{}/{}] {} as {}",
+                index + 1,
+                apk_paths.len(),
+                apk_filename,
+                split_file
+            )),
+            None => log.info(format!(
+                "      [{}/{}] {}",
+                index + 1,
+                apk_paths.len(),
+                apk_filename
+            )),
+        }
+        let sha256 = sha256_file(&final_local_path)?;
+        events::emit(Event::FilePulled {
+            package: &package_dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            file: &apk_filename,
+            bytes: fs::metadata(&final_local_path)?.len(),
+        });
+        if let Some(store) = options.store {
+            match store.link(&final_local_path, &sha256) {
+                Ok(true) => log.info(format!(
+                    "        Identical to a stored copy, linked {}",
+                    apk_filename
+                )),
+                Ok(false) => {}
+                Err(e) => log.info(format!(
+                    "        Keeping a separate copy of {} ({})",
+                    apk_filename, e
+                )),
+            }
+        }
+        let (device_size, device_modified_at) = options.recorded_stat(stats.get(apk_path));
+        pulled_files.push(ApkFile {
+            name: final_local_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            sha256: Some(sha256),
+            device_size,
+            device_modified_at,
+            split,
+            device_name,
+        });
+    }
+
+    Ok(pulled_files)
+}
+
+/// Pulls a single package's APKs to `--out`, or next to the working
+/// directory, without any of the backup bookkeeping.
+pub(crate) fn run_pull_mode(adb: &dyn AdbRunner, args: &PullArgs, config: &Config) -> Result<()> {
+    connect_device(adb, &config.warnings)?;
+
+    let apk_paths = get_package_paths(adb, &args.package)?;
+    let into_dir = args.out.as_ref().is_some_and(|out| out.is_dir());
+    let mut log = PackageLog::default();
+
+    if let Some(format) = args.format {
+        let file_name = format!("{}.{}", args.package, format.extension());
+        let output = match &args.out {
+            Some(out) if into_dir => out.join(file_name),
+            Some(out) => out.clone(),
+            None => PathBuf::from(file_name),
+        };
+        if output.exists() {
+            return Err(format!("{} already exists.", output.display()).into());
+        }
+        let scratch = TempDir::new(&config.temp_dir, "apktool-pull")?;
+        let options = PullOptions {
+            preserve_timestamps: args.preserve_timestamps,
+            record_stats: false,
+            splits: SplitFilter::default(),
+            retry_pm_path: false,
+            split_names: false,
+            retries: config.retries,
+            store: None,
+            stream: None,
+            progress: None,
+        };
+        let result = pull_apks(adb, &apk_paths, scratch.path(), &options, &mut log);
+        log.print(&config.warnings);
+        let files = result.map_err(|e| format!("Failed to pull {}: {}", args.package, e))?;
+        let apk_files = order_split_apks(
+            files
+                .iter()
+                .map(|file| scratch.path().join(&file.name))
+                .collect(),
+        );
+        let info = axml::read_apk_info(&apk_files[0]).unwrap_or_default();
+        if let Err(e) = bundle::pack(&apk_files, &info, &args.package, &output, format) {
+            let _ = fs::remove_file(&output);
+            return Err(format!("Failed to write {}: {}", output.display(), e).into());
+        }
+        status!(
+            "✓ Pulled {} ({} APK(s)) to {}",
+            args.package,
+            apk_files.len(),
+            output.display()
+        );
+        return Ok(());
+    }
+
+    if apk_paths.len() == 1 && !into_dir {
+        let local_path = args
+            .out
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("{}.apk", args.package)));
+        if local_path.exists() {
+            return Err(format!("{} already exists.", local_path.display()).into());
+        }
+        let mut pull_args = vec![OsStr::new("pull")];
+        if args.preserve_timestamps {
+            pull_args.push(OsStr::new("-a"));
+        }
+        pull_args.extend([OsStr::new(&apk_paths[0]), local_path.as_os_str()]);
+        let pulled = pull_file(
+            adb,
+            &pull_args,
+            &local_path,
+            config.retries,
+            PULL_RETRY_BACKOFF,
+            &mut log,
+        );
+        log.print(&config.warnings);
+        pulled.map_err(|e| format!("Failed to pull {}: {}", args.package, e))?;
+        status!("✓ Pulled {} to {}", args.package, local_path.display());
+        return Ok(());
+    }
+
+    let dir = match &args.out {
+        Some(out) if !into_dir && out.extension().is_some_and(|ext| ext == "apk") => {
+            return Err(format!(
+                "{} is split into {} APKs and can't be written to one file. Give --out a folder instead.",
+                args.package,
+                apk_paths.len()
+            )
+            .into());
+        }
+        Some(out) => out.clone(),
+        None => PathBuf::from(&args.package),
+    };
+    let existing: Vec<_> = apk_paths
+        .iter()
+        .filter_map(|apk_path| Path::new(apk_path).file_name())
+        .filter(|name| dir.join(name).exists())
+        .map(|name| name.to_string_lossy())
+        .collect();
+    if !existing.is_empty() {
+        return Err(format!("{} already has {}.", dir.display(), existing.join(", ")).into());
+    }
+
+    let options = PullOptions {
+        preserve_timestamps: args.preserve_timestamps,
+        record_stats: false,
+        splits: SplitFilter::default(),
+        retry_pm_path: false,
+        split_names: false,
+        retries: config.retries,
+        store: None,
+        stream: None,
+        progress: None,
+    };
+    let result = pull_apks(adb, &apk_paths, &dir, &options, &mut log);
+    log.print(&config.warnings);
+    let files = result.map_err(|e| format!("Failed to pull {}: {}", args.package, e))?;
+    status!(
+        "✓ Pulled {} ({} APK(s)) into {}",
+        args.package,
+        files.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Where `backup --stream` sends pulled APKs.
+pub(crate) struct StreamTarget {
+    pub(crate) archive: StreamArchive,
+    /// Whether `adb exec-out` works on the device. Without it each APK is
+    /// pulled to a scratch file and then added.
+    pub(crate) exec_out: bool,
+}
+
+/// Whether `adb exec-out` runs commands on the device and passes their
+/// output through unchanged, as very old devices and adb versions don't.
+pub(crate) fn exec_out_works(adb: &dyn AdbRunner) -> bool {
+    adb.run(&["exec-out", "echo apktool"])
+        .is_ok_and(|output| output.status.success() && output.stdout == b"apktool\n")
+}
+
+/// For `--stream`: copies the `size` byte APK at `apk_path` into the
+/// archive as `entry`, retrying like [`pull_file`], and returns its
+/// SHA-256. Transfers are counted in `options.progress` by the size of the
+/// compressed scratch file.
+fn stream_apk(
+    adb: &dyn AdbRunner,
+    apk_path: &str,
+    entry: &Path,
+    size: u64,
+    target: &StreamTarget,
+    options: &PullOptions,
+    log: &mut PackageLog,
+) -> Result<String, String> {
+    let archive = &target.archive;
+    if !target.exec_out {
+        let raw_path = archive.scratch_path();
+        if let Some(progress) = options.progress {
+            progress.start(apk_path, &raw_path);
+        }
+        let pull_args = [
+            OsStr::new("pull"),
+            OsStr::new(apk_path),
+            raw_path.as_os_str(),
+        ];
+        let pulled = pull_file(
+            adb,
+            &pull_args,
+            &raw_path,
+            options.retries,
+            PULL_RETRY_BACKOFF,
+            log,
+        );
+        if let Some(progress) = options.progress {
+            progress.finish(&raw_path);
+        }
+        let result = pulled.and_then(|()| {
+            let add = || -> io::Result<String> {
+                let mut reader = Sha256Reader::new(File::open(&raw_path)?);
+                let size = fs::metadata(&raw_path)?.len();
+                archive.append(entry, size, &mut reader, &archive.scratch_path())?;
+                Ok(reader.hex_digest())
+            };
+            add().map_err(|e| e.to_string())
+        });
+        let _ = fs::remove_file(&raw_path);
+        return result;
+    }
+
+    let cat = format!("cat {}", shell_quote(apk_path));
+    let args = [OsStr::new("exec-out"), OsStr::new(&cat)];
+    let mut attempt = 0;
+    loop {
+        let scratch = archive.scratch_path();
+        if let Some(progress) = options.progress {
+            progress.start(apk_path, &scratch);
+        }
+        let mut sha256 = None;
+        // The data is only added once exactly `size` bytes have arrived, so
+        // a complete copy is kept even if adb reports an error afterwards.
+        let result = adb.read_os(&args, &mut |stdout| {
+            let mut reader = Sha256Reader::new(stdout);
+            archive.append(entry, size, &mut reader, &scratch)?;
+            sha256 = Some(reader.hex_digest());
+            Ok(())
+        });
+        if let Some(progress) = options.progress {
+            progress.finish(&scratch);
+        }
+        let error = match (sha256, result) {
+            (Some(sha256), _) => return Ok(sha256),
+            (None, Ok(output)) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            (None, Err(e)) => e.to_string(),
+        };
+
+        if attempt >= options.retries {
+            return Err(error);
+        }
+        attempt += 1;
+        log.info(format!(
+            "    Pull failed ({}), retrying {}/{}...",
+            error, attempt, options.retries
+        ));
+        thread::sleep(PULL_RETRY_BACKOFF * attempt);
+    }
+}
+
+/// Runs an `adb pull`, retrying up to `retries` more times when it fails or
+/// doesn't produce `local_path`. The delay grows by `backoff` per attempt.
+fn pull_file(
+    adb: &dyn AdbRunner,
+    pull_args: &[&OsStr],
+    local_path: &Path,
+    retries: u32,
+    backoff: Duration,
+    log: &mut PackageLog,
+) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let error = match adb.run(pull_args) {
+            Ok(output) if output.status.success() && local_path.exists() => return Ok(()),
+            Ok(output) if output.status.success() => "file was not created".to_string(),
+            Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        if attempt >= retries {
+            return Err(error);
+        }
+        attempt += 1;
+        log.info(format!(
+            "    Pull failed ({}), retrying {}/{}...",
+            error, attempt, retries
+        ));
+        thread::sleep(backoff * attempt);
+    }
+}
+
+/// A per-package folder in shared storage that can be backed up next to the
+/// APKs.
+pub(crate) struct SharedDir {
+    /// Folder inside the package's backup folder.
+    local_dir: &'static str,
+    /// Device folder holding one subfolder per package.
+    device_root: &'static str,
+    /// Used in messages, e.g. "external data".
+    label: &'static str,
+}
+
+/// `/sdcard/Android/data/<package>`, for `--with-external-data`.
+pub(crate) const EXTERNAL_DATA: SharedDir = SharedDir {
+    local_dir: EXTERNAL_DATA_DIR,
+    device_root: DEVICE_EXTERNAL_DATA_ROOT,
+    label: "external data",
+};
+
+/// `/sdcard/Android/obb/<package>` expansion files, for `--with-obb`.
+pub(crate) const OBB_FILES: SharedDir = SharedDir {
+    local_dir: OBB_DIR,
+    device_root: DEVICE_OBB_ROOT,
+    label: "OBB files",
+};
+
+/// Pulls `<device_root>/<package>` into the package folder, doing nothing if
+/// the package has no such folder.
+///
+/// Scoped storage denies this on some Android versions, which is reported as
+/// inaccessible data rather than failing the package.
+pub(crate) fn extract_shared_dir(
+    adb: &dyn AdbRunner,
+    shared: &SharedDir,
+    package_name: &str,
+    package_dir: &Path,
+    log: &mut PackageLog,
+) {
+    let remote_dir = format!("{}/{}", shared.device_root, package_name);
+
+    let probe = match adb.run(&["shell", "ls", "-d", &remote_dir]) {
+        Ok(output) => output,
+        Err(e) => {
+            log.warn(format!(
+                "    Warning: Failed to check {}: {}",
+                shared.label, e
+            ));
+            return;
+        }
+    };
+    let probe_text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&probe.stdout),
+        String::from_utf8_lossy(&probe.stderr)
+    );
+    if probe_text.contains("No such file") {
+        return;
+    }
+    if !probe.status.success() || probe_text.contains("Permission denied") {
+        log.warn(format!(
+            "    Warning: {} inaccessible",
+            capitalize(shared.label)
+        ));
+        return;
+    }
+
+    let local_dir = package_dir.join(shared.local_dir);
+    if let Err(e) = fs::create_dir_all(&local_dir) {
+        log.warn(format!(
+            "    Warning: Failed to create {:?}: {}",
+            local_dir, e
+        ));
+        return;
+    }
+
+    let pulled = adb.run(&[
+        OsStr::new("pull"),
+        OsStr::new(&remote_dir),
+        local_dir.as_os_str(),
+    ]);
+    match pulled {
+        Ok(output) if output.status.success() => {
+            log.info(format!("    Extracted {}", shared.label))
+        }
+        Ok(output) => {
+            log.warn(format!(
+                "    Warning: {} inaccessible: {}",
+                capitalize(shared.label),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+            // Don't keep a partial copy that would be pushed back on restore.
+            let _ = fs::remove_dir_all(&local_dir);
+        }
+        Err(e) => log.warn(format!(
+            "    Warning: Failed to extract {}: {}",
+            shared.label, e
+        )),
+    }
+}
+
+/// Pushes a folder captured by [`extract_shared_dir`] back to the device,
+/// if the package folder contains one.
+pub(crate) fn restore_shared_dir(
+    adb: &dyn AdbRunner,
+    shared: &SharedDir,
+    package_name: &str,
+    package_dir: &Path,
+) {
+    let local_dir = package_dir.join(shared.local_dir).join(package_name);
+    if !local_dir.is_dir() {
+        return;
+    }
+
+    let pushed = adb.run(&[
+        OsStr::new("push"),
+        local_dir.as_os_str(),
+        OsStr::new(shared.device_root),
+    ]);
+    match pushed {
+        Ok(output) if output.status.success() => status!("  ✓ Restored {}", shared.label),
+        Ok(output) => failure!(
+            "  ✗ Failed to restore {}: {}",
+            shared.label,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => failure!("  ✗ Failed to execute adb push: {}", e),
+    }
+}
+
+/// How app data can be read or written for a package.
+#[derive(Clone, Copy)]
+enum DataAccess {
+    /// The app is debuggable, so `run-as` runs commands as its user.
+    RunAs,
+    Root,
+}
+
+impl DataAccess {
+    fn detect(adb: &dyn AdbRunner, package_name: &str, has_root: bool) -> Option<Self> {
+        let mut check = vec!["shell".to_string()];
+        check.extend(Self::RunAs.command(adb, package_name, "id"));
+        let run_as = adb.run(&check).is_ok_and(|output| {
+            output.status.success() && String::from_utf8_lossy(&output.stdout).contains("uid=")
+        });
+        if run_as {
+            Some(DataAccess::RunAs)
+        } else if has_root {
+            Some(DataAccess::Root)
+        } else {
+            None
+        }
+    }
+
+    /// Wraps a device shell command so it runs with this access.
+    fn command(self, adb: &dyn AdbRunner, package_name: &str, command: &str) -> Vec<String> {
+        match self {
+            DataAccess::RunAs => {
+                let mut args = vec!["run-as".to_string()];
+                if let Some(user) = adb.user() {
+                    args.extend(["--user".to_string(), user.to_string()]);
+                }
+                args.push(package_name.to_string());
+                args.extend(command.split(' ').map(str::to_string));
+                args
+            }
+            DataAccess::Root => vec!["su".into(), "-c".into(), format!("'{}'", command)],
+        }
+    }
+}
+
+/// The package's data folder on the device: `/data/data/<package>` for the
+/// primary user, `/data/user/<id>/<package>` for others.
+fn app_data_dir(adb: &dyn AdbRunner, package_name: &str) -> String {
+    match adb.user() {
+        Some(user) if user != 0 => format!("/data/user/{}/{}", user, package_name),
+        _ => format!("{}/{}", DEVICE_APP_DATA_ROOT, package_name),
+    }
+}
+
+/// Captures `/data/data/<package>` as [`APP_DATA_FILE`] in the package
+/// folder. Apps that are neither debuggable nor on a rooted device are
+/// backed up APK-only.
+pub(crate) fn extract_app_data(
+    adb: &dyn AdbRunner,
+    package_name: &str,
+    package_dir: &Path,
+    has_root: bool,
+    log: &mut PackageLog,
+) {
+    let Some(access) = DataAccess::detect(adb, package_name, has_root) else {
+        log.info(
+            "    App data skipped: app is not debuggable and device is not rooted".to_string(),
+        );
+        return;
+    };
+
+    let data_dir = app_data_dir(adb, package_name);
+    let mut args = vec!["exec-out".to_string()];
+    args.extend(access.command(adb, package_name, &format!("tar -cf - -C {} .", data_dir)));
+
+    let local_path = package_dir.join(APP_DATA_FILE);
+    match adb.run(&args) {
+        Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+            match fs::write(&local_path, &output.stdout) {
+                Ok(()) => log.info("    Extracted app data".to_string()),
+                Err(e) => log.warn(format!(
+                    "    Warning: Failed to write {:?}: {}",
+                    local_path, e
+                )),
+            }
+        }
+        Ok(output) => log.warn(format!(
+            "    Warning: Failed to extract app data: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) => log.warn(format!("    Warning: Failed to extract app data: {}", e)),
+    }
+}
+
+/// Restores app data captured by [`extract_app_data`], if the package folder
+/// has any. The app is stopped first; with root the files are handed back
+/// to the app's user and relabelled for SELinux.
+pub(crate) fn restore_app_data(adb: &dyn AdbRunner, package_name: &str, package_dir: &Path) {
+    let local_path = package_dir.join(APP_DATA_FILE);
+    if !local_path.is_file() {
+        return;
+    }
+
+    let Some(access) = DataAccess::detect(adb, package_name, device_has_root(adb)) else {
+        failure!("  ✗ Cannot restore app data: app is not debuggable and device is not rooted");
+        return;
+    };
+
+    let data_dir = app_data_dir(adb, package_name);
+    let _ = adb.run(&["shell", "am", "force-stop", package_name]);
+
+    let mut args = vec!["exec-in".to_string()];
+    args.extend(access.command(adb, package_name, &format!("tar -xf - -C {}", data_dir)));
+    let restored = File::open(&local_path).and_then(|input| adb.run_with_input(&args, input));
+    match restored {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            failure!(
+                "  ✗ Failed to restore app data: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return;
+        }
+        Err(e) => {
+            failure!("  ✗ Failed to execute adb exec-in: {}", e);
+            return;
+        }
+    }
+
+    if let DataAccess::Root = access {
+        let owner = get_device_output(adb, &["shell", "stat", "-c", "%u", &data_dir]);
+        let Some(owner) = owner else {
+            failure!(
+                "  ✗ Restored app data, but could not read the owner of {}",
+                data_dir
+            );
+            return;
+        };
+        let fix = format!(
+            "'chown -R {owner}:{owner} {dir} && restorecon -R {dir}'",
+            owner = owner,
+            dir = data_dir
+        );
+        match adb.run(&["shell", "su", "-c", &fix]) {
+            Ok(output) if output.status.success() => {}
+            _ => {
+                failure!("  ✗ Restored app data, but failed to fix its ownership");
+                return;
+            }
+        }
+    }
+
+    status!("  ✓ Restored app data");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::PackageLog;
+    use crate::install::SplitFilter;
+    use crate::testing::FakeAdb;
+
+    #[test]
+    fn extract_apk_asks_pm_path_again_with_retry_pm_path() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-retry-pm-path").unwrap();
+        let adb = || {
+            FakeAdb::default()
+                .once("shell pm path com.example", "")
+                .with(
+                    "shell pm path com.example",
+                    "package:/data/app/com.example-1/base.apk\n",
+                )
+        };
+        let options = |retry_pm_path| PullOptions {
+            preserve_timestamps: false,
+            record_stats: false,
+            splits: SplitFilter::default(),
+            retry_pm_path,
+            split_names: false,
+            retries: 0,
+            store: None,
+            stream: None,
+            progress: None,
+        };
+
+        assert!(matches!(
+            extract_apk(
+                &adb(),
+                "com.example",
+                root.path(),
+                &options(false),
+                &mut PackageLog::default()
+            ),
+            Err(AppError::NoApkPaths(_))
+        ));
+        let (files, _) = extract_apk(
+            &adb(),
+            "com.example",
+            root.path(),
+            &options(true),
+            &mut PackageLog::default(),
+        )
+        .unwrap();
+        let names: Vec<_> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["base.apk"]);
+    }
+
+    #[test]
+    fn pull_apks_numbers_repeated_file_names() {
+        let root = TempDir::new(&std::env::temp_dir(), "apktool-test-pull-apks").unwrap();
+        let apk_paths = [
+            "/data/app/a/base.apk".to_string(),
+            "/data/app/b/base.apk".to_string(),
+            "/data/app/a/split_config.en.apk".to_string(),
+        ];
+        let options = PullOptions {
+            preserve_timestamps: false,
+            record_stats: false,
+            splits: SplitFilter::default(),
+            retry_pm_path: false,
+            split_names: true,
+            retries: 0,
+            store: None,
+            stream: None,
+            progress: None,
+        };
+        let files = pull_apks(
+            &FakeAdb::default(),
+            &apk_paths,
+            root.path(),
+            &options,
+            &mut PackageLog::default(),
+        )
+        .unwrap();
+
+        let names: Vec<_> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["base.apk", "base_2.apk", "split_config.en.apk"]);
+        assert_eq!(
+            fs::read_to_string(root.path().join("base_2.apk")).unwrap(),
+            "/data/app/b/base.apk"
+        );
+    }
+}
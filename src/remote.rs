@@ -0,0 +1,159 @@
+//! Copies backups to and from a remote store over SSH, for `backup
+//! --remote` and `install --from-remote`.
+//!
+//! Transfers use rsync when it is installed, which can pick up where an
+//! interrupted copy left off, and scp otherwise. Both reach the host through
+//! the user's own SSH setup, so keys, agents and `~/.ssh/config` apply.
+
+use crate::runlog;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Instant;
+
+/// A `[user@]host:/path` location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remote {
+    host: String,
+    path: String,
+}
+
+impl FromStr for Remote {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // As with scp, a colon after a slash is part of a local path.
+        let (host, path) = value
+            .split_once(':')
+            .filter(|(host, _)| !host.contains('/'))
+            .ok_or("expected [USER@]HOST:PATH")?;
+        if host.is_empty() || host.ends_with('@') || host.starts_with('-') {
+            return Err("missing host before ':'".to_string());
+        }
+        if path.is_empty() {
+            return Err("missing path after ':'".to_string());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.path)
+    }
+}
+
+impl Remote {
+    /// The last component of the path, e.g. the backup name to fetch.
+    pub fn name(&self) -> Option<&str> {
+        self.path.rsplit('/').next().filter(|name| !name.is_empty())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Rsync,
+    Scp,
+}
+
+impl Tool {
+    /// The tool transfers are made with, or `None` if neither is installed.
+    pub fn find() -> Option<Self> {
+        let runs = |program: &str, arg: &str| {
+            Command::new(program)
+                .arg(arg)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if runs("rsync", "--version") {
+            Some(Tool::Rsync)
+        } else if runs("ssh", "-V") {
+            // scp has no version flag, but ships with ssh.
+            Some(Tool::Scp)
+        } else {
+            None
+        }
+    }
+
+    fn command(self, source: &str, dest: &str) -> Command {
+        let mut command = match self {
+            Tool::Rsync => {
+                let mut command = Command::new("rsync");
+                command.args(["-a", "--partial"]);
+                command
+            }
+            Tool::Scp => {
+                let mut command = Command::new("scp");
+                command.args(["-r", "-q"]);
+                command
+            }
+        };
+        command.arg(source).arg(dest);
+        command
+    }
+}
+
+/// Copies `local`, a backup folder or archive, into the remote folder,
+/// creating the folder first.
+pub fn push(tool: Tool, local: &Path, remote: &Remote) -> io::Result<()> {
+    let mut mkdir = Command::new("ssh");
+    mkdir
+        .arg(&remote.host)
+        .arg(format!("mkdir -p {}", crate::shell_quote(&remote.path)));
+    run(&mut mkdir)?;
+
+    let source = local.to_string_lossy();
+    run(&mut tool.command(&source, &format!("{}/", remote)))
+}
+
+/// Copies the remote backup folder or archive `remote` into `dest`, and
+/// returns where it ended up.
+pub fn fetch(tool: Tool, remote: &Remote, dest: &Path) -> io::Result<PathBuf> {
+    let name = remote.name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} doesn't name a backup", remote),
+        )
+    })?;
+    let dest_dir = format!("{}/", dest.to_string_lossy());
+    run(&mut tool.command(&remote.to_string(), &dest_dir))?;
+    let fetched = dest.join(name);
+    if !fetched.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} wasn't copied", remote),
+        ));
+    }
+    Ok(fetched)
+}
+
+/// Runs a transfer command, failing with its stderr when it fails. ssh,
+/// scp and rsync already name themselves in their messages.
+fn run(command: &mut Command) -> io::Result<()> {
+    let start = Instant::now();
+    // Password and host key prompts go to the terminal, not to stdin.
+    let result = command.stdin(Stdio::null()).output();
+    runlog::record(command, &result, start.elapsed());
+    let output = result?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    Err(io::Error::other(if stderr.is_empty() {
+        format!(
+            "{} exited with {}",
+            command.get_program().to_string_lossy(),
+            output.status
+        )
+    } else {
+        stderr
+    }))
+}
@@ -0,0 +1,98 @@
+//! Per-run log of every adb command, with its exit status and output, kept
+//! regardless of what is shown on screen.
+
+use chrono::Local;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+pub const LOG_FILE: &str = "apktool.log";
+/// Output longer than this is summarized by size rather than copied, which
+/// keeps `exec-out` tar streams and the like out of the log.
+const MAX_LOGGED_OUTPUT: usize = 64 * 1024;
+
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Opens `path` for appending, creating its folder if needed, and starts the
+/// run with a header line naming the command line.
+pub fn open(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let command_line: Vec<_> = std::env::args().collect();
+    writeln!(
+        file,
+        "\n=== {} {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        command_line.join(" ")
+    )?;
+    let _ = LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Also prints each record to stderr.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Records a finished adb command.
+pub fn record(command: &Command, result: &io::Result<Output>, elapsed: Duration) {
+    let verbose = VERBOSE.load(Ordering::Relaxed);
+    if LOG.get().is_none() && !verbose {
+        return;
+    }
+
+    let mut text = format!(
+        "[{}] $ {}",
+        Local::now().format("%H:%M:%S%.3f"),
+        command_line(command)
+    );
+    match result {
+        Ok(output) => {
+            let status = match output.status.code() {
+                Some(code) => format!("exit {}", code),
+                None => "killed by signal".to_string(),
+            };
+            text.push_str(&format!(" ({}, {:.2}s)\n", status, elapsed.as_secs_f64()));
+            append_output(&mut text, "stdout", &output.stdout);
+            append_output(&mut text, "stderr", &output.stderr);
+        }
+        Err(e) => text.push_str(&format!(" (error: {}, {:.2}s)\n", e, elapsed.as_secs_f64())),
+    }
+
+    if verbose {
+        eprint!("{}", text);
+    }
+    if let Some(log) = LOG.get() {
+        let _ = log.lock().unwrap().write_all(text.as_bytes());
+    }
+}
+
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn append_output(text: &mut String, label: &str, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(output) if bytes.len() <= MAX_LOGGED_OUTPUT && !output.contains('\0') => {
+            text.push_str(&format!("  {}:\n", label));
+            for line in output.trim_end().lines() {
+                text.push_str(&format!("    {}\n", line));
+            }
+        }
+        _ => text.push_str(&format!("  {}: <{} bytes>\n", label, bytes.len())),
+    }
+}
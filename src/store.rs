@@ -0,0 +1,88 @@
+//! Content-addressed store of APK files shared by the backups under one
+//! backup root, used by `backup --dedup`.
+//!
+//! Each distinct file is kept once as `.objects/<sha256>`, and package
+//! folders hold hard links to it. A backup folder therefore keeps the same
+//! flat layout with or without deduplication, and can be installed, copied
+//! or archived on its own.
+
+use crate::manifest::Manifest;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const STORE_DIR: &str = ".objects";
+
+pub struct ObjectStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn open(backup_root: &Path) -> io::Result<Self> {
+        let dir = backup_root.join(STORE_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Makes `path`, whose contents hash to `sha256`, share its data with
+    /// the stored object: an existing object replaces the file, otherwise the
+    /// file becomes the object. Returns whether an existing object was
+    /// reused.
+    pub fn link(&self, path: &Path, sha256: &str) -> io::Result<bool> {
+        let object = self.dir.join(sha256);
+        match fs::hard_link(path, &object) {
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+
+        // Link next to the file first so it is never missing, even if the
+        // link fails half way.
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".link");
+        let temp_path = path.with_file_name(temp_name);
+        let _ = fs::remove_file(&temp_path);
+        fs::hard_link(&object, &temp_path)?;
+        fs::rename(&temp_path, path)?;
+        Ok(true)
+    }
+}
+
+/// Whether `path` is the object store rather than a backup.
+pub fn is_store(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == STORE_DIR)
+}
+
+/// Deletes objects that no backup folder's manifest lists any more, and
+/// returns how many were deleted. Archives hold their own copies, so they
+/// never keep an object alive.
+pub fn prune(backup_root: &Path) -> io::Result<usize> {
+    let dir = backup_root.join(STORE_DIR);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+
+    let mut referenced = HashSet::new();
+    for entry in fs::read_dir(backup_root)?.filter_map(Result::ok) {
+        let Ok(Some(manifest)) = Manifest::load(&entry.path()) else {
+            continue;
+        };
+        referenced.extend(
+            manifest
+                .packages
+                .into_iter()
+                .flat_map(|package| package.files)
+                .filter_map(|file| file.sha256),
+        );
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(&dir)?.filter_map(Result::ok) {
+        if !referenced.contains(entry.file_name().to_string_lossy().as_ref()) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,127 @@
+//! Test doubles shared by the unit tests.
+
+use crate::device::AdbRunner;
+use crate::manifest::{ApkFile, DeviceInfo, Manifest, PackageEntry, PackageStatus, SCHEMA_VERSION};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+
+/// Answers adb commands with canned stdout, keyed by the arguments
+/// joined with spaces. `pull` writes the device path into the local
+/// file, so pulled files can be told apart.
+#[derive(Default)]
+pub(crate) struct FakeAdb {
+    responses: HashMap<String, String>,
+    /// Answers used once each, in order, before `responses`.
+    queued: Mutex<HashMap<String, std::collections::VecDeque<String>>>,
+}
+
+impl FakeAdb {
+    pub(crate) fn with(mut self, args: &str, stdout: &str) -> Self {
+        self.responses.insert(args.to_string(), stdout.to_string());
+        self
+    }
+
+    pub(crate) fn once(self, args: &str, stdout: &str) -> Self {
+        self.queued
+            .lock()
+            .unwrap()
+            .entry(args.to_string())
+            .or_default()
+            .push_back(stdout.to_string());
+        self
+    }
+}
+
+impl AdbRunner for FakeAdb {
+    fn run_os(&self, args: &[&OsStr]) -> io::Result<Output> {
+        let args = args
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if let Some(pull) = args.strip_prefix("pull ") {
+            let (remote, local) = pull.rsplit_once(' ').unwrap();
+            fs::write(local, remote)?;
+            return Ok(fake_output(0, ""));
+        }
+        if let Some(stdout) = self
+            .queued
+            .lock()
+            .unwrap()
+            .get_mut(&args)
+            .and_then(|queue| queue.pop_front())
+        {
+            return Ok(fake_output(0, &stdout));
+        }
+        Ok(match self.responses.get(&args) {
+            Some(stdout) => fake_output(0, stdout),
+            None => fake_output(1, ""),
+        })
+    }
+
+    fn run_os_with_input(&self, args: &[&OsStr], _input: File) -> io::Result<Output> {
+        self.run_os(args)
+    }
+}
+
+pub(crate) fn fake_output(code: i32, stdout: &str) -> Output {
+    #[cfg(unix)]
+    let status = std::os::unix::process::ExitStatusExt::from_raw(code << 8);
+    #[cfg(windows)]
+    let status = std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+    let status: ExitStatus = status;
+    Output {
+        status,
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: Vec::new(),
+    }
+}
+
+/// A manifest holding `packages`, from an unknown device, with everything
+/// else left at its default.
+pub(crate) fn fake_manifest(packages: Vec<PackageEntry>) -> Manifest {
+    Manifest {
+        schema_version: SCHEMA_VERSION,
+        created_at: String::new(),
+        created_by: None,
+        base: None,
+        device: DeviceInfo::default(),
+        partial: false,
+        warnings: 0,
+        strict_failed: false,
+        packages,
+    }
+}
+
+/// A manifest entry for `name` listing `files` by name only.
+pub(crate) fn fake_entry(name: &str, status: PackageStatus, files: &[&str]) -> PackageEntry {
+    PackageEntry {
+        name: name.to_string(),
+        status,
+        version_code: None,
+        version_name: None,
+        min_sdk: None,
+        target_sdk: None,
+        installer: None,
+        system: false,
+        permissions: Vec::new(),
+        files: files
+            .iter()
+            .map(|file| ApkFile {
+                name: file.to_string(),
+                sha256: None,
+                device_size: None,
+                device_modified_at: None,
+                split: None,
+                device_name: None,
+            })
+            .collect(),
+        dropped_splits: Vec::new(),
+        total_bytes: 0,
+        error: None,
+    }
+}